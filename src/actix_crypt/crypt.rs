@@ -20,20 +20,18 @@ use block_cipher_trait::BlockCipher;
 
 use hex;
 
+use hmac::{Hmac, Mac};
+
 use sha2::{Digest, Sha256};
 
 use num_traits::Num;
 
-use lazy_static::lazy_static;
+use rand::RngCore;
 
 // create an alias for convinience
 type Aes256Cbc = Cbc<Aes256, Pkcs7>;
 
-lazy_static! {
-    pub static ref AES_KEY: String = std::env::var("AES_KEY").expect("AES_KEY must be set");
-    pub static ref BLOB_MAGIC: String = std::env::var("BLOB_MAGIC").expect("BLOB_MAGIC must be set");
-}
-
+const KEY_SIZE: usize = 0x20;
 const MAGIC_SIZE: usize = 0x8;
 const UNPADDED_SIZE: usize = 0x8;
 const INITIAL_VECTOR_SIZE: usize = 0x10;
@@ -49,15 +47,90 @@ pub type BlobMagic = [u8; MAGIC_SIZE];
 pub type BlobInitialVector = [u8; INITIAL_VECTOR_SIZE];
 pub type BlobHash = [u8; HASH_SIZE];
 
+/// The AES key and the magic identifying blobs encrypted with it.
+#[derive(Clone, Copy, PartialEq)]
+pub struct BlobKey {
+    key: [u8; KEY_SIZE],
+    magic: BlobMagic,
+}
+
+impl BlobKey {
+    /// Create a key from its hexadecimal representation and the magic of its blobs.
+    pub fn new(key: &str, magic: &str) -> std::io::Result<Self> {
+        let invalid = |message| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+
+        let key = hex::decode(key)
+            .map_err(|_| invalid("the AES key is not valid hexadecimal".to_string()))?;
+        if key.len() != KEY_SIZE {
+            return Err(invalid(format!(
+                "the AES key must be {} bytes long ({} hexadecimal characters), got {} bytes",
+                KEY_SIZE,
+                KEY_SIZE * 2,
+                key.len()
+            )));
+        }
+
+        if magic.len() != MAGIC_SIZE {
+            return Err(invalid(format!(
+                "the blob magic must be {} bytes long, got {} bytes",
+                MAGIC_SIZE,
+                magic.len()
+            )));
+        }
+
+        let mut result = BlobKey {
+            key: [0; KEY_SIZE],
+            magic: [0; MAGIC_SIZE],
+        };
+        result.key.copy_from_slice(&key);
+        result.magic.copy_from_slice(magic.as_bytes());
+        Ok(result)
+    }
+
+    /// Derive a secret for another use of the key, identified by `label`.
+    pub fn derive(&self, label: &str) -> [u8; KEY_SIZE] {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC can take key of any size");
+        mac.input(label.as_bytes());
+
+        let mut result = [0; KEY_SIZE];
+        result.copy_from_slice(&mac.result().code());
+        result
+    }
+
+    /// Return a short identifier of the key, derived from it so that it can be shown.
+    pub fn id(&self) -> String {
+        hex::encode(&self.derive("imagers key id")[..4])
+    }
+
+    /// Encrypt `data` as a blob with a random initial vector.
+    pub fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let mut iv = BlobInitialVector::default();
+        rand::thread_rng().fill_bytes(&mut iv);
+
+        let mut result = Vec::with_capacity(HEADER_SIZE + align_up(data.len() + 1, 0x10));
+        result.extend_from_slice(&self.magic);
+        result.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        result.extend_from_slice(&iv);
+        result.extend_from_slice(&Sha256::digest(data));
+        result.extend_from_slice(&self.cipher(&iv).encrypt_vec(data));
+        result
+    }
+
+    fn cipher(&self, iv: &BlobInitialVector) -> Aes256Cbc {
+        Aes256Cbc::new_var(&self.key, iv).unwrap()
+    }
+}
+
 pub struct EncryptedBlob<T: Read + Write + Seek + Sized> {
     accessor: T,
     size: u64,
+    key: BlobKey,
     cipher: Option<Aes256Cbc>,
     iv: Option<BlobInitialVector>,
 }
 
 impl<T: Read + Write + Seek + Sized> EncryptedBlob<T> {
-    pub fn from(accessor: T) -> std::io::Result<Self> {
+    pub fn from(accessor: T, key: BlobKey) -> std::io::Result<Self> {
         let mut accessor = accessor;
 
         // Compute stream size
@@ -67,12 +140,12 @@ impl<T: Read + Write + Seek + Sized> EncryptedBlob<T> {
         let mut res = EncryptedBlob {
             accessor,
             size,
+            key,
             cipher: None,
             iv: None,
         };
-        let key = hex::decode(AES_KEY.as_str()).unwrap();
         let iv = res.initial_vector()?;
-        let cipher = Aes256Cbc::new_var(&key, &iv).unwrap();
+        let cipher = key.cipher(&iv);
 
         // Reset stream position and setup the cipher
         res.accessor.seek(SeekFrom::Start(0))?;
@@ -83,8 +156,7 @@ impl<T: Read + Write + Seek + Sized> EncryptedBlob<T> {
 
     fn reset_cipher(&mut self) {
         if let Some(iv) = self.iv {
-            let key = hex::decode(AES_KEY.as_str()).unwrap();
-            self.cipher = Some(Aes256Cbc::new_var(&key, &iv).unwrap());
+            self.cipher = Some(self.key.cipher(&iv));
         } else {
             panic!();
         }
@@ -95,13 +167,23 @@ impl<T: Read + Write + Seek + Sized> EncryptedBlob<T> {
         let magic_opt = self.magic();
 
         if let Ok(magic) = magic_opt {
-            return magic == BLOB_MAGIC.as_bytes();
+            return magic == self.key.magic;
         }
         false
     }
 
     #[allow(clippy::wrong_self_convention)]
     pub fn is_content_valid(&mut self) -> bool {
+        self.inspect_content(|_| {})
+    }
+
+    /// Check the content like `is_content_valid`, giving each decrypted chunk to `inspect`.
+    pub fn inspect_content<F: FnMut(&[u8])>(&mut self, mut inspect: F) -> bool {
+        // A truncated blob cannot be valid
+        if self.size < HEADER_SIZE as u64 {
+            return false;
+        }
+
         if self
             .accessor
             .seek(SeekFrom::Start(HEADER_SIZE as u64))
@@ -135,6 +217,7 @@ impl<T: Read + Write + Seek + Sized> EncryptedBlob<T> {
                 let slice = &slice[..read_len];
                 decrypted_size += slice.len() as u64;
                 hasher.input(slice);
+                inspect(slice);
             }
 
             let computed_hash = hasher.result();
@@ -195,10 +278,8 @@ impl<T: Read + Write + Seek + Sized> EncryptedBlob<T> {
     }
 
     pub fn decrypted_data(&mut self) -> Result<Vec<u8>, BlockModeError> {
-        let key = hex::decode(AES_KEY.as_str()).unwrap();
-
         if let Some(iv) = self.iv {
-            let cipher = Aes256Cbc::new_var(&key, &iv).unwrap();
+            let cipher = self.key.cipher(&iv);
             let encrypted_data = self.encrypted_data().unwrap();
             cipher.decrypt_vec(&encrypted_data)
         } else {
@@ -347,6 +428,34 @@ where
     }
 }
 
+/// Length of the start of a header needed by `announced_blob_size` and `header_initial_vector`.
+pub const HEADER_PREFIX_SIZE: usize = MAGIC_SIZE + UNPADDED_SIZE + INITIAL_VECTOR_SIZE;
+
+/// Return the size of a blob as announced by the start of its `header`.
+///
+/// Legacy blobs don't announce their size, `None` is also returned while the header is too short.
+pub fn announced_blob_size(header: &[u8]) -> Option<u64> {
+    let mut unpadded_size = [0u8; UNPADDED_SIZE];
+    unpadded_size.copy_from_slice(header.get(MAGIC_SIZE..MAGIC_SIZE + UNPADDED_SIZE)?);
+
+    let unpadded_size = u64::from_le_bytes(unpadded_size);
+    if unpadded_size == LEGACY_HEADER_PADDING {
+        return None;
+    }
+
+    // PKCS7 always adds a padding block when the plaintext is aligned
+    let block_size = <Aes256 as BlockCipher>::BlockSize::to_u64();
+    let padded_size = align_down(unpadded_size, block_size).checked_add(block_size)?;
+    padded_size.checked_add(HEADER_SIZE as u64)
+}
+
+/// Return the initial vector found at the start of a `header`, `None` while it is too short.
+pub fn header_initial_vector(header: &[u8]) -> Option<BlobInitialVector> {
+    let mut result = [0u8; INITIAL_VECTOR_SIZE];
+    result.copy_from_slice(header.get(MAGIC_SIZE + UNPADDED_SIZE..HEADER_PREFIX_SIZE)?);
+    Some(result)
+}
+
 pub fn align_down<T: Num + Not<Output = T> + BitAnd<Output = T> + Copy>(addr: T, align: T) -> T {
     addr & !(align - T::one())
 }
@@ -0,0 +1,372 @@
+//! Decoding of user-supplied images, with limits against decompression bombs.
+//!
+//! A few KiB of PNG or GIF can claim billions of pixels. The dimensions and the amount of frames
+//! of an image are read from its header and checked against the limits before it is decoded, and
+//! the decoder cannot allocate more than `DecodeLimits::max_memory` on top of that.
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Cursor};
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::io::{Limits, Reader};
+use image::{ColorType, DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageResult};
+
+/// The limits of the images a tenant decodes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodeLimits {
+    /// Largest width or height of a decoded image.
+    pub max_dimension: u32,
+    /// Most pixels of a decoded image, counting every frame of an animation.
+    pub max_pixels: u64,
+    /// Most frames of an animation.
+    pub max_frames: u32,
+    /// Most bytes the decoder of an image can allocate.
+    pub max_memory: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_dimension: 16_384,
+            max_pixels: 100_000_000,
+            max_frames: 1000,
+            max_memory: 512 * 1024 * 1024,
+        }
+    }
+}
+
+/// The reason an image over the limits isn't decoded.
+#[derive(Debug)]
+pub struct TooLarge(String);
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Image too large: {}", self.0)
+    }
+}
+
+impl Error for TooLarge {}
+
+fn too_large(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, TooLarge(reason))
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Not an image that can be decoded")
+}
+
+/// Return true if `e` is about an image over the limits.
+pub fn is_too_large(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<TooLarge>())
+}
+
+/// What the header of an image tells about it.
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    /// Amount of frames, 1 unless the image is animated.
+    pub frames: u32,
+    pub color_type: ColorType,
+}
+
+/// Return the amount of frames of the PNG `data`, from its animation control chunk.
+fn png_frames(data: &[u8]) -> u32 {
+    let mut position = 8;
+    while let Some(header) = data.get(position..position + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        match &header[4..8] {
+            b"acTL" => {
+                return data
+                    .get(position + 8..position + 12)
+                    .map(|frames| u32::from_be_bytes([frames[0], frames[1], frames[2], frames[3]]))
+                    .unwrap_or(1);
+            }
+            // The animation control comes before the image data
+            b"IDAT" => break,
+            _ => position += 12 + length,
+        }
+    }
+    1
+}
+
+/// Return the amount of frames of the GIF `data` by walking its blocks.
+fn gif_frames(data: &[u8]) -> u32 {
+    let skip_sub_blocks = |mut position: usize| -> Option<usize> {
+        loop {
+            let length = usize::from(*data.get(position)?);
+            position += 1 + length;
+            if length == 0 {
+                return Some(position);
+            }
+        }
+    };
+    let color_table_size = |flags: u8| if flags & 0x80 != 0 { 3 << ((flags & 0x07) + 1) } else { 0 };
+
+    let mut frames = 0;
+    let mut position = match data.get(10) {
+        Some(flags) => 13 + color_table_size(*flags),
+        None => return 0,
+    };
+    loop {
+        position = match data.get(position) {
+            // Extension: label and sub-blocks
+            Some(0x21) => match skip_sub_blocks(position + 2) {
+                Some(position) => position,
+                None => break,
+            },
+            // Image: descriptor, local color table, LZW code size and sub-blocks
+            Some(0x2c) => {
+                frames += 1;
+                let flags = match data.get(position + 9) {
+                    Some(flags) => *flags,
+                    None => break,
+                };
+                match skip_sub_blocks(position + 10 + color_table_size(flags) + 1) {
+                    Some(position) => position,
+                    None => break,
+                }
+            }
+            _ => break,
+        };
+    }
+    frames
+}
+
+/// Return the amount of frames of the WebP `data`, from its animation frame chunks.
+fn webp_frames(data: &[u8]) -> u32 {
+    let mut frames = 0;
+    let mut position = 12;
+    while let Some(header) = data.get(position..position + 8) {
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if &header[..4] == b"ANMF" {
+            frames += 1;
+        }
+        position += 8 + length + (length & 1);
+    }
+    frames.max(1)
+}
+
+/// Read the header of the image `data` in `format`, without decoding it.
+///
+/// The frames of a GIF or a WebP are spread over the whole file, for the other formats the
+/// start of the image is enough.
+pub fn read_header(data: &[u8], format: ImageFormat) -> ImageResult<Option<Header>> {
+    let ((width, height), color_type, frames) = match format {
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(data))?;
+            (decoder.dimensions(), decoder.color_type(), png_frames(data))
+        }
+        ImageFormat::Jpeg => {
+            let decoder = JpegDecoder::new(Cursor::new(data))?;
+            (decoder.dimensions(), decoder.color_type(), 1)
+        }
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(Cursor::new(data))?;
+            (decoder.dimensions(), decoder.color_type(), gif_frames(data))
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(data))?;
+            (decoder.dimensions(), decoder.color_type(), webp_frames(data))
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Header {
+        width,
+        height,
+        frames,
+        color_type,
+    }))
+}
+
+/// Check the image described by `header` against `limits`.
+pub fn check(header: &Header, limits: &DecodeLimits) -> io::Result<()> {
+    let dimension = header.width.max(header.height);
+    if dimension > limits.max_dimension {
+        return Err(too_large(format!("{} pixels wide or high", dimension)));
+    }
+    if header.frames > limits.max_frames {
+        return Err(too_large(format!("{} frames", header.frames)));
+    }
+
+    let pixels = u64::from(header.width) * u64::from(header.height) * u64::from(header.frames.max(1));
+    if pixels > limits.max_pixels {
+        return Err(too_large(format!("{} pixels", pixels)));
+    }
+
+    Ok(())
+}
+
+/// Return the format of the image `name`, if it can be decoded.
+pub fn image_format(name: &str) -> Option<ImageFormat> {
+    match ImageFormat::from_path(name) {
+        Ok(format @ ImageFormat::Png)
+        | Ok(format @ ImageFormat::Jpeg)
+        | Ok(format @ ImageFormat::Gif)
+        | Ok(format @ ImageFormat::WebP) => Some(format),
+        _ => None,
+    }
+}
+
+/// Return the format of the image starting with `data`, if it can be decoded.
+pub fn content_format(data: &[u8]) -> Option<ImageFormat> {
+    match image::guess_format(data) {
+        Ok(format @ ImageFormat::Png)
+        | Ok(format @ ImageFormat::Jpeg)
+        | Ok(format @ ImageFormat::Gif)
+        | Ok(format @ ImageFormat::WebP) => Some(format),
+        _ => None,
+    }
+}
+
+/// Decode the image `data` in `format`, once its header is within `limits`.
+///
+/// Errors of kind `InvalidData` mean the image is malformed, or over the limits if
+/// `is_too_large` is true.
+pub fn decode(data: &[u8], format: ImageFormat, limits: &DecodeLimits) -> io::Result<DynamicImage> {
+    match read_header(data, format) {
+        Ok(Some(header)) => check(&header, limits)?,
+        Ok(None) => {}
+        Err(_) => return Err(malformed()),
+    }
+
+    let mut decoder_limits = Limits::default();
+    decoder_limits.max_image_width = Some(limits.max_dimension);
+    decoder_limits.max_image_height = Some(limits.max_dimension);
+    decoder_limits.max_alloc = Some(limits.max_memory);
+
+    let mut reader = Reader::with_format(Cursor::new(data), format);
+    reader.limits(decoder_limits);
+    reader.decode().map_err(|e| match e {
+        ImageError::Limits(e) => too_large(e.to_string()),
+        _ => malformed(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ColorType;
+
+    use super::{check, gif_frames, is_too_large, png_frames, webp_frames, DecodeLimits, Header};
+
+    fn png(chunks: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (chunk_type, content) in chunks {
+            data.extend_from_slice(&(content.len() as u32).to_be_bytes());
+            data.extend_from_slice(chunk_type);
+            data.extend_from_slice(content);
+            // The CRC isn't checked
+            data.extend_from_slice(&[0; 4]);
+        }
+        data
+    }
+
+    fn gif(frames: usize) -> Vec<u8> {
+        // A 1 by 1 screen without global color table
+        let mut data = b"GIF89a\x01\x00\x01\x00\x00\x00\x00".to_vec();
+        for _ in 0..frames {
+            // Graphic control extension, then the image with a single sub-block
+            data.extend_from_slice(&[0x21, 0xf9, 4, 0, 0, 0, 0, 0]);
+            data.extend_from_slice(&[0x2c, 0, 0, 0, 0, 1, 0, 1, 0, 0, 2, 2, 0x44, 0x01, 0]);
+        }
+        data.push(0x3b);
+        data
+    }
+
+    fn webp(chunks: &[(&[u8], u32)]) -> Vec<u8> {
+        let mut data = b"RIFF\0\0\0\0WEBP".to_vec();
+        for (chunk_type, length) in chunks {
+            data.extend_from_slice(chunk_type);
+            data.extend_from_slice(&length.to_le_bytes());
+            data.resize(data.len() + (*length as usize).min(16), 0);
+        }
+        data
+    }
+
+    #[test]
+    fn png_animation() {
+        let ihdr: &[u8] = &[0; 13];
+        let actl: &[u8] = &[0, 0, 0, 3, 0, 0, 0, 0];
+        assert_eq!(png_frames(&png(&[(b"IHDR", ihdr), (b"IDAT", &[])])), 1);
+        assert_eq!(png_frames(&png(&[(b"IHDR", ihdr), (b"acTL", actl), (b"IDAT", &[])])), 3);
+        // The animation control after the image data is ignored
+        assert_eq!(png_frames(&png(&[(b"IHDR", ihdr), (b"IDAT", &[]), (b"acTL", actl)])), 1);
+
+        // Truncated in the animation control
+        let data = png(&[(b"IHDR", ihdr), (b"acTL", actl)]);
+        assert_eq!(png_frames(&data[..data.len() - 10]), 1);
+        assert_eq!(png_frames(&data[..10]), 1);
+
+        // A chunk claiming more than the file
+        let mut data = png(&[(b"IHDR", ihdr), (b"acTL", actl)]);
+        data[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(png_frames(&data), 1);
+    }
+
+    #[test]
+    fn gif_animation() {
+        assert_eq!(gif_frames(&gif(1)), 1);
+        assert_eq!(gif_frames(&gif(3)), 3);
+
+        // Truncated in the sub-blocks of the second image, which is still counted
+        let data = gif(2);
+        assert_eq!(gif_frames(&data[..data.len() - 3]), 2);
+        assert_eq!(gif_frames(&data[..13]), 0);
+        assert_eq!(gif_frames(&data[..5]), 0);
+
+        // A global color table larger than the file
+        let mut data = gif(2);
+        data[10] = 0x87;
+        assert_eq!(gif_frames(&data), 0);
+
+        // A sub-block longer than the file
+        let mut data = gif(1);
+        data[8 + 13 + 11] = 0xff;
+        assert_eq!(gif_frames(&data), 1);
+    }
+
+    #[test]
+    fn webp_animation() {
+        assert_eq!(webp_frames(&webp(&[(b"VP8L", 5)])), 1);
+        let animation = webp(&[(b"VP8X", 10), (b"ANIM", 6), (b"ANMF", 16), (b"ANMF", 16)]);
+        assert_eq!(webp_frames(&animation), 2);
+
+        // Truncated in the second frame
+        let data = webp(&[(b"ANMF", 16), (b"ANMF", 16)]);
+        assert_eq!(webp_frames(&data[..data.len() - 20]), 1);
+        assert_eq!(webp_frames(&data[..8]), 1);
+
+        // A frame claiming more than the file
+        assert_eq!(webp_frames(&webp(&[(b"ANMF", u32::MAX), (b"ANMF", 16)])), 1);
+    }
+
+    #[test]
+    fn limits() {
+        let limits = DecodeLimits {
+            max_dimension: 100,
+            max_pixels: 1000,
+            max_frames: 10,
+            max_memory: 1024 * 1024,
+        };
+        let header = |width, height, frames| Header {
+            width,
+            height,
+            frames,
+            color_type: ColorType::Rgb8,
+        };
+
+        assert!(check(&header(100, 10, 1), &limits).is_ok());
+        assert!(check(&header(10, 10, 10), &limits).is_ok());
+        let oversized = [header(101, 1, 1), header(1, 101, 1), header(1, 1, 11), header(40, 40, 1)];
+        for header in &oversized {
+            assert!(is_too_large(&check(header, &limits).unwrap_err()));
+        }
+        // Every frame counts
+        assert!(is_too_large(&check(&header(10, 10, 11), &limits).unwrap_err()));
+        assert!(is_too_large(&check(&header(20, 10, 6), &limits).unwrap_err()));
+        assert!(check(&header(u32::MAX, u32::MAX, u32::MAX), &DecodeLimits::default()).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! Content-addressed deduplication of uploads.
+//!
+//! A deduplicated blob is stored once as `.cas/<plaintext hash>`, every upload of it gets
+//! an alias `.alias/<name>` pointing to the hash. The names referencing a hash are tracked
+//! in `.refs/<plaintext hash>` and the blob is only removed once the last one is deleted.
+//!
+//! The reference lists are updated under a lock of the store, the command line deleting blobs
+//! while the server is uploading them.
+use std::io::{self, Cursor, Read};
+
+use super::crypt::BlobHash;
+use super::downloads::downloads_name;
+use super::info;
+use super::meta::{self, meta_name};
+use super::parity::{parity_name, write_parity};
+use super::phash;
+use super::store::{BlobStore, StoreLock};
+use super::usage;
+use super::variants::delete_variants;
+
+/// Name of the lock of the reference lists.
+const REFERENCES_LOCK: &str = "references";
+
+fn content_name(hash: &str) -> String {
+    format!(".cas/{}", hash)
+}
+
+fn alias_name(name: &str) -> String {
+    format!(".alias/{}", name)
+}
+
+fn references_name(hash: &str) -> String {
+    format!(".refs/{}", hash)
+}
+
+fn read_string(store: &dyn BlobStore, name: &str) -> io::Result<String> {
+    let mut result = String::new();
+    store.get(name)?.read_to_string(&mut result)?;
+    Ok(result)
+}
+
+fn read_references(store: &dyn BlobStore, hash: &str) -> io::Result<Vec<String>> {
+    match read_string(store, &references_name(hash)) {
+        Ok(content) => Ok(content.lines().map(str::to_string).collect()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_references(store: &dyn BlobStore, hash: &str, references: &[String]) -> io::Result<()> {
+    let content = references.join("\n");
+    store.put(&references_name(hash), &mut Cursor::new(content))
+}
+
+/// Return the name of the stored blob behind the alias `name`, if `name` is an alias.
+pub fn resolve_alias(store: &dyn BlobStore, name: &str) -> io::Result<Option<String>> {
+    match read_string(store, &alias_name(name)) {
+        Ok(hash) => Ok(Some(content_name(hash.trim()))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Store `data` under `name`, reusing the existing blob if the same plaintext was already uploaded.
+///
+/// Return true if an existing blob was reused, a new one gets `parity_shards` of parity.
+pub fn put_deduplicated(
+    store: &dyn BlobStore,
+    name: &str,
+    hash: &BlobHash,
+    data: &[u8],
+    parity_shards: usize,
+) -> io::Result<bool> {
+    let hash = hex::encode(hash);
+    let content_name = content_name(&hash);
+
+    let _lock = StoreLock::acquire(store, REFERENCES_LOCK)?;
+
+    let is_duplicate = store.stat(&content_name).is_ok();
+    if !is_duplicate {
+        store.put(&content_name, &mut Cursor::new(data))?;
+
+        // Missing parity is backfilled by the scrubber, don't fail the upload for it
+        if let Err(e) = write_parity(store, &content_name, data, parity_shards) {
+            log::error!("parity generation failed for {}: {}", content_name, e);
+        }
+    }
+
+    // Uploading the same name again doesn't add a reference
+    let mut references = read_references(store, &hash)?;
+    if !references.iter().any(|reference| reference == name) {
+        references.push(name.to_string());
+        write_references(store, &hash, &references)?;
+    }
+
+    store.put(&alias_name(name), &mut Cursor::new(hash))?;
+
+    Ok(is_duplicate)
+}
+
+/// Delete the blob `name`, a deduplicated blob is only removed with its last alias.
+pub fn delete(store: &dyn BlobStore, name: &str) -> io::Result<()> {
+    let _lock = StoreLock::acquire(store, REFERENCES_LOCK)?;
+
+    // Blobs uploaded before metadata existed have none
+    if let Some(meta) = meta::read(store, name)? {
+        if let Some(ref user) = meta.user {
+            usage::release(store, user, meta.size)?;
+        }
+        if meta.max_downloads.is_some() {
+            store.delete(&downloads_name(name)).ok();
+        }
+        store.delete(&meta_name(name))?;
+    }
+    phash::delete(store, name)?;
+
+    let hash = match read_string(store, &alias_name(name)) {
+        Ok(hash) => hash.trim().to_string(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            // Not deduplicated
+            store.delete(name)?;
+            store.delete(&parity_name(name)).ok();
+            delete_variants(store, name)?;
+            info::delete(store, name)?;
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    store.delete(&alias_name(name))?;
+
+    let mut references = read_references(store, &hash)?;
+    references.retain(|reference| reference != name);
+
+    if references.is_empty() {
+        let content_name = content_name(&hash);
+        store.delete(&content_name)?;
+        store.delete(&parity_name(&content_name)).ok();
+        delete_variants(store, &content_name)?;
+        info::delete(store, &content_name)?;
+        store.delete(&references_name(&hash))?;
+    } else {
+        write_references(store, &hash, &references)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::store::{BlobStore, MemoryBlobStore};
+    use super::{delete, put_deduplicated, read_references, resolve_alias};
+
+    #[test]
+    fn references() {
+        let store = MemoryBlobStore::default();
+        let hash = [7; 32];
+        let content_name = format!(".cas/{}", hex::encode(hash));
+
+        assert!(!put_deduplicated(&store, "a.png", &hash, b"blob", 8).unwrap());
+        assert!(put_deduplicated(&store, "b.png", &hash, b"blob", 8).unwrap());
+        // Uploaded again under the same name
+        assert!(put_deduplicated(&store, "a.png", &hash, b"blob", 8).unwrap());
+        assert_eq!(read_references(&store, &hex::encode(hash)).unwrap(), ["a.png", "b.png"]);
+        assert_eq!(resolve_alias(&store, "b.png").unwrap(), Some(content_name.clone()));
+
+        delete(&store, "a.png").unwrap();
+        assert_eq!(resolve_alias(&store, "a.png").unwrap(), None);
+        assert!(store.stat(&content_name).is_ok());
+
+        delete(&store, "b.png").unwrap();
+        assert!(store.stat(&content_name).is_err());
+        assert!(store.list(".").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,337 @@
+//! Uploads with a limited amount of downloads, a limit of 1 is burn-after-read.
+//!
+//! Downloads of a limited blob are counted in `.downloads/<name>`. A counted download starts a
+//! download session, a cookie holding a random nonce signed like the signed URLs. The nonces of
+//! the open sessions are kept with the count: the Range continuations of a session aren't counted
+//! again, so a video playback counts once. HEAD requests are never counted, Range requests without
+//! a session are counted like any download.
+//!
+//! Once the last download is counted the blob is only served to the open sessions. The burner
+//! deletes it when they expire and a tombstone `.burned/<name>` makes later requests answer 410.
+use std::io::{self, Cursor, Read};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::HttpMessage;
+
+use lazy_static::lazy_static;
+
+use rand::RngCore;
+
+use super::crypt::BlobKey;
+use super::dedup;
+use super::meta::meta_name;
+use super::signed;
+use super::store::{BlobStore, SharedBlobStore};
+
+/// How long a download session lasts, and how long an exhausted blob is kept for it.
+pub const DOWNLOAD_SESSION_LIFETIME: u64 = 30 * 60;
+
+/// Name of the cookie of a download session, its path is the one of the blob.
+pub const DOWNLOAD_COOKIE: &str = "imagers_download";
+
+/// Delay between two searches of the exhausted blobs to delete.
+const BURN_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+lazy_static! {
+    /// Serialize updates of the download counters.
+    static ref DOWNLOADS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// How a request to a limited blob is handled.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    /// Served as a new download, the session with this nonce starts.
+    Counted(String),
+    /// Served as part of a session or without content.
+    Uncounted,
+    /// The blob is exhausted or deleted.
+    Gone,
+}
+
+#[derive(Default)]
+struct Downloads {
+    count: u64,
+    /// Unix time of the last allowed download.
+    exhausted: Option<u64>,
+    /// Nonces of the open sessions, with their expiry.
+    sessions: Vec<(String, u64)>,
+}
+
+pub fn downloads_name(name: &str) -> String {
+    format!(".downloads/{}", name)
+}
+
+fn burned_name(name: &str) -> String {
+    format!(".burned/{}", name)
+}
+
+/// Return the key of the download session cookies of blobs encrypted with `key`.
+pub fn cookie_key(key: &BlobKey) -> [u8; 32] {
+    key.derive("imagers download session")
+}
+
+/// The cookies of a session are signed for the blob and the nonce together.
+fn session_name(name: &str, nonce: &str) -> String {
+    format!("{}\n{}", name, nonce)
+}
+
+/// Return the `Set-Cookie` value of the session `nonce` of `name`, served at `path`.
+pub fn session_cookie(key: &BlobKey, name: &str, nonce: &str, path: &str, now: u64, secure: bool) -> String {
+    let signature = signed::signed_query(
+        &cookie_key(key),
+        &session_name(name, nonce),
+        now + DOWNLOAD_SESSION_LIFETIME,
+    );
+    let value = format!("session={}&{}", nonce, signature);
+    signed::cookie_header(DOWNLOAD_COOKIE, &value, path, DOWNLOAD_SESSION_LIFETIME, secure)
+}
+
+/// Return the nonce of the session of `name` the request `req` belongs to, if any.
+///
+/// The session might have ended since, `record` checks it is still open.
+pub fn session<R: HttpMessage>(req: &R, key: &BlobKey, name: &str, now: u64) -> Option<String> {
+    let cookie_key = cookie_key(key);
+    let cookies = req.cookies().ok()?;
+
+    // Cookies of parent paths are sent too, they are signed for another name
+    cookies
+        .iter()
+        .filter(|candidate| candidate.name() == DOWNLOAD_COOKIE)
+        .find_map(|candidate| {
+            let nonce = candidate
+                .value()
+                .split('&')
+                .find_map(|parameter| parameter.strip_prefix("session="))?;
+            signed::verify(&cookie_key, &session_name(name, nonce), candidate.value(), now).ok()?;
+            Some(nonce.to_string())
+        })
+}
+
+/// Return true if `name` was deleted after reaching its download limit.
+pub fn is_burned(store: &dyn BlobStore, name: &str) -> bool {
+    store.stat(&burned_name(name)).is_ok()
+}
+
+fn read(store: &dyn BlobStore, name: &str) -> io::Result<Downloads> {
+    let mut content = String::new();
+    match store.get(&downloads_name(name)) {
+        Ok(mut file) => file.read_to_string(&mut content)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Downloads::default()),
+        Err(e) => return Err(e),
+    };
+
+    let mut downloads = Downloads::default();
+    for line in content.lines() {
+        if let Some(count) = line.strip_prefix("count=") {
+            downloads.count = count.parse().unwrap_or(0);
+        } else if let Some(exhausted) = line.strip_prefix("exhausted=") {
+            downloads.exhausted = exhausted.parse().ok();
+        } else if let Some(session) = line.strip_prefix("session=") {
+            let mut parts = session.splitn(2, ' ');
+            if let (Some(nonce), Some(Ok(expires))) = (parts.next(), parts.next().map(str::parse)) {
+                downloads.sessions.push((nonce.to_string(), expires));
+            }
+        }
+    }
+
+    Ok(downloads)
+}
+
+fn write(store: &dyn BlobStore, name: &str, downloads: &Downloads) -> io::Result<()> {
+    let mut content = format!("count={}\n", downloads.count);
+    if let Some(exhausted) = downloads.exhausted {
+        content.push_str(&format!("exhausted={}\n", exhausted));
+    }
+    for (nonce, expires) in &downloads.sessions {
+        content.push_str(&format!("session={} {}\n", nonce, expires));
+    }
+    store.put(&downloads_name(name), &mut Cursor::new(content))
+}
+
+/// Delete the exhausted blob `name` and leave its tombstone.
+fn burn(store: &dyn BlobStore, name: &str) -> io::Result<()> {
+    store.put(&burned_name(name), &mut Cursor::new(Vec::new()))?;
+    dedup::delete(store, name)?;
+    log::info!("Downloads: {} reached its download limit and was deleted", name);
+    Ok(())
+}
+
+/// Account for a request of `name`, limited to `max_downloads`.
+///
+/// `is_download` tells if the request serves content, `session` is the nonce of its session.
+pub fn record(
+    store: &dyn BlobStore,
+    name: &str,
+    max_downloads: u64,
+    is_download: bool,
+    session: Option<&str>,
+    now: u64,
+) -> io::Result<Access> {
+    let _lock = DOWNLOADS_LOCK.lock().unwrap();
+
+    let mut downloads = read(store, name)?;
+    downloads.sessions.retain(|&(_, expires)| expires > now);
+    let has_session = downloads
+        .sessions
+        .iter()
+        .any(|(nonce, _)| Some(nonce.as_str()) == session);
+
+    if let Some(exhausted) = downloads.exhausted {
+        // The burner didn't run yet
+        if now >= exhausted + DOWNLOAD_SESSION_LIFETIME {
+            burn(store, name)?;
+            return Ok(Access::Gone);
+        }
+
+        return Ok(if has_session { Access::Uncounted } else { Access::Gone });
+    }
+
+    if has_session || !is_download {
+        return Ok(Access::Uncounted);
+    }
+
+    let mut random = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut random);
+    let nonce = hex::encode(random);
+
+    downloads.count += 1;
+    downloads.sessions.push((nonce.clone(), now + DOWNLOAD_SESSION_LIFETIME));
+    if downloads.count >= max_downloads {
+        // Let the sessions of the last downloads finish
+        downloads.exhausted = Some(now);
+    }
+
+    write(store, name, &downloads)?;
+    Ok(Access::Counted(nonce))
+}
+
+/// Delete the exhausted blobs of `store` whose last sessions expired at `now`.
+pub fn burn_exhausted(store: &dyn BlobStore, now: u64) -> io::Result<()> {
+    for downloads_name in store.list(".downloads/")? {
+        let name = match downloads_name.strip_prefix(".downloads/") {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let _lock = DOWNLOADS_LOCK.lock().unwrap();
+        let exhausted = match read(store, name)?.exhausted {
+            Some(exhausted) => exhausted,
+            None => continue,
+        };
+        // Already burned or deleted, deduplicated names only exist through their metadata
+        if now < exhausted + DOWNLOAD_SESSION_LIFETIME
+            || is_burned(store, name)
+            || store.stat(&meta_name(name)).is_err()
+        {
+            continue;
+        }
+        if let Err(e) = burn(store, name) {
+            log::error!("Downloads: cannot delete {}: {}", name, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete the exhausted blobs of `store` in the background, once their sessions expire.
+pub fn spawn_burner(store: SharedBlobStore) {
+    thread::spawn(move || loop {
+        thread::sleep(BURN_INTERVAL);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        if let Err(e) = burn_exhausted(&*store, now) {
+            log::error!("Downloads: cannot delete the exhausted blobs: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::super::meta::{self, BlobMeta};
+    use super::super::store::{BlobStore, MemoryBlobStore};
+    use super::{burn_exhausted, is_burned, record, Access, DOWNLOAD_SESSION_LIFETIME};
+
+    const NOW: u64 = 1_000_000;
+
+    fn limited(store: &dyn BlobStore, name: &str, max_downloads: u64) {
+        store.put(name, &mut Cursor::new(b"blob".to_vec())).unwrap();
+        let meta = BlobMeta {
+            max_downloads: Some(max_downloads),
+            ..BlobMeta::default()
+        };
+        meta::write(store, name, &meta).unwrap();
+    }
+
+    fn counted(access: Access) -> String {
+        match access {
+            Access::Counted(nonce) => nonce,
+            access => panic!("{:?} isn't counted", access),
+        }
+    }
+
+    #[test]
+    fn counting() {
+        let store = MemoryBlobStore::default();
+        limited(&store, "a.png", 2);
+
+        let first = counted(record(&store, "a.png", 2, true, None, NOW).unwrap());
+        let second = counted(record(&store, "a.png", 2, true, None, NOW).unwrap());
+        assert_ne!(first, second);
+        assert_eq!(record(&store, "a.png", 2, true, None, NOW).unwrap(), Access::Gone);
+
+        // Both sessions stay open until they expire
+        assert_eq!(record(&store, "a.png", 2, true, Some(&first), NOW).unwrap(), Access::Uncounted);
+        assert_eq!(record(&store, "a.png", 2, true, Some(&second), NOW).unwrap(), Access::Uncounted);
+    }
+
+    #[test]
+    fn uncounted() {
+        let store = MemoryBlobStore::default();
+        limited(&store, "a.png", 3);
+
+        // HEAD requests
+        assert_eq!(record(&store, "a.png", 3, false, None, NOW).unwrap(), Access::Uncounted);
+
+        // Range continuations of a session
+        let session = counted(record(&store, "a.png", 3, true, None, NOW).unwrap());
+        assert_eq!(record(&store, "a.png", 3, true, Some(&session), NOW).unwrap(), Access::Uncounted);
+
+        // Unknown and expired sessions are counted
+        counted(record(&store, "a.png", 3, true, Some("unknown"), NOW).unwrap());
+        let expired = NOW + DOWNLOAD_SESSION_LIFETIME;
+        counted(record(&store, "a.png", 3, true, Some(&session), expired).unwrap());
+        assert_eq!(record(&store, "a.png", 3, true, None, expired).unwrap(), Access::Gone);
+    }
+
+    #[test]
+    fn burn() {
+        let store = MemoryBlobStore::default();
+        limited(&store, "a.png", 1);
+        limited(&store, "b.png", 2);
+
+        let session = counted(record(&store, "a.png", 1, true, None, NOW).unwrap());
+        counted(record(&store, "b.png", 2, true, None, NOW).unwrap());
+
+        // Kept for the open session
+        burn_exhausted(&store, NOW + 1).unwrap();
+        assert!(store.stat("a.png").is_ok());
+        assert_eq!(record(&store, "a.png", 1, true, Some(&session), NOW + 1).unwrap(), Access::Uncounted);
+
+        let expired = NOW + DOWNLOAD_SESSION_LIFETIME;
+        burn_exhausted(&store, expired).unwrap();
+        assert!(is_burned(&store, "a.png"));
+        assert!(store.stat("a.png").is_err());
+        assert!(store.stat(".downloads/a.png").is_err());
+
+        // Not exhausted
+        assert!(store.stat("b.png").is_ok());
+        assert!(!is_burned(&store, "b.png"));
+    }
+}
@@ -7,12 +7,37 @@ use derive_more::Display;
 pub enum CryptFilesError {
     #[display(fmt = "Nothing to see here")]
     IsDirectory,
+    /// The blob is private and the request isn't signed, or not correctly.
+    #[display(fmt = "Invalid signature")]
+    InvalidSignature,
+    /// The blob is private and the signature expired.
+    #[display(fmt = "Expired signature")]
+    ExpiredSignature,
+    /// The blob reached its download limit.
+    #[display(fmt = "Gone")]
+    Gone,
+    /// The resize or format parameters are invalid.
+    #[display(fmt = "Invalid image parameters")]
+    InvalidVariant,
+    /// A variant was requested for a blob that isn't a supported image.
+    #[display(fmt = "Not an image")]
+    NotAnImage,
+    /// A variant was requested for an image over the decoding limits.
+    #[display(fmt = "Image too large")]
+    ImageTooLarge,
 }
 
-/// Return `Forbidden` for `CryptFilesError`
+/// Return `Gone` for a blob that reached its download limit, `BadRequest`,
+/// `UnsupportedMediaType` and `UnprocessableEntity` for invalid variants, `Forbidden` otherwise
 impl ResponseError for CryptFilesError {
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::new(StatusCode::FORBIDDEN)
+        match self {
+            CryptFilesError::Gone => HttpResponse::new(StatusCode::GONE),
+            CryptFilesError::InvalidVariant => HttpResponse::new(StatusCode::BAD_REQUEST),
+            CryptFilesError::NotAnImage => HttpResponse::new(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            CryptFilesError::ImageTooLarge => HttpResponse::new(StatusCode::UNPROCESSABLE_ENTITY),
+            _ => HttpResponse::new(StatusCode::FORBIDDEN),
+        }
     }
 }
 
@@ -1,5 +1,3 @@
-use std::fs::File;
-use std::fs::OpenOptions;
 use std::io;
 use std::path::Path;
 
@@ -13,23 +11,41 @@ use actix_web::{Error, HttpRequest, HttpResponse, Responder};
 
 use actix_files::HttpRange;
 
-use super::crypt::EncryptedBlob;
+use super::crypt::{BlobKey, EncryptedBlob};
 
 use super::chunked_stream::ChunkedReadStream;
 
+use super::store::{BlobObject, BlobStore};
+
+/// Return the content type of the blob at `path`, guessed from its extension.
+pub fn content_type(path: &Path) -> mime::Mime {
+    // Too recent for mime_guess
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("avif") => "image/avif".parse().unwrap(),
+        _ => guess_mime_type(path),
+    }
+}
+
 /// A file to decrypt with a name.
-#[derive(Debug)]
 pub struct ChunkedCryptFile {
-    file: File,
+    file: Box<dyn BlobObject>,
+    key: BlobKey,
     content_type: mime::Mime,
     content_disposition: header::ContentDisposition,
     file_length: u64,
     encoding: Option<ContentEncoding>,
+    /// Also respond to POST, used by the password prompt.
+    accept_post: bool,
 }
 
 impl ChunkedCryptFile {
-    pub fn from_file<P: AsRef<Path>>(file: File, path: P) -> io::Result<Self> {
-        let path = path.as_ref().to_path_buf();
+    pub fn from_blob(
+        file: Box<dyn BlobObject>,
+        key: BlobKey,
+        name: &str,
+        file_length: u64,
+    ) -> io::Result<Self> {
+        let path = Path::new(name);
 
         let (content_type, content_disposition) = {
             let filename = match path.file_name() {
@@ -42,7 +58,7 @@ impl ChunkedCryptFile {
                 }
             };
 
-            let ct = guess_mime_type(&path);
+            let ct = content_type(path);
             let disposition_type = match ct.type_() {
                 mime::IMAGE | mime::TEXT | mime::VIDEO => DispositionType::Inline,
                 _ => DispositionType::Attachment,
@@ -54,21 +70,33 @@ impl ChunkedCryptFile {
             (ct, cd)
         };
 
-        let md = file.metadata()?;
         let encoding = None;
         Ok(ChunkedCryptFile {
             file,
+            key,
             content_type,
             content_disposition,
-            file_length: md.len(),
+            file_length,
             encoding,
+            accept_post: false,
         })
     }
 
-    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-        let mut option = OpenOptions::new();
-        option.write(true).read(true);
-        Self::from_file(option.open(&path)?, path)
+    /// Open the blob `storage_name`, its content type is guessed from `name`.
+    pub fn open(
+        store: &dyn BlobStore,
+        key: BlobKey,
+        storage_name: &str,
+        name: &str,
+    ) -> io::Result<Self> {
+        let file_length = store.stat(storage_name)?.len;
+        Self::from_blob(store.get(storage_name)?, key, name, file_length)
+    }
+
+    /// Respond to POST like to GET.
+    pub fn accept_post(mut self) -> Self {
+        self.accept_post = true;
+        self
     }
 }
 
@@ -79,6 +107,7 @@ impl Responder for ChunkedCryptFile {
     fn respond_to(self, req: &HttpRequest) -> Self::Future {
         match *req.method() {
             Method::HEAD | Method::GET => (),
+            Method::POST if self.accept_post => (),
             _ => {
                 return Ok(HttpResponse::MethodNotAllowed()
                     .header(header::CONTENT_TYPE, "text/plain")
@@ -94,7 +123,7 @@ impl Responder for ChunkedCryptFile {
                 self.content_disposition.to_string(),
             );
 
-        let mut encrypted_file = EncryptedBlob::from(self.file)?;
+        let mut encrypted_file = EncryptedBlob::from(self.file, self.key)?;
 
         let encrypted_is_valid = encrypted_file.is_header_magic_valid();
 
@@ -149,7 +178,7 @@ impl Responder for ChunkedCryptFile {
         if *req.method() == Method::HEAD {
             Ok(resp.finish())
         } else if encrypted_is_valid {
-                let reader = ChunkedReadStream::new(offset, length, EncryptedBlob::from(file)?);
+                let reader = ChunkedReadStream::new(offset, length, EncryptedBlob::from(file, self.key)?);
                 if offset != 0 || length != file_length {
                     return Ok(resp.status(StatusCode::PARTIAL_CONTENT).streaming(reader));
                 };
@@ -0,0 +1,337 @@
+//! Description of blobs, served as JSON by `<name>/info`.
+//!
+//! The description of an image is read from the start of its plaintext, except for GIF and WebP
+//! whose frames are spread over the whole file. The format is detected from the content, as a
+//! deduplicated blob can be served under names with other extensions. The description is
+//! computed once and cached as `.info/<stored blob>`, a list of `key=value` lines like the
+//! metadata.
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+use image::{ImageFormat, ImageResult};
+
+use serde::Serialize;
+
+use sha2::{Digest, Sha256};
+
+use super::crypt::{BlobKey, EncryptedBlob};
+use super::decode;
+use super::store::BlobStore;
+
+/// Plaintext decrypted to describe a JPEG or PNG, enough for large EXIF segments.
+const HEADER_REGION_SIZE: usize = 1024 * 1024;
+
+/// Plaintext decrypted to detect the format of a blob.
+const SIGNATURE_SIZE: usize = 16;
+
+/// The description of a blob, fields about images are `None` for other blobs.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct BlobInfo {
+    pub format: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Amount of frames, 1 unless the image is animated.
+    pub frames: Option<u32>,
+    pub color_type: Option<String>,
+    /// Size of the plaintext.
+    pub size: u64,
+    /// SHA-256 of the plaintext, in hexadecimal.
+    pub sha256: String,
+    /// Unix time of the upload, `None` for blobs uploaded before metadata existed.
+    pub uploaded: Option<u64>,
+    /// BlurHash of the image, from the metadata.
+    pub blurhash: Option<String>,
+    /// Dominant color of the image as `#rrggbb`, from the metadata.
+    pub dominant_color: Option<String>,
+}
+
+fn info_name(storage_name: &str) -> String {
+    format!(".info/{}", storage_name)
+}
+
+fn read_cached(store: &dyn BlobStore, storage_name: &str) -> io::Result<Option<BlobInfo>> {
+    let mut content = String::new();
+    match store.get(&info_name(storage_name)) {
+        Ok(mut file) => file.read_to_string(&mut content)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut info = BlobInfo::default();
+    for line in content.lines() {
+        if let Some(format) = line.strip_prefix("format=") {
+            info.format = Some(format.to_string());
+        } else if let Some(width) = line.strip_prefix("width=") {
+            info.width = width.parse().ok();
+        } else if let Some(height) = line.strip_prefix("height=") {
+            info.height = height.parse().ok();
+        } else if let Some(frames) = line.strip_prefix("frames=") {
+            info.frames = frames.parse().ok();
+        } else if let Some(color_type) = line.strip_prefix("color_type=") {
+            info.color_type = Some(color_type.to_string());
+        } else if let Some(size) = line.strip_prefix("size=") {
+            info.size = size.parse().unwrap_or(0);
+        } else if let Some(sha256) = line.strip_prefix("sha256=") {
+            info.sha256 = sha256.to_string();
+        }
+    }
+
+    Ok(Some(info))
+}
+
+fn write_cached(store: &dyn BlobStore, storage_name: &str, info: &BlobInfo) -> io::Result<()> {
+    let mut content = format!("size={}\nsha256={}\n", info.size, info.sha256);
+    if let Some(ref format) = info.format {
+        content.push_str(&format!("format={}\n", format));
+    }
+    if let Some(width) = info.width {
+        content.push_str(&format!("width={}\n", width));
+    }
+    if let Some(height) = info.height {
+        content.push_str(&format!("height={}\n", height));
+    }
+    if let Some(frames) = info.frames {
+        content.push_str(&format!("frames={}\n", frames));
+    }
+    if let Some(ref color_type) = info.color_type {
+        content.push_str(&format!("color_type={}\n", color_type));
+    }
+
+    store.put(&info_name(storage_name), &mut Cursor::new(content))
+}
+
+/// Delete the cached description of the blob `storage_name`.
+pub fn delete(store: &dyn BlobStore, storage_name: &str) -> io::Result<()> {
+    match store.delete(&info_name(storage_name)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Fill the image fields of `info` from the start of the image `data` in `format`.
+fn describe_image(info: &mut BlobInfo, data: &[u8], format: ImageFormat) -> ImageResult<()> {
+    let header = match decode::read_header(data, format)? {
+        Some(header) => header,
+        None => return Ok(()),
+    };
+
+    info.format = Some(format.extensions_str()[0].to_string());
+    info.width = Some(header.width);
+    info.height = Some(header.height);
+    info.frames = Some(header.frames);
+    info.color_type = Some(format!("{:?}", header.color_type).to_lowercase());
+    Ok(())
+}
+
+fn compute(store: &dyn BlobStore, key: BlobKey, storage_name: &str, name: &str) -> io::Result<BlobInfo> {
+    let mut info = BlobInfo::default();
+    let mut encrypted_blob = EncryptedBlob::from(store.get(storage_name)?, key)?;
+
+    let (format, data) = if encrypted_blob.is_header_magic_valid() {
+        info.size = encrypted_blob.get_unpadded_size()?;
+        info.sha256 = hex::encode(encrypted_blob.hash()?);
+
+        let mut signature = vec![0; SIGNATURE_SIZE.min(info.size as usize)];
+        encrypted_blob.seek(SeekFrom::Start(0))?;
+        encrypted_blob.read_exact(&mut signature)?;
+        let format = decode::content_format(&signature);
+
+        let padded_size = encrypted_blob.get_padded_size() as usize;
+        let data = match format {
+            None => Vec::new(),
+            // Reading a prefix leaves the last block and its padding out. It continues after the
+            // signature, an encrypted blob can't seek backwards.
+            Some(ImageFormat::Png) | Some(ImageFormat::Jpeg) if padded_size > HEADER_REGION_SIZE => {
+                let mut data = signature;
+                data.resize(HEADER_REGION_SIZE, 0);
+                encrypted_blob.read_exact(&mut data[SIGNATURE_SIZE..])?;
+                data
+            }
+            Some(_) => encrypted_blob
+                .decrypted_data()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Cannot decrypt the blob"))?,
+        };
+        (format, data)
+    } else {
+        // Unencrypted blobs are served as is
+        let mut file = encrypted_blob.into_inner();
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut data)?;
+        info.size = data.len() as u64;
+        info.sha256 = hex::encode(Sha256::digest(&data));
+        (decode::content_format(&data), data)
+    };
+
+    if let Some(format) = format {
+        // Not an image after all, only the plaintext is described
+        if let Err(e) = describe_image(&mut info, &data, format) {
+            log::debug!("Info: cannot decode {}: {}", name, e);
+        }
+    }
+
+    Ok(info)
+}
+
+/// Return the description of the blob `storage_name` served as `name`, computed if not cached.
+///
+/// `name` is only used in logs, the format is detected from the content.
+///
+/// The fields from the metadata are left to the caller, deduplicated blobs share their description.
+pub fn read(
+    store: &dyn BlobStore,
+    keys: &[BlobKey],
+    storage_name: &str,
+    name: &str,
+) -> io::Result<BlobInfo> {
+    if let Some(info) = read_cached(store, storage_name)? {
+        return Ok(info);
+    }
+
+    let key = super::blob_key(store, keys, storage_name)?;
+    let info = compute(store, key, storage_name, name)?;
+    write_cached(store, storage_name, &info)?;
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use image::{DynamicImage, ImageFormat, RgbImage};
+
+    use sha2::{Digest, Sha256};
+
+    use super::super::store::{BlobStore, MemoryBlobStore};
+    use super::*;
+
+    fn key() -> BlobKey {
+        BlobKey::new(&"00".repeat(32), "IMAGERS0").unwrap()
+    }
+
+    fn image(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn png() {
+        let store = MemoryBlobStore::default();
+        let png = image(40, 30, ImageFormat::Png);
+        store.put("photo.jpg", &mut Cursor::new(key().encrypt(&png))).unwrap();
+
+        // Detected from the content, not the extension
+        let info = compute(&store, key(), "photo.jpg", "photo.jpg").unwrap();
+        assert_eq!(info.format.as_deref(), Some("png"));
+        assert_eq!((info.width, info.height, info.frames), (Some(40), Some(30), Some(1)));
+        assert_eq!(info.color_type.as_deref(), Some("rgb8"));
+        assert_eq!(info.size, png.len() as u64);
+        assert_eq!(info.sha256, hex::encode(Sha256::digest(&png)));
+    }
+
+    #[test]
+    fn large_jpeg() {
+        let store = MemoryBlobStore::default();
+        // Only the start of the blob is decrypted, the end isn't needed to describe it
+        let mut jpeg = image(64, 48, ImageFormat::Jpeg);
+        jpeg.resize(HEADER_REGION_SIZE * 2 + 5, 0);
+        store.put("large.jpg", &mut Cursor::new(key().encrypt(&jpeg))).unwrap();
+
+        let info = compute(&store, key(), "large.jpg", "large.jpg").unwrap();
+        assert_eq!(info.format.as_deref(), Some("jpg"));
+        assert_eq!((info.width, info.height, info.frames), (Some(64), Some(48), Some(1)));
+        assert_eq!(info.size, jpeg.len() as u64);
+        assert_eq!(info.sha256, hex::encode(Sha256::digest(&jpeg)));
+    }
+
+    #[test]
+    fn not_an_image() {
+        let store = MemoryBlobStore::default();
+        store.put("notes.png", &mut Cursor::new(key().encrypt(b"hello"))).unwrap();
+        // A PNG signature followed by garbage
+        let mut broken = image(4, 4, ImageFormat::Png)[..SIGNATURE_SIZE].to_vec();
+        broken.extend_from_slice(&[0xff; 64]);
+        store.put("broken.png", &mut Cursor::new(key().encrypt(&broken))).unwrap();
+
+        for (name, data) in &[("notes.png", &b"hello"[..]), ("broken.png", &broken)] {
+            let info = compute(&store, key(), name, name).unwrap();
+            assert_eq!(info.format, None);
+            assert_eq!(
+                (info.width, info.height, info.frames, info.color_type),
+                (None, None, None, None)
+            );
+            assert_eq!(info.size, data.len() as u64);
+            assert_eq!(info.sha256, hex::encode(Sha256::digest(data)));
+        }
+
+        // Unencrypted blobs are described as is
+        let png = image(4, 2, ImageFormat::Png);
+        store.put("raw.png", &mut Cursor::new(&png)).unwrap();
+        let info = compute(&store, key(), "raw.png", "raw.png").unwrap();
+        assert_eq!(
+            (info.format.as_deref(), info.width, info.height),
+            (Some("png"), Some(4), Some(2))
+        );
+        assert_eq!(info.size, png.len() as u64);
+    }
+
+    #[test]
+    fn cache() {
+        let store = MemoryBlobStore::default();
+        assert!(read_cached(&store, "photo.png").unwrap().is_none());
+
+        let info = BlobInfo {
+            format: Some("gif".to_string()),
+            width: Some(10),
+            height: Some(20),
+            frames: Some(3),
+            color_type: Some("rgba8".to_string()),
+            size: 1234,
+            sha256: "ab".repeat(32),
+            uploaded: Some(1),
+            blurhash: Some("LEHV6nWB2yk8pyo0adR*.7kCMdnj".to_string()),
+            dominant_color: Some("#000000".to_string()),
+        };
+        write_cached(&store, "photo.png", &info).unwrap();
+        let cached = read_cached(&store, "photo.png").unwrap().unwrap();
+        assert_eq!(cached.format, info.format);
+        assert_eq!((cached.width, cached.height, cached.frames), (Some(10), Some(20), Some(3)));
+        assert_eq!(cached.color_type, info.color_type);
+        assert_eq!((cached.size, cached.sha256), (info.size, info.sha256));
+        // The metadata fields are left to the caller
+        assert_eq!((cached.uploaded, cached.blurhash, cached.dominant_color), (None, None, None));
+
+        let empty = BlobInfo { size: 5, sha256: "cd".repeat(32), ..BlobInfo::default() };
+        write_cached(&store, "notes.txt", &empty).unwrap();
+        let cached = read_cached(&store, "notes.txt").unwrap().unwrap();
+        assert_eq!(
+            (cached.format, cached.width, cached.frames, cached.color_type),
+            (None, None, None, None)
+        );
+        assert_eq!((cached.size, cached.sha256), (5, "cd".repeat(32)));
+
+        delete(&store, "notes.txt").unwrap();
+        delete(&store, "notes.txt").unwrap();
+        assert!(read_cached(&store, "notes.txt").unwrap().is_none());
+    }
+
+    #[test]
+    fn read_computes_once() {
+        let store = MemoryBlobStore::default();
+        let png = image(8, 8, ImageFormat::Png);
+        store.put("photo.png", &mut Cursor::new(key().encrypt(&png))).unwrap();
+
+        let info = read(&store, &[key()], "photo.png", "photo.png").unwrap();
+        assert_eq!((info.width, info.height), (Some(8), Some(8)));
+        let mut cached = String::new();
+        store.get(&info_name("photo.png")).unwrap().read_to_string(&mut cached).unwrap();
+        assert!(cached.contains("width=8\n"));
+
+        // Served from the cache, even if the blob is gone
+        store.delete("photo.png").unwrap();
+        let info = read(&store, &[key()], "photo.png", "photo.png").unwrap();
+        assert_eq!((info.format.as_deref(), info.width), (Some("png"), Some(8)));
+    }
+}
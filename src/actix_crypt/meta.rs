@@ -0,0 +1,87 @@
+//! Metadata of uploads, stored as `.meta/<name>` next to the blob.
+//!
+//! The content is a list of `key=value` lines, unknown keys are ignored.
+use std::io::{self, Cursor, Read};
+
+use super::store::BlobStore;
+
+#[derive(Debug, Default, Clone)]
+pub struct BlobMeta {
+    /// Identity of the API token used to upload the blob.
+    pub user: Option<String>,
+    /// Unix time of the upload.
+    pub uploaded: u64,
+    /// Size of the stored blob, as charged to the quota of its user.
+    pub size: u64,
+    /// Only serve the blob with a signed URL.
+    pub private: bool,
+    /// Argon2 hash of the password protecting the blob.
+    pub password: Option<String>,
+    /// Downloads allowed before the blob is deleted.
+    pub max_downloads: Option<u64>,
+    /// BlurHash of the image, shown while it loads.
+    pub blurhash: Option<String>,
+    /// Dominant color of the image, as `#rrggbb`.
+    pub dominant_color: Option<String>,
+}
+
+pub fn meta_name(name: &str) -> String {
+    format!(".meta/{}", name)
+}
+
+/// Return the metadata of the blob `name`, `None` if it was uploaded before metadata existed.
+pub fn read(store: &dyn BlobStore, name: &str) -> io::Result<Option<BlobMeta>> {
+    let mut content = String::new();
+    match store.get(&meta_name(name)) {
+        Ok(mut file) => file.read_to_string(&mut content)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut meta = BlobMeta::default();
+    for line in content.lines() {
+        if let Some(user) = line.strip_prefix("user=") {
+            meta.user = Some(user.to_string());
+        } else if let Some(uploaded) = line.strip_prefix("uploaded=") {
+            meta.uploaded = uploaded.parse().unwrap_or(0);
+        } else if let Some(size) = line.strip_prefix("size=") {
+            meta.size = size.parse().unwrap_or(0);
+        } else if line == "private=true" {
+            meta.private = true;
+        } else if let Some(password) = line.strip_prefix("password=") {
+            meta.password = Some(password.to_string());
+        } else if let Some(max_downloads) = line.strip_prefix("max_downloads=") {
+            meta.max_downloads = max_downloads.parse().ok();
+        } else if let Some(blurhash) = line.strip_prefix("blurhash=") {
+            meta.blurhash = Some(blurhash.to_string());
+        } else if let Some(dominant_color) = line.strip_prefix("dominant_color=") {
+            meta.dominant_color = Some(dominant_color.to_string());
+        }
+    }
+
+    Ok(Some(meta))
+}
+
+pub fn write(store: &dyn BlobStore, name: &str, meta: &BlobMeta) -> io::Result<()> {
+    let mut content = format!("uploaded={}\nsize={}\n", meta.uploaded, meta.size);
+    if let Some(ref user) = meta.user {
+        content.push_str(&format!("user={}\n", user));
+    }
+    if meta.private {
+        content.push_str("private=true\n");
+    }
+    if let Some(ref password) = meta.password {
+        content.push_str(&format!("password={}\n", password));
+    }
+    if let Some(max_downloads) = meta.max_downloads {
+        content.push_str(&format!("max_downloads={}\n", max_downloads));
+    }
+    if let Some(ref blurhash) = meta.blurhash {
+        content.push_str(&format!("blurhash={}\n", blurhash));
+    }
+    if let Some(ref dominant_color) = meta.dominant_color {
+        content.push_str(&format!("dominant_color={}\n", dominant_color));
+    }
+
+    store.put(&meta_name(name), &mut Cursor::new(content))
+}
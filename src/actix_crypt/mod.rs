@@ -1,26 +1,64 @@
 //! Custom actix_files that actually work the way I need it.
 use std::cell::RefCell;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 mod chunked_stream;
 mod crypt;
+pub mod decode;
+pub mod dedup;
+pub mod downloads;
 mod error;
 mod file;
+mod html;
+mod info;
+pub mod meta;
+pub mod oembed;
+mod parity;
+pub mod password;
+pub mod phash;
+pub mod placeholder;
+mod preview;
+pub mod sanitize;
+pub mod signed;
+pub mod store;
+pub mod tokens;
+pub mod usage;
+pub mod variants;
+pub mod watermark;
 
-pub use crypt::EncryptedBlob;
+pub use crypt::{
+    announced_blob_size, header_initial_vector, BlobHash, BlobInitialVector, BlobKey, EncryptedBlob,
+    HEADER_PREFIX_SIZE,
+};
+pub use parity::{parity_name, repair, write_parity, RepairOutcome, MAX_PARITY_SHARDS};
+
+use store::{BlobStore, SharedBlobStore};
+
+use crate::config::{SharedConfig, Tenant};
 
 use actix_service::boxed::{BoxedNewService, BoxedService};
 use actix_service::{NewService, Service};
 use actix_web::dev::*;
-use actix_web::error::Error;
-use actix_web::{FromRequest, HttpRequest, Responder};
+use actix_web::error::{BlockingError, Error};
+use actix_web::guard::Guard;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Form;
+use actix_web::{FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder};
 
 use file::ChunkedCryptFile;
+use meta::BlobMeta;
+use variants::Variant;
+use watermark::Watermark;
 use futures::future::{ok, Either, FutureResult};
 use futures::{Async, Future, Poll};
 
+use serde::Deserialize;
+
 use error::*;
 
 type HttpService = BoxedService<ServiceRequest, ServiceResponse, Error>;
@@ -28,19 +66,28 @@ type HttpNewService = BoxedNewService<(), ServiceRequest, ServiceResponse, Error
 
 pub struct CryptFiles {
     path: String,
-    directory: PathBuf,
+    store: SharedBlobStore,
+    config: SharedConfig,
+    tenant: String,
+    guards: Vec<Box<dyn Guard>>,
     index: Option<String>,
     default: Rc<RefCell<Option<Rc<HttpNewService>>>>,
 }
 
+/// Serve blobs of the store, `..` segments never leave the root and the local store
+/// keeps resolved paths inside the bucket as required by its symlink policy.
+#[derive(Clone)]
 pub struct CryptFilesService {
-    directory: PathBuf,
+    store: SharedBlobStore,
+    config: SharedConfig,
+    tenant: String,
     index: Option<String>,
-    default: Option<HttpService>,
+    /// Shared with the futures of the requests, they are handled on a single thread.
+    default: Option<Rc<RefCell<HttpService>>>,
 }
 
 impl HttpServiceFactory for CryptFiles {
-    fn register(self, config: &mut AppService) {
+    fn register(mut self, config: &mut AppService) {
         if self.default.borrow().is_none() {
             *self.default.borrow_mut() = Some(config.default_service());
         }
@@ -50,7 +97,13 @@ impl HttpServiceFactory for CryptFiles {
             ResourceDef::prefix(&self.path)
         };
 
-        config.register_service(rdef, None, self, None)
+        let guards = if self.guards.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.guards))
+        };
+
+        config.register_service(rdef, guards, self, None)
     }
 }
 
@@ -65,7 +118,9 @@ impl NewService for CryptFiles {
 
     fn new_service(&self, _: &()) -> Self::Future {
         let mut srv = CryptFilesService {
-            directory: self.directory.clone(),
+            store: self.store.clone(),
+            config: self.config.clone(),
+            tenant: self.tenant.clone(),
             index: self.index.clone(),
             default: None,
         };
@@ -75,7 +130,7 @@ impl NewService for CryptFiles {
                 default
                     .new_service(&())
                     .map(move |default| {
-                        srv.default = Some(default);
+                        srv.default = Some(Rc::new(RefCell::new(default)));
                         srv
                     })
                     .map_err(|_| ()),
@@ -87,19 +142,364 @@ impl NewService for CryptFiles {
 }
 
 impl CryptFiles {
-    pub fn new<T: Into<PathBuf>>(path: &str, dir: T) -> Self {
-        let dir = dir.into().canonicalize().unwrap_or_else(|_| PathBuf::new());
-        if !dir.is_dir() {
-            log::error!("Specified path is not a directory");
-        }
-
+    /// Serve the blobs of the tenant `tenant` under `path`.
+    pub fn new(path: &str, store: SharedBlobStore, config: SharedConfig, tenant: &str) -> Self {
         CryptFiles {
             path: path.to_string(),
-            directory: dir,
+            store,
+            config,
+            tenant: tenant.to_string(),
+            guards: Vec::new(),
             index: None,
             default: Rc::new(RefCell::new(None)),
         }
     }
+
+    /// Only serve requests matching `guard`.
+    pub fn guard<G: Guard + 'static>(mut self, guard: G) -> Self {
+        self.guards.push(Box::new(guard));
+        self
+    }
+}
+
+/// Largest blob whose content is verified before being served, larger ones are left to the scrubber.
+const VERIFIED_MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Return the key of `keys` the blob `storage_name` was encrypted with.
+///
+/// Blobs uploaded before a key rotation are encrypted with a previous key. The first key is
+/// returned if no magic matches, unencrypted blobs are served as is.
+fn blob_key(store: &dyn BlobStore, keys: &[BlobKey], storage_name: &str) -> io::Result<BlobKey> {
+    let mut matching = Vec::new();
+    for &key in keys {
+        let mut encrypted_blob = match EncryptedBlob::from(store.get(storage_name)?, key) {
+            Ok(encrypted_blob) => encrypted_blob,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        if encrypted_blob.is_header_magic_valid() {
+            matching.push((key, encrypted_blob));
+        }
+    }
+
+    // Keys can share a magic, only the content tells them apart
+    if matching.len() > 1 {
+        for (key, encrypted_blob) in &mut matching {
+            if encrypted_blob.is_content_valid() {
+                return Ok(*key);
+            }
+        }
+    }
+
+    Ok(matching.first().map(|(key, _)| *key).unwrap_or(keys[0]))
+}
+
+/// Open the blob stored as `storage_name` and served as `name`, with the key of `keys` it was
+/// encrypted with.
+///
+/// The blob is repaired from its parity first if its header is damaged, or if its content doesn't
+/// match the hash of its header.
+fn open_blob(
+    store: &dyn BlobStore,
+    keys: &[BlobKey],
+    storage_name: &str,
+    name: &str,
+) -> io::Result<ChunkedCryptFile> {
+    let has_parity = store.stat(&parity_name(storage_name)).is_ok();
+
+    // Only encrypted blobs get parity
+    if has_parity {
+        let verify_content = store.stat(storage_name)?.len <= VERIFIED_MAX_SIZE;
+        let key = blob_key(store, keys, storage_name)?;
+        let valid = EncryptedBlob::from(store.get(storage_name)?, key)
+            .map(|mut encrypted_blob| {
+                encrypted_blob.is_header_magic_valid()
+                    && (!verify_content || encrypted_blob.is_content_valid())
+            })
+            .unwrap_or(false);
+
+        if !valid {
+            // A damaged magic matches no key, the repaired blob is verified with each of them
+            let mut result = Err(io::Error::new(io::ErrorKind::InvalidInput, "No key"));
+            for &key in keys {
+                result = repair(store, storage_name, key);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            match result {
+                Ok(outcome) => log::warn!("Files: {} damaged, repair: {:?}", storage_name, outcome),
+                Err(e) => log::error!("Files: {} damaged and cannot be repaired: {}", storage_name, e),
+            }
+        }
+    }
+
+    let key = blob_key(store, keys, storage_name)?;
+    ChunkedCryptFile::open(store, key, storage_name, name)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Check that the request is allowed to read `name`, private blobs need a signed URL.
+fn authorize(tenant: &Tenant, meta: &BlobMeta, name: &str, query: &str) -> Result<(), CryptFilesError> {
+    if !meta.private {
+        return Ok(());
+    }
+
+    signed::verify(&tenant.url_signing_key, name, query, now())
+}
+
+/// Return true if the request has a cookie unlocking the password protected `name`.
+fn is_unlocked(req: &ServiceRequest, tenant: &Tenant, name: &str) -> bool {
+    let cookie_key = password::cookie_key(&tenant.key);
+    signed::has_cookie(req, password::UNLOCK_COOKIE, &cookie_key, name, now())
+}
+
+/// A blob found by `resolve`.
+struct Resolved {
+    /// Name the blob is served as.
+    name: String,
+    /// Name the blob is stored as, a deduplicated blob is stored under its hash.
+    storage_name: String,
+    /// The request is for the description of the blob.
+    is_info: bool,
+    /// The request is for the preview page of the blob.
+    is_preview: bool,
+    meta: BlobMeta,
+    /// Drawn on the served image, `None` if the original is served.
+    watermark: Option<Arc<Watermark>>,
+}
+
+/// Why a request isn't served.
+#[derive(Debug)]
+enum Refusal {
+    /// Nothing is stored under the name, the default service handles the request.
+    NotFound(io::Error),
+    /// The request is answered with this error.
+    Denied(CryptFilesError),
+    /// The store failed.
+    Failed(io::Error),
+}
+
+impl Refusal {
+    /// Return the error answering the request, without falling back to the default service.
+    fn into_error(self) -> Error {
+        match self {
+            Refusal::NotFound(e) | Refusal::Failed(e) => e.into(),
+            Refusal::Denied(e) => e.into(),
+        }
+    }
+}
+
+/// Find the blob of `tenant` requested as `name` with the API `token`, a directory is served as
+/// its `index`.
+///
+/// Stores can be remote, this runs outside of the event loop.
+fn resolve(
+    store: &dyn BlobStore,
+    tenant: &Tenant,
+    mut name: String,
+    index: Option<&str>,
+    token: Option<String>,
+) -> Result<Resolved, Refusal> {
+    // `<name>/info` describes the blob `name`, unless a blob has this name
+    let is_info = match name.strip_suffix("/info") {
+        Some(target) if store.stat(&name).is_err() => {
+            name = target.to_string();
+            true
+        }
+        _ => false,
+    };
+
+    // `v/<name>` is the preview page of the blob `name`, unless a blob has this name
+    let is_preview = match name.strip_prefix("v/") {
+        Some(target) if !is_info && store.stat(&name).is_err() => {
+            name = target.to_string();
+            true
+        }
+        _ => false,
+    };
+
+    let mut storage_name = name.clone();
+
+    // The bucket root is a directory too
+    let is_dir = if name.is_empty() {
+        true
+    } else {
+        match store.stat(&name) {
+            Ok(stat) => stat.is_dir,
+            Err(e) => match dedup::resolve_alias(store, &name) {
+                Ok(Some(target)) => {
+                    storage_name = target;
+                    false
+                }
+                _ if downloads::is_burned(store, &name) => {
+                    return Err(Refusal::Denied(CryptFilesError::Gone))
+                }
+                _ => return Err(Refusal::NotFound(e)),
+            },
+        }
+    };
+
+    // A directory is served as its index
+    let (storage_name, name) = if is_dir {
+        let index = index.ok_or(Refusal::Denied(CryptFilesError::IsDirectory))?;
+        let name = if name.is_empty() {
+            index.to_string()
+        } else {
+            format!("{}/{}", name, index)
+        };
+        (name.clone(), name)
+    } else {
+        (storage_name, name)
+    };
+
+    let meta = match meta::read(store, &name) {
+        Ok(meta) => meta.unwrap_or_default(),
+        Err(e) => {
+            // Don't serve a blob that might be private
+            log::error!("Files: cannot read the metadata of {}: {}", name, e);
+            return Err(Refusal::Denied(CryptFilesError::InvalidSignature));
+        }
+    };
+
+    // Descriptions and preview pages aren't images
+    let watermark = if is_info || is_preview {
+        None
+    } else {
+        watermark_for(store, tenant, &name, token.as_deref())
+    };
+
+    Ok(Resolved {
+        name,
+        storage_name,
+        is_info,
+        is_preview,
+        meta,
+        watermark,
+    })
+}
+
+/// A request of the blob `name`, limited to `max_downloads`.
+struct Download {
+    name: String,
+    /// Path of the session cookie.
+    path: String,
+    max_downloads: u64,
+    /// HEAD requests don't serve content.
+    is_download: bool,
+    /// Nonce of the download session of the request.
+    session: Option<String>,
+}
+
+impl Download {
+    /// Describe the request `req` of `name`, made with `method`.
+    fn new<R: HttpMessage>(
+        req: &R,
+        method: &Method,
+        path: &str,
+        tenant: &Tenant,
+        name: &str,
+        max_downloads: u64,
+    ) -> Self {
+        Download {
+            name: name.to_string(),
+            path: path.to_string(),
+            max_downloads,
+            is_download: *method != Method::HEAD,
+            session: downloads::session(req, &tenant.key, name, now()),
+        }
+    }
+
+    /// Account for the download, return the `Set-Cookie` value of the session it starts.
+    ///
+    /// Stores can be remote, this runs outside of the event loop.
+    fn count(&self, store: &SharedBlobStore, tenant: &Tenant) -> Result<Option<String>, Refusal> {
+        let now = now();
+        let session = self.session.as_deref();
+        match downloads::record(&**store, &self.name, self.max_downloads, self.is_download, session, now) {
+            Ok(downloads::Access::Counted(nonce)) => Ok(Some(downloads::session_cookie(
+                &tenant.key,
+                &self.name,
+                &nonce,
+                &self.path,
+                now,
+                tenant.base_url.starts_with("https://"),
+            ))),
+            Ok(downloads::Access::Uncounted) => Ok(None),
+            Ok(downloads::Access::Gone) => Err(Refusal::Denied(CryptFilesError::Gone)),
+            Err(e) => {
+                log::error!("Files: cannot count the downloads of {}: {}", self.name, e);
+                Err(Refusal::Failed(e))
+            }
+        }
+    }
+}
+
+/// Tell caches what the response to `name` depends on: the formats accepted by the client, and
+/// its API token if images are watermarked.
+fn set_cache_headers(response: &mut HttpResponse, tenant: &Tenant, name: &str) {
+    if !variants::is_image(name) {
+        return;
+    }
+
+    let headers = response.headers_mut();
+    if tenant.variants.negotiate_format {
+        headers.append(header::VARY, HeaderValue::from_static("Accept"));
+    }
+    if tenant.watermark.is_some() {
+        headers.append(header::VARY, HeaderValue::from_static("Authorization"));
+        // A shared cache would serve the original to clients without a bypassing token
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("private"));
+    }
+}
+
+/// Return the watermark to draw on `name` for a request with the API `token`, `None` if the
+/// original is served.
+///
+/// Users and tokens bypassing the watermark authenticate with their API token, stores can be
+/// remote, this runs outside of the event loop.
+fn watermark_for(
+    store: &dyn BlobStore,
+    tenant: &Tenant,
+    name: &str,
+    token: Option<&str>,
+) -> Option<Arc<Watermark>> {
+    let watermark = tenant.watermark.as_ref()?;
+    if !variants::is_image(name) {
+        return None;
+    }
+
+    let token = match token {
+        Some(token) => token,
+        None => return Some(watermark.clone()),
+    };
+    match tokens::authenticate(store, token) {
+        Ok(Some(user)) if watermark.is_bypassed(&user, &tokens::hash_token(token)) => None,
+        Ok(_) => Some(watermark.clone()),
+        Err(e) => {
+            log::error!("Files: cannot authenticate a token: {}", e);
+            Some(watermark.clone())
+        }
+    }
+}
+
+/// Add `cookie` to `response` as a `Set-Cookie` header.
+fn set_cookie(response: &mut HttpResponse, cookie: &str) {
+    if let Ok(cookie) = HeaderValue::from_str(cookie) {
+        response.headers_mut().append(header::SET_COOKIE, cookie);
+    }
+}
+
+/// The form of the password prompt.
+#[derive(Deserialize)]
+struct PasswordForm {
+    password: String,
 }
 
 impl CryptFilesService {
@@ -112,12 +512,470 @@ impl CryptFilesService {
         Box<Future<Item = ServiceResponse, Error = Error>>,
     > {
         log::debug!("Files: Failed to handle {}: {}", req.path(), e);
-        if let Some(ref mut default) = self.default {
-            default.call(req)
+        if let Some(ref default) = self.default {
+            default.borrow_mut().call(req)
         } else {
             Either::A(ok(req.error_response(e)))
         }
     }
+
+    /// Answer the request refused for `e` by a blocking task.
+    fn refuse(
+        &mut self,
+        e: BlockingError<Refusal>,
+        req: ServiceRequest,
+    ) -> Either<
+        FutureResult<ServiceResponse, Error>,
+        Box<dyn Future<Item = ServiceResponse, Error = Error>>,
+    > {
+        match e {
+            BlockingError::Error(Refusal::NotFound(e)) => self.handle_err(e, req),
+            BlockingError::Error(refusal) => Either::A(ok(req.error_response(refusal.into_error()))),
+            e @ BlockingError::Canceled => Either::A(ok(req.error_response(e))),
+        }
+    }
+
+    fn serve(
+        &mut self,
+        req: ServiceRequest,
+        tenant: Arc<Tenant>,
+        storage_name: String,
+        name: String,
+        cookie: Option<String>,
+    ) -> Either<
+        FutureResult<ServiceResponse, Error>,
+        Box<dyn Future<Item = ServiceResponse, Error = Error>>,
+    > {
+        let store = self.store.clone();
+        let keys = tenant.keys();
+        let open_name = name.clone();
+        let mut service = self.clone();
+
+        // The blob can be verified before being served
+        Either::B(Box::new(
+            actix_web::web::block(move || open_blob(&*store, &keys, &storage_name, &open_name))
+                .then(move |crypt_file| match crypt_file {
+                    Ok(crypt_file) => {
+                        let (req, _) = req.into_parts();
+                        Either::A(ok(match crypt_file.respond_to(&req) {
+                            Ok(mut item) => {
+                                set_cache_headers(&mut item, &tenant, &name);
+                                if let Some(ref cookie) = cookie {
+                                    set_cookie(&mut item, cookie);
+                                }
+                                ServiceResponse::new(req, item)
+                            }
+                            Err(e) => ServiceResponse::from_err(e, req),
+                        }))
+                    }
+                    Err(BlockingError::Error(e)) => service.handle_err(e, req),
+                    Err(e) => Either::A(ok(req.error_response(e))),
+                }),
+        ))
+    }
+
+    /// Serve the `variant` of `name`, it is rendered first if it isn't cached.
+    fn serve_variant(
+        &mut self,
+        req: ServiceRequest,
+        tenant: Arc<Tenant>,
+        storage_name: String,
+        name: String,
+        variant: Variant,
+        cookie: Option<String>,
+    ) -> Either<
+        FutureResult<ServiceResponse, Error>,
+        Box<dyn Future<Item = ServiceResponse, Error = Error>>,
+    > {
+        let store = self.store.clone();
+        let keys = tenant.keys();
+        let limits = tenant.decode_limits;
+        let (req, _) = req.into_parts();
+        let served_name = variant.served_name(&name);
+
+        // Decoding and encoding a large photo takes a while
+        Either::B(Box::new(
+            actix_web::web::block(move || {
+                let variant_name =
+                    variants::render(&*store, &keys, &storage_name, &variant, &limits)?;
+                open_blob(&*store, &keys, &variant_name, &served_name)
+            })
+            .then(move |crypt_file| {
+                let crypt_file = match crypt_file {
+                    Ok(crypt_file) => crypt_file,
+                    Err(BlockingError::Error(ref e)) if decode::is_too_large(e) => {
+                        log::warn!("Files: not rendering {}: {}", name, e);
+                        return Ok(ServiceResponse::from_err(CryptFilesError::ImageTooLarge, req));
+                    }
+                    Err(BlockingError::Error(ref e)) if e.kind() == io::ErrorKind::InvalidData => {
+                        return Ok(ServiceResponse::from_err(CryptFilesError::NotAnImage, req));
+                    }
+                    Err(e) => {
+                        log::error!("Files: cannot render a variant of {}: {}", name, e);
+                        return Ok(ServiceResponse::from_err(e, req));
+                    }
+                };
+
+                let mut response = match crypt_file.respond_to(&req) {
+                    Ok(response) => response,
+                    Err(e) => return Ok(ServiceResponse::from_err(e, req)),
+                };
+                set_cache_headers(&mut response, &tenant, &name);
+                if let Some(ref cookie) = cookie {
+                    set_cookie(&mut response, cookie);
+                }
+
+                Ok(ServiceResponse::new(req, response))
+            }),
+        ))
+    }
+
+    /// Serve the description of `name` as JSON.
+    fn serve_info(
+        &mut self,
+        req: ServiceRequest,
+        keys: Vec<BlobKey>,
+        storage_name: String,
+        name: String,
+        meta: BlobMeta,
+    ) -> Either<
+        FutureResult<ServiceResponse, Error>,
+        Box<dyn Future<Item = ServiceResponse, Error = Error>>,
+    > {
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            let response = HttpResponse::MethodNotAllowed()
+                .header(header::ALLOW, "GET, HEAD")
+                .finish();
+            return Either::A(ok(req.into_response(response)));
+        }
+
+        let store = self.store.clone();
+        let (req, _) = req.into_parts();
+        let info_name = name.clone();
+
+        Either::B(Box::new(
+            actix_web::web::block(move || info::read(&*store, &keys, &storage_name, &info_name))
+                .then(move |info| match info {
+                    Ok(mut info) => {
+                        info.uploaded = Some(meta.uploaded).filter(|uploaded| *uploaded != 0);
+                        info.blurhash = meta.blurhash;
+                        info.dominant_color = meta.dominant_color;
+                        Ok(ServiceResponse::new(req, HttpResponse::Ok().json(info)))
+                    }
+                    Err(e) => {
+                        log::error!("Files: cannot describe {}: {}", name, e);
+                        Ok(ServiceResponse::from_err(e, req))
+                    }
+                }),
+        ))
+    }
+
+    /// Serve the preview page of `name`, embedding it if `is_embedded`.
+    fn serve_preview(
+        &mut self,
+        req: ServiceRequest,
+        tenant: Arc<Tenant>,
+        storage_name: String,
+        name: String,
+        is_embedded: bool,
+        has_variant: bool,
+    ) -> Either<
+        FutureResult<ServiceResponse, Error>,
+        Box<dyn Future<Item = ServiceResponse, Error = Error>>,
+    > {
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            let response = HttpResponse::MethodNotAllowed()
+                .header(header::ALLOW, "GET, HEAD")
+                .finish();
+            return Either::A(ok(req.into_response(response)));
+        }
+
+        // Signed URLs stay signed, variants stay variants
+        let query = match req.query_string() {
+            "" => String::new(),
+            query => format!("?{}", query),
+        };
+        let url = format!("{}/{}{}", tenant.base_url, name, query);
+        let page_url = format!("{}/v/{}{}", tenant.base_url, name, query);
+        let oembed_url = format!("{}/oembed", tenant.base_url);
+        let content_type = file::content_type(Path::new(&name));
+
+        let (req, _) = req.into_parts();
+        if !is_embedded {
+            let response = preview::page(&name, &url, &page_url, &oembed_url, &content_type, None, false);
+            return Either::A(ok(ServiceResponse::new(req, response)));
+        }
+
+        let store = self.store.clone();
+        let keys = tenant.keys();
+        let info_name = name.clone();
+
+        Either::B(Box::new(
+            actix_web::web::block(move || info::read(&*store, &keys, &storage_name, &info_name))
+                .then(move |info| match info {
+                    Ok(info) => {
+                        let response = preview::page(
+                            &name,
+                            &url,
+                            &page_url,
+                            &oembed_url,
+                            &content_type,
+                            Some(&info),
+                            !has_variant,
+                        );
+                        Ok(ServiceResponse::new(req, response))
+                    }
+                    Err(e) => {
+                        log::error!("Files: cannot describe {}: {}", name, e);
+                        Ok(ServiceResponse::from_err(e, req))
+                    }
+                }),
+        ))
+    }
+
+    /// Show the password prompt of the blob, or serve it if the posted password is its password.
+    fn unlock(
+        &mut self,
+        req: ServiceRequest,
+        tenant: Arc<Tenant>,
+        resolved: Resolved,
+    ) -> Either<
+        FutureResult<ServiceResponse, Error>,
+        Box<dyn Future<Item = ServiceResponse, Error = Error>>,
+    > {
+        let Resolved {
+            name,
+            storage_name,
+            meta,
+            watermark,
+            ..
+        } = resolved;
+
+        if *req.method() != Method::POST {
+            let prompt = password::prompt(&name, StatusCode::UNAUTHORIZED);
+            return Either::A(ok(req.into_response(prompt)));
+        }
+
+        // Behind a proxy every client shares its address, the limit is then per blob
+        let client = req
+            .peer_addr()
+            .map(|address| address.ip().to_string())
+            .unwrap_or_default();
+
+        // Counted before verifying, concurrent attempts are limited too
+        let attempt = match password::start_attempt(&tenant.name, &name, &client) {
+            Some(attempt) => attempt,
+            None => {
+                let prompt = password::prompt(&name, StatusCode::TOO_MANY_REQUESTS);
+                return Either::A(ok(req.into_response(prompt)));
+            }
+        };
+
+        let is_watermarked = watermark.is_some();
+        let download = meta
+            .max_downloads
+            .map(|max_downloads| Download::new(&req, req.method(), req.path(), &tenant, &name, max_downloads));
+        let password_hash = meta.password.unwrap_or_default();
+        let store = self.store.clone();
+        let (req, mut payload) = req.into_parts();
+        let form = Form::<PasswordForm>::from_request(&req, &mut payload);
+
+        Either::B(Box::new(form.then(move |form| {
+            let form = match form {
+                Ok(form) => form.into_inner(),
+                Err(_) => {
+                    attempt.forget();
+                    let prompt = password::prompt(&name, StatusCode::BAD_REQUEST);
+                    return Either::A(ok(ServiceResponse::new(req, prompt)));
+                }
+            };
+
+            let unlock_tenant = tenant.clone();
+            let unlock_name = name.clone();
+
+            // Argon2 is slow on purpose
+            Either::B(
+                actix_web::web::block(move || -> Result<_, Refusal> {
+                    if !password::verify(&password_hash, &form.password) {
+                        return Ok(None);
+                    }
+                    attempt.forget();
+
+                    let download_cookie = match download {
+                        Some(ref download) => download.count(&store, &unlock_tenant)?,
+                        None => None,
+                    };
+
+                    // The original isn't served, the unlocked request renders the watermarked image
+                    let crypt_file = if is_watermarked {
+                        None
+                    } else {
+                        let keys = unlock_tenant.keys();
+                        let crypt_file = open_blob(&*store, &keys, &storage_name, &unlock_name)
+                            .map_err(Refusal::Failed)?;
+                        Some(crypt_file.accept_post())
+                    };
+
+                    Ok(Some((download_cookie, crypt_file)))
+                })
+                .then(move |unlocked| {
+                    let (download_cookie, crypt_file) = match unlocked {
+                        Ok(Some(unlocked)) => unlocked,
+                        Ok(None) => {
+                            let prompt = password::prompt(&name, StatusCode::FORBIDDEN);
+                            return Ok(ServiceResponse::new(req, prompt));
+                        }
+                        Err(BlockingError::Error(refusal)) => {
+                            return Ok(ServiceResponse::from_err(refusal.into_error(), req))
+                        }
+                        Err(e) => return Ok(ServiceResponse::from_err(e, req)),
+                    };
+
+                    let mut response = match crypt_file {
+                        Some(crypt_file) => match crypt_file.respond_to(&req) {
+                            Ok(mut response) => {
+                                set_cache_headers(&mut response, &tenant, &name);
+                                response
+                            }
+                            Err(e) => return Ok(ServiceResponse::from_err(e, req)),
+                        },
+                        None => HttpResponse::SeeOther()
+                            .header(header::LOCATION, req.uri().to_string())
+                            .finish(),
+                    };
+
+                    let unlock = signed::signed_query(
+                        &password::cookie_key(&tenant.key),
+                        &name,
+                        now() + password::UNLOCK_LIFETIME,
+                    );
+                    let cookie = signed::cookie_header(
+                        password::UNLOCK_COOKIE,
+                        &unlock,
+                        req.path(),
+                        password::UNLOCK_LIFETIME,
+                        tenant.base_url.starts_with("https://"),
+                    );
+                    set_cookie(&mut response, &cookie);
+                    if let Some(ref download_cookie) = download_cookie {
+                        set_cookie(&mut response, download_cookie);
+                    }
+
+                    Ok(ServiceResponse::new(req, response))
+                }),
+            )
+        })))
+    }
+
+    /// Answer the request of the blob found by `resolve`.
+    fn respond(
+        &mut self,
+        req: ServiceRequest,
+        tenant: Arc<Tenant>,
+        resolved: Resolved,
+    ) -> Either<
+        FutureResult<ServiceResponse, Error>,
+        Box<dyn Future<Item = ServiceResponse, Error = Error>>,
+    > {
+        if let Err(e) = authorize(&tenant, &resolved.meta, &resolved.name, req.query_string()) {
+            return Either::A(ok(req.error_response(e)));
+        }
+
+        if resolved.meta.password.is_some() && !is_unlocked(&req, &tenant, &resolved.name) {
+            if resolved.is_info {
+                return Either::A(ok(req.into_response(HttpResponse::Unauthorized().finish())));
+            }
+            if resolved.is_preview {
+                return self.serve_preview(req, tenant, resolved.storage_name, resolved.name, false, false);
+            }
+            return self.unlock(req, tenant, resolved);
+        }
+
+        // Invalid parameters don't count as a download
+        let accept = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .filter(|_| tenant.variants.negotiate_format);
+        let sizes = &tenant.variants.sizes;
+        let variant = match Variant::from_request(&resolved.name, req.query_string(), accept, sizes) {
+            Ok(variant) => variant,
+            Err(_) if resolved.is_info => None,
+            Err(e) => return Either::A(ok(req.error_response(e))),
+        };
+
+        // Downloads are only counted once the blob is about to be served
+        let max_downloads = match resolved.meta.max_downloads {
+            Some(max_downloads) => max_downloads,
+            None => return self.dispatch(req, tenant, resolved, variant, None),
+        };
+
+        // Describing a blob isn't a download, like HEAD
+        let method = if resolved.is_info || resolved.is_preview {
+            Method::HEAD
+        } else {
+            req.method().clone()
+        };
+        let download = Download::new(&req, &method, req.path(), &tenant, &resolved.name, max_downloads);
+        let store = self.store.clone();
+        let count_tenant = tenant.clone();
+        let mut service = self.clone();
+
+        Either::B(Box::new(
+            actix_web::web::block(move || download.count(&store, &count_tenant)).then(
+                move |cookie| match cookie {
+                    Ok(cookie) => service.dispatch(req, tenant, resolved, variant, cookie),
+                    Err(e) => service.refuse(e, req),
+                },
+            ),
+        ))
+    }
+
+    /// Serve the blob found by `resolve` as requested: its description, preview page, `variant` or
+    /// itself.
+    fn dispatch(
+        &mut self,
+        req: ServiceRequest,
+        tenant: Arc<Tenant>,
+        resolved: Resolved,
+        variant: Option<Variant>,
+        cookie: Option<String>,
+    ) -> Either<
+        FutureResult<ServiceResponse, Error>,
+        Box<dyn Future<Item = ServiceResponse, Error = Error>>,
+    > {
+        let Resolved {
+            name,
+            storage_name,
+            is_info,
+            is_preview,
+            meta,
+            watermark,
+        } = resolved;
+
+        if is_info {
+            return self.serve_info(req, tenant.keys(), storage_name, name, meta);
+        }
+        if is_preview {
+            // Crawlers fetching the blob would use up its downloads
+            let is_embedded = meta.max_downloads.is_none();
+            return self.serve_preview(req, tenant, storage_name, name, is_embedded, variant.is_some());
+        }
+
+        let variant = match watermark {
+            Some(watermark) => variant
+                .or_else(|| Variant::original(&name))
+                .map(|variant| variant.watermarked(watermark)),
+            None => variant,
+        };
+
+        match variant {
+            Some(variant) if *req.method() == Method::GET || *req.method() == Method::HEAD => {
+                self.serve_variant(req, tenant, storage_name, name, variant, cookie)
+            }
+            _ => self.serve(req, tenant, storage_name, name, cookie),
+        }
+    }
 }
 
 impl Service for CryptFilesService {
@@ -141,44 +999,27 @@ impl Service for CryptFilesService {
             Err(e) => return Either::A(ok(req.error_response(e))),
         };
 
-        // full filepath
-        let path = match self.directory.join(&real_path.0).canonicalize() {
-            Ok(path) => path,
-            Err(e) => return self.handle_err(e, req),
-        };
+        let name = real_path.to_blob_name();
 
-        if path.is_dir() {
-            if let Some(ref redir_index) = self.index {
-                let path = path.join(redir_index);
+        // Requests in flight keep the key they started with
+        let tenant = self.config.tenant(&self.tenant);
 
-                match ChunkedCryptFile::open(path) {
-                    Ok(crypt_file) => {
-                        let (req, _) = req.into_parts();
-                        Either::A(ok(match crypt_file.respond_to(&req) {
-                            Ok(item) => ServiceResponse::new(req, item),
-                            Err(e) => ServiceResponse::from_err(e, req),
-                        }))
-                    }
-                    Err(e) => return self.handle_err(e, req),
-                }
-            } else {
-                Either::A(ok(ServiceResponse::from_err(
-                    CryptFilesError::IsDirectory,
-                    req.into_parts().0,
-                )))
-            }
-        } else {
-            match ChunkedCryptFile::open(path) {
-                Ok(crypt_file) => {
-                    let (req, _) = req.into_parts();
-                    match crypt_file.respond_to(&req) {
-                        Ok(item) => Either::A(ok(ServiceResponse::new(req.clone(), item))),
-                        Err(e) => Either::A(ok(ServiceResponse::from_err(e, req))),
-                    }
-                }
-                Err(e) => self.handle_err(e, req),
-            }
-        }
+        let store = self.store.clone();
+        let index = self.index.clone();
+        let token = tokens::bearer_token(&req);
+        let resolve_tenant = tenant.clone();
+        let mut service = self.clone();
+
+        // Stores can be remote, the blob is looked up outside of the event loop
+        Either::B(Box::new(
+            actix_web::web::block(move || {
+                resolve(&*store, &resolve_tenant, name, index.as_deref(), token)
+            })
+            .then(move |resolved| match resolved {
+                Ok(resolved) => service.respond(req, tenant, resolved),
+                Err(e) => service.refuse(e, req),
+            }),
+        ))
     }
 }
 
@@ -212,6 +1053,18 @@ impl PathBufWrp {
 
         Ok(PathBufWrp(buf))
     }
+
+    /// Return the blob name of this path, segments are always separated by `/`.
+    fn to_blob_name(&self) -> String {
+        self.0
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(segment) => Some(segment.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
 }
 
 impl FromRequest for PathBufWrp {
@@ -223,3 +1076,293 @@ impl FromRequest for PathBufWrp {
         PathBufWrp::get_pathbuf(req.match_info().path())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    use image::{DynamicImage, GenericImageView, ImageFormat, Rgb, RgbImage};
+
+    use super::decode::DecodeLimits;
+    use super::store::{open_local, BucketLayout, StorageConfig, SymlinkPolicy};
+    use super::tokens;
+    use super::watermark::{Mark, Position, Watermark};
+    use super::{BlobKey, CryptFiles};
+    use crate::config::{
+        BlocklistAction, Config, Moderation, SharedConfig, Tenant, TenantRoute, VariantSettings, DEFAULT_TENANT,
+    };
+    use crate::limits::UploadLimits;
+    use crate::scrubber::ScrubSettings;
+
+    /// Create a bucket with an `inside.txt` blob, next to an `outside` directory holding `secret.txt`.
+    fn setup(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("imagers-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&directory).ok();
+        fs::create_dir_all(directory.join("bucket")).unwrap();
+        fs::create_dir_all(directory.join("outside")).unwrap();
+        // Raw blobs are served as is, they only need to be large enough to hold a header
+        fs::write(directory.join("bucket/inside.txt"), format!("{:64}", "inside")).unwrap();
+        fs::write(directory.join("outside/secret.txt"), format!("{:64}", "secret")).unwrap();
+        directory
+    }
+
+    /// Return the default tenant serving the bucket of `directory`.
+    fn tenant(directory: &Path, symlinks: SymlinkPolicy) -> Tenant {
+        let storage = StorageConfig::Local {
+            path: directory.join("bucket"),
+            layout: BucketLayout::Flat,
+            symlinks,
+        };
+        Tenant {
+            name: DEFAULT_TENANT.to_string(),
+            route: TenantRoute::Default,
+            base_url: "http://localhost".to_string(),
+            key: BlobKey::new(&"00".repeat(32), "IMAGERS0").unwrap(),
+            previous_keys: Vec::new(),
+            require_upload_token: true,
+            limits: UploadLimits {
+                quota_bytes: 0,
+                quota_files: 0,
+                rate_uploads: 0,
+                rate_window: std::time::Duration::from_secs(3600),
+            },
+            decode_limits: DecodeLimits::default(),
+            replay_protection: None,
+            url_signing_key: b"key".to_vec(),
+            strip_metadata: false,
+            dedup: false,
+            parity_shards: 8,
+            moderation: Moderation {
+                moderators: Vec::new(),
+                blocklist: BlocklistAction::Off,
+                blocklist_distance: 8,
+            },
+            variants: VariantSettings {
+                sizes: vec![64],
+                negotiate_format: false,
+            },
+            watermark: None,
+            storage,
+        }
+    }
+
+    /// Send `request` to the files of `tenant`.
+    fn call(
+        tenant: Tenant,
+        request: test::TestRequest,
+    ) -> (StatusCode, Vec<(String, String)>, Vec<u8>) {
+        let store = open_local(&tenant.storage).unwrap();
+        let config = Config {
+            ip: "127.0.0.1".to_string(),
+            port: 8080,
+            temp_dir: std::env::temp_dir(),
+            scrubber: ScrubSettings {
+                quarantine: PathBuf::from("./quarantine"),
+                report: PathBuf::from("./scrub_report.txt"),
+                state: PathBuf::from("./scrub.state"),
+                rate: 0,
+                interval: std::time::Duration::from_secs(0),
+            },
+            tenants: vec![Arc::new(tenant)],
+        };
+
+        let files = CryptFiles::new("/", Arc::new(store), SharedConfig::new(config), DEFAULT_TENANT);
+        let mut app = test::init_service(App::new().service(files));
+
+        let response = test::call_service(&mut app, request.to_request());
+        let status = response.status();
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or_default().to_string()))
+            .collect();
+        (status, headers, test::read_body(response).to_vec())
+    }
+
+    fn get(directory: &Path, symlinks: SymlinkPolicy, uri: &str) -> (StatusCode, String) {
+        let request = test::TestRequest::with_uri(uri);
+        let (status, _, body) = call(tenant(directory, symlinks), request);
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[test]
+    fn traversal_stays_in_root() {
+        let directory = setup("traversal");
+
+        for uri in &[
+            "/../outside/secret.txt",
+            "/../../outside/secret.txt",
+            "/%2E%2E/outside/secret.txt",
+            "/inside.txt/../../outside/secret.txt",
+        ] {
+            let (status, body) = get(&directory, SymlinkPolicy::Follow, uri);
+            assert_ne!(status, StatusCode::OK, "{} was served", uri);
+            assert!(!body.contains("secret"), "{} was served", uri);
+        }
+
+        let (status, body) = get(&directory, SymlinkPolicy::Deny, "/../inside.txt");
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with("inside"));
+
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn encoded_separators_are_not_split() {
+        let directory = setup("separators");
+
+        for uri in &[
+            "/..%2Foutside%2Fsecret.txt",
+            "/..%2foutside%2fsecret.txt",
+            "/%2E%2E%2Foutside%2Fsecret.txt",
+            "/..%5Coutside%5Csecret.txt",
+        ] {
+            let (status, body) = get(&directory, SymlinkPolicy::Follow, uri);
+            assert_ne!(status, StatusCode::OK, "{} was served", uri);
+            assert!(!body.contains("secret"), "{} was served", uri);
+        }
+
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn symlink_policy() {
+        let directory = setup("symlinks");
+        let bucket = directory.join("bucket");
+        std::os::unix::fs::symlink(directory.join("outside"), bucket.join("escape")).unwrap();
+        std::os::unix::fs::symlink(directory.join("outside/secret.txt"), bucket.join("secret.txt"))
+            .unwrap();
+        std::os::unix::fs::symlink(bucket.join("inside.txt"), bucket.join("alias.txt")).unwrap();
+
+        for &(symlinks, uri, expected) in &[
+            (SymlinkPolicy::Deny, "/escape/secret.txt", false),
+            (SymlinkPolicy::Deny, "/secret.txt", false),
+            (SymlinkPolicy::Deny, "/alias.txt", false),
+            (SymlinkPolicy::WithinRoot, "/escape/secret.txt", false),
+            (SymlinkPolicy::WithinRoot, "/secret.txt", false),
+            (SymlinkPolicy::WithinRoot, "/alias.txt", true),
+            (SymlinkPolicy::Follow, "/escape/secret.txt", true),
+            (SymlinkPolicy::Follow, "/alias.txt", true),
+        ] {
+            let (status, _) = get(&directory, symlinks, uri);
+            assert_eq!(status == StatusCode::OK, expected, "{:?} {}", symlinks, uri);
+        }
+
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn previous_keys() {
+        let directory = setup("rotation");
+        let bucket = directory.join("bucket");
+        let mut tenant = tenant(&directory, SymlinkPolicy::Deny);
+        // The first previous key shares the magic of the current key
+        tenant.previous_keys = vec![
+            BlobKey::new(&"01".repeat(32), "IMAGERS0").unwrap(),
+            BlobKey::new(&"02".repeat(32), "IMAGERS2").unwrap(),
+        ];
+
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(128, 96))
+            .write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        for (index, key) in tenant.keys().iter().enumerate() {
+            fs::write(bucket.join(format!("{}.txt", index)), key.encrypt(b"rotated")).unwrap();
+            fs::write(bucket.join(format!("{}.png", index)), key.encrypt(&png)).unwrap();
+        }
+
+        for index in 0..tenant.keys().len() {
+            let uri = format!("/{}.txt", index);
+            let request = test::TestRequest::with_uri(&uri);
+            let (status, _, body) = call(tenant.clone(), request);
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+            assert_eq!(body, b"rotated", "{}", uri);
+
+            let uri = format!("/{}.png?w=64", index);
+            let (status, _, body) = call(tenant.clone(), test::TestRequest::with_uri(&uri));
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+            assert_eq!(image::load_from_memory(&body).unwrap().dimensions(), (64, 48), "{}", uri);
+
+            let uri = format!("/{}.png/info", index);
+            let (status, _, body) = call(tenant.clone(), test::TestRequest::with_uri(&uri));
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+            assert!(String::from_utf8_lossy(&body).contains("\"width\":128"), "{}", uri);
+        }
+
+        // Variants are cached for the key of their blob
+        let variant_names: Vec<String> = fs::read_dir(bucket.join(".variants/1.png"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(variant_names.len(), 1);
+        assert!(variant_names[0].starts_with(&format!("{}-", tenant.previous_keys[0].id())));
+
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn watermarked() {
+        let directory = setup("watermark");
+        let bucket = directory.join("bucket");
+        let mut tenant = tenant(&directory, SymlinkPolicy::Deny);
+
+        let encode = |image: DynamicImage| {
+            let mut data = Vec::new();
+            image.write_to(&mut std::io::Cursor::new(&mut data), ImageFormat::Png).unwrap();
+            data
+        };
+        let white = DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([255; 3])));
+        let mark = Mark::image(&encode(white)).unwrap();
+        let bypass_users = vec!["alice".to_string()];
+        let watermark = Watermark::new(mark, Position::Center, 1.0, 0.5, bypass_users, Vec::new());
+        tenant.watermark = Some(Arc::new(watermark));
+
+        let original = encode(DynamicImage::ImageRgb8(RgbImage::new(64, 64)));
+        fs::write(bucket.join("photo.png"), tenant.key.encrypt(&original)).unwrap();
+        let store = open_local(&tenant.storage).unwrap();
+        let alice = tokens::create(&store, "alice").unwrap();
+        let bob = tokens::create(&store, "bob").unwrap();
+
+        let is_watermarked = |body: &[u8]| {
+            let image = image::load_from_memory(body).unwrap().to_rgb8();
+            image.pixels().any(|pixel| *pixel == Rgb([255; 3]))
+        };
+        let header = |headers: &[(String, String)], name: &str| -> Vec<String> {
+            let values = headers.iter().filter(|(header, _)| header == name);
+            values.map(|(_, value)| value.clone()).collect()
+        };
+
+        for &(uri, token) in &[
+            ("/photo.png", None),
+            ("/photo.png", Some(bob.as_str())),
+            ("/photo.png", Some("unknown")),
+            ("/photo.png?w=64", None),
+            ("/photo.png", Some(alice.as_str())),
+        ] {
+            let mut request = test::TestRequest::with_uri(uri);
+            if let Some(token) = token {
+                request = request.header("Authorization", format!("Bearer {}", token));
+            }
+            let (status, headers, body) = call(tenant.clone(), request);
+            assert_eq!(status, StatusCode::OK, "{} {:?}", uri, token);
+
+            // Shared caches never serve the original to others
+            assert_eq!(header(&headers, "vary"), ["Authorization"], "{} {:?}", uri, token);
+            assert_eq!(header(&headers, "cache-control"), ["private"], "{} {:?}", uri, token);
+
+            if token == Some(alice.as_str()) {
+                assert_eq!(body, original);
+            } else {
+                assert_ne!(body, original, "{} {:?}", uri, token);
+                assert!(is_watermarked(&body), "{} {:?}", uri, token);
+            }
+        }
+
+        fs::remove_dir_all(&directory).ok();
+    }
+}
@@ -0,0 +1,403 @@
+//! oEmbed descriptions of the blobs served by `CryptFiles`, for the `oembed` route.
+//!
+//! Images are described as photos, resized with the variant parameters to the largest configured
+//! size fitting the maximum size asked by the consumer, videos get a `<video>` player and other
+//! blobs are links. Like on the preview pages, blobs behind a password or a download limit are
+//! only ever links.
+use std::io;
+
+use actix_web::dev::Url;
+use actix_web::http::Uri;
+
+use serde::Serialize;
+
+use super::html::escape_html;
+use super::info;
+use super::store::{is_hidden, BlobStore};
+use super::variants::{self, MAX_DIMENSION};
+use super::{authorize, dedup, file, meta, PathBufWrp};
+use crate::config::{Tenant, DEFAULT_TENANT};
+
+/// Largest side of the thumbnails of photos.
+const THUMBNAIL_SIZE: u32 = 320;
+
+/// Size of video players, the dimensions of videos are unknown.
+const PLAYER_WIDTH: u32 = 640;
+const PLAYER_HEIGHT: u32 = 360;
+
+/// An oEmbed response, see <https://oembed.com>.
+#[derive(Debug, Serialize)]
+pub struct OEmbed {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    provider_name: String,
+    provider_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    html: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_height: Option<u32>,
+}
+
+impl OEmbed {
+    fn new(kind: &'static str, title: &str, tenant: &Tenant) -> Self {
+        OEmbed {
+            version: "1.0",
+            kind,
+            title: title.to_string(),
+            provider_name: provider_name(tenant),
+            provider_url: tenant.base_url.clone(),
+            url: None,
+            html: None,
+            width: None,
+            height: None,
+            thumbnail_url: None,
+            thumbnail_width: None,
+            thumbnail_height: None,
+        }
+    }
+}
+
+/// Return the name of `tenant`, the host of its base URL for the default tenant.
+fn provider_name(tenant: &Tenant) -> String {
+    if tenant.name != DEFAULT_TENANT {
+        return tenant.name.clone();
+    }
+
+    let url = tenant.base_url.as_str();
+    let host = url.find("://").map_or(url, |index| &url[index + 3..]);
+    host.split('/').next().unwrap_or(host).to_string()
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "No blob at this URL")
+}
+
+/// Return `width` by `height` shrunk to fit in `max_width` by `max_height`, keeping its ratio.
+fn fit(width: u32, height: u32, max_width: Option<u32>, max_height: Option<u32>) -> (u32, u32) {
+    let scale_width = max_width.map_or(1.0, |max_width| f64::from(max_width) / f64::from(width));
+    let scale_height = max_height.map_or(1.0, |max_height| f64::from(max_height) / f64::from(height));
+    let scale = scale_width.min(scale_height).min(1.0);
+
+    let width = ((f64::from(width) * scale).round() as u32).max(1);
+    let height = ((f64::from(height) * scale).round() as u32).max(1);
+    (width, height)
+}
+
+/// Return `url` with `query` resized to fit in `size` with the variant parameters, and its size.
+///
+/// The variant parameters already in `query` are replaced. The box is shrunk to the allowed
+/// `sizes`, `None` if none fits in it.
+fn resized(
+    url: &str,
+    query: &str,
+    size: (u32, u32),
+    original: (u32, u32),
+    sizes: &[u32],
+) -> Option<(String, (u32, u32))> {
+    let mut parameters: Vec<String> = query
+        .split('&')
+        .filter(|parameter| {
+            let key = parameter.split('=').next().unwrap_or(parameter);
+            !parameter.is_empty() && !["w", "h", "fit", "format"].contains(&key)
+        })
+        .map(str::to_string)
+        .collect();
+
+    let size = if size == original {
+        original
+    } else {
+        let (width, height) = (variants::snap(sizes, size.0)?, variants::snap(sizes, size.1)?);
+        parameters.push(format!("w={}&h={}", width, height));
+        fit(original.0, original.1, Some(width), Some(height))
+    };
+
+    if parameters.is_empty() {
+        Some((url.to_string(), size))
+    } else {
+        Some((format!("{}?{}", url, parameters.join("&")), size))
+    }
+}
+
+/// Describe the blob at `url`, a link to it or to its preview page under the base URL of `tenant`.
+///
+/// Errors of kind `NotFound` mean `url` isn't a blob of `tenant`, `PermissionDenied` that it is
+/// private and `url` isn't signed.
+pub fn describe(
+    store: &dyn BlobStore,
+    tenant: &Tenant,
+    url: &str,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+) -> io::Result<OEmbed> {
+    let path = url
+        .strip_prefix(&tenant.base_url)
+        .and_then(|path| path.strip_prefix('/'))
+        .ok_or_else(not_found)?;
+    let (path, query) = match path.find('?') {
+        Some(index) => (&path[..index], &path[index + 1..]),
+        None => (path, ""),
+    };
+    let path = path.strip_prefix("v/").unwrap_or(path);
+
+    // Decoded like the paths of the requests served by `CryptFiles`
+    let name = format!("/{}", path)
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| PathBufWrp::get_pathbuf(Url::new(uri).path()).ok())
+        .map(|path| path.to_blob_name())
+        .ok_or_else(not_found)?;
+    let name = name.as_str();
+    if name.is_empty() || is_hidden(name) {
+        return Err(not_found());
+    }
+
+    let storage_name = match store.stat(name) {
+        Ok(stat) if !stat.is_dir => name.to_string(),
+        Ok(_) => return Err(not_found()),
+        Err(_) => dedup::resolve_alias(store, name)?.ok_or_else(not_found)?,
+    };
+
+    let meta = meta::read(store, name)?.unwrap_or_default();
+    if authorize(tenant, &meta, name, query).is_err() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Private blob"));
+    }
+
+    let path_url = format!("{}/{}", tenant.base_url, path);
+    let blob_url = match query {
+        "" => path_url.clone(),
+        query => format!("{}?{}", path_url, query),
+    };
+
+    // Consumers fetching the blob would be prompted, or use up its downloads
+    if meta.password.is_some() || meta.max_downloads.is_some() {
+        return Ok(OEmbed::new("link", name, tenant));
+    }
+
+    let content_type = file::content_type(std::path::Path::new(name));
+    match content_type.type_() {
+        mime::IMAGE => {
+            let info = info::read(store, &tenant.keys(), &storage_name, name)?;
+            let original = match (info.width, info.height) {
+                (Some(width), Some(height)) if width > 0 && height > 0 => (width, height),
+                // Not an image that can be decoded
+                _ => return Ok(OEmbed::new("link", name, tenant)),
+            };
+
+            // Variants can't be larger
+            let size = fit(
+                original.0,
+                original.1,
+                max_width.map(|max_width| max_width.min(MAX_DIMENSION)),
+                max_height.map(|max_height| max_height.min(MAX_DIMENSION)),
+            );
+            let thumbnail_size = fit(
+                original.0,
+                original.1,
+                Some(max_width.unwrap_or(THUMBNAIL_SIZE).min(THUMBNAIL_SIZE)),
+                Some(max_height.unwrap_or(THUMBNAIL_SIZE).min(THUMBNAIL_SIZE)),
+            );
+
+            let sizes = &tenant.variants.sizes;
+            let ((url, size), (thumbnail_url, thumbnail_size)) = match (
+                resized(&path_url, query, size, original, sizes),
+                resized(&path_url, query, thumbnail_size, original, sizes),
+            ) {
+                (Some(photo), Some(thumbnail)) => (photo, thumbnail),
+                // No variant is small enough
+                _ => return Ok(OEmbed::new("link", name, tenant)),
+            };
+
+            let mut oembed = OEmbed::new("photo", name, tenant);
+            oembed.url = Some(url);
+            oembed.width = Some(size.0);
+            oembed.height = Some(size.1);
+            oembed.thumbnail_url = Some(thumbnail_url);
+            oembed.thumbnail_width = Some(thumbnail_size.0);
+            oembed.thumbnail_height = Some(thumbnail_size.1);
+            Ok(oembed)
+        }
+        mime::VIDEO => {
+            let (width, height) = fit(PLAYER_WIDTH, PLAYER_HEIGHT, max_width, max_height);
+
+            let mut oembed = OEmbed::new("video", name, tenant);
+            oembed.html = Some(format!(
+                "<video src=\"{}\" width=\"{}\" height=\"{}\" controls></video>",
+                escape_html(&blob_url),
+                width,
+                height
+            ));
+            oembed.width = Some(width);
+            oembed.height = Some(height);
+            Ok(oembed)
+        }
+        _ => Ok(OEmbed::new("link", name, tenant)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use image::{DynamicImage, ImageFormat, RgbImage};
+
+    use super::super::meta::{self, BlobMeta};
+    use super::super::signed;
+    use super::super::store::{BlobStore, MemoryBlobStore};
+    use super::{describe, resized, OEmbed};
+    use crate::config::tests::{load, settings};
+    use crate::config::Tenant;
+
+    /// Return the default tenant and its store, holding a 800x800 `photo.png`.
+    fn setup() -> (Tenant, MemoryBlobStore) {
+        let config =
+            load(&settings("[storage]\nbackend = \"memory\"\n[variants]\nsizes = [160, 320, 640]"))
+                .unwrap();
+        let tenant = (*config.tenants[0]).clone();
+
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(800, 800))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let store = MemoryBlobStore::default();
+        store.put("photo.png", &mut Cursor::new(tenant.key.encrypt(&png))).unwrap();
+        (tenant, store)
+    }
+
+    fn describe_url(
+        tenant: &Tenant,
+        store: &MemoryBlobStore,
+        url: &str,
+        max_width: Option<u32>,
+    ) -> OEmbed {
+        describe(store, tenant, url, max_width, None).unwrap()
+    }
+
+    #[test]
+    fn photo() {
+        let (tenant, store) = setup();
+
+        let oembed = describe_url(&tenant, &store, "http://localhost/photo.png", None);
+        assert_eq!((oembed.kind, oembed.title.as_str()), ("photo", "photo.png"));
+        assert_eq!(oembed.provider_name, "localhost");
+        assert_eq!(oembed.url.as_deref(), Some("http://localhost/photo.png"));
+        assert_eq!((oembed.width, oembed.height), (Some(800), Some(800)));
+        assert_eq!(oembed.thumbnail_url.as_deref(), Some("http://localhost/photo.png?w=320&h=320"));
+        assert_eq!((oembed.thumbnail_width, oembed.thumbnail_height), (Some(320), Some(320)));
+
+        let oembed = describe_url(&tenant, &store, "http://localhost/v/photo.png", Some(400));
+        assert_eq!(oembed.url.as_deref(), Some("http://localhost/photo.png?w=320&h=320"));
+        assert_eq!((oembed.width, oembed.height), (Some(320), Some(320)));
+
+        // The variant parameters of the URL are replaced, not repeated
+        let url = "http://localhost/photo.png?w=640&h=640&fit=cover&format=webp&x=1";
+        let oembed = describe_url(&tenant, &store, url, Some(200));
+        assert_eq!(oembed.url.as_deref(), Some("http://localhost/photo.png?x=1&w=160&h=160"));
+        assert_eq!(
+            oembed.thumbnail_url.as_deref(),
+            Some("http://localhost/photo.png?x=1&w=160&h=160")
+        );
+        let oembed = describe_url(&tenant, &store, url, None);
+        assert_eq!(oembed.url.as_deref(), Some("http://localhost/photo.png?x=1"));
+        assert_eq!((oembed.width, oembed.height), (Some(800), Some(800)));
+
+        // Smaller than every size
+        assert_eq!(
+            describe_url(&tenant, &store, "http://localhost/photo.png", Some(100)).kind,
+            "link"
+        );
+        assert_eq!(resized("u", "", (100, 100), (800, 800), &[160]), None);
+    }
+
+    #[test]
+    fn video() {
+        let (tenant, store) = setup();
+        store.put("clip.mp4", &mut Cursor::new(vec![0; 64])).unwrap();
+
+        let oembed = describe_url(&tenant, &store, "http://localhost/clip.mp4?a=1&b=2", Some(320));
+        assert_eq!(oembed.kind, "video");
+        assert_eq!(
+            oembed.html.as_deref(),
+            Some(
+                "<video src=\"http://localhost/clip.mp4?a=1&amp;b=2\" width=\"320\" height=\"180\" \
+                 controls></video>"
+            )
+        );
+        assert_eq!((oembed.width, oembed.height), (Some(320), Some(180)));
+        assert_eq!(oembed.url, None);
+    }
+
+    #[test]
+    fn link() {
+        let (tenant, store) = setup();
+        store.put("notes.txt", &mut Cursor::new(vec![0; 64])).unwrap();
+
+        let oembed = describe_url(&tenant, &store, "http://localhost/notes.txt", None);
+        assert_eq!((oembed.kind, oembed.title.as_str()), ("link", "notes.txt"));
+        assert_eq!((oembed.url, oembed.html, oembed.width), (None, None, None));
+
+        // Prompted for a password, or using up downloads
+        for meta in &[
+            BlobMeta { password: Some("hash".to_string()), ..BlobMeta::default() },
+            BlobMeta { max_downloads: Some(1), ..BlobMeta::default() },
+        ] {
+            meta::write(&store, "photo.png", meta).unwrap();
+            let oembed = describe_url(&tenant, &store, "http://localhost/photo.png", None);
+            assert_eq!((oembed.kind, oembed.url), ("link", None));
+        }
+    }
+
+    #[test]
+    fn private() {
+        let (tenant, store) = setup();
+        meta::write(&store, "photo.png", &BlobMeta { private: true, ..BlobMeta::default() })
+            .unwrap();
+
+        let error =
+            describe(&store, &tenant, "http://localhost/photo.png", None, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        let query = signed::signed_query(&tenant.url_signing_key, "photo.png", 0);
+        let url = format!("http://localhost/photo.png?{}", query);
+        let error = describe(&store, &tenant, &url, None, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        let query = signed::signed_query(&tenant.url_signing_key, "photo.png", u64::MAX);
+        let url = format!("http://localhost/photo.png?{}", query);
+        let oembed = describe_url(&tenant, &store, &url, Some(400));
+        assert_eq!(oembed.kind, "photo");
+        assert_eq!(oembed.url, Some(format!("{}&w=320&h=320", url)));
+    }
+
+    #[test]
+    fn paths() {
+        let (tenant, store) = setup();
+        store.put("album/my photo.mp4", &mut Cursor::new(vec![0; 64])).unwrap();
+
+        let oembed = describe_url(&tenant, &store, "http://localhost/album/my%20photo.mp4", None);
+        assert_eq!((oembed.kind, oembed.title.as_str()), ("video", "album/my photo.mp4"));
+        assert!(oembed.html.unwrap().contains("src=\"http://localhost/album/my%20photo.mp4\""));
+
+        for url in &[
+            "http://localhost/missing.png",
+            "http://localhost/album",
+            "http://localhost/",
+            "http://localhost/.meta/photo.png",
+            "http://localhostphoto.png",
+            "http://example.com/photo.png",
+        ] {
+            let error = describe(&store, &tenant, url, None, None).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::NotFound, "{}", url);
+        }
+    }
+}
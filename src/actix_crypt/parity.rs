@@ -0,0 +1,318 @@
+//! Reed-Solomon parity data, used to repair blobs damaged by bit rot.
+//!
+//! The parity of a blob is stored as `.parity/<name>` in the blob store.
+//! Every shard carries its SHA-256 so damaged shards can be located and rebuilt as erasures.
+use std::io::{self, Cursor, Read};
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use sha2::{Digest, Sha256};
+
+use super::crypt::{BlobKey, EncryptedBlob};
+use super::store::BlobStore;
+
+const PARITY_MAGIC: &[u8; 8] = b"IMGPAR01";
+const PARITY_DIRECTORY: &str = ".parity";
+const DATA_SHARDS: usize = 64;
+
+/// Largest amount of parity shards, the codec handles up to 256 shards.
+pub const MAX_PARITY_SHARDS: usize = 256 - DATA_SHARDS;
+const SHARD_HASH_SIZE: usize = 0x20;
+
+/// magic + data shards + parity shards + shard size + file size
+const PARITY_HEADER_SIZE: usize = 8 + 4 + 4 + 4 + 8;
+
+#[derive(Debug, PartialEq)]
+pub enum RepairOutcome {
+    /// Every shard matches its hash.
+    Intact,
+    /// The given amount of shards were rebuilt and the blob passed verification.
+    Repaired(usize),
+}
+
+struct ParityData {
+    parity_shards: usize,
+    shard_size: usize,
+    file_size: u64,
+    hashes: Vec<[u8; SHARD_HASH_SIZE]>,
+    parity: Vec<Vec<u8>>,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn shard_hash(shard: &[u8]) -> [u8; SHARD_HASH_SIZE] {
+    let mut result = [0; SHARD_HASH_SIZE];
+    result.copy_from_slice(&Sha256::digest(shard));
+    result
+}
+
+/// Return the name of the parity data of the blob `name`.
+pub fn parity_name(name: &str) -> String {
+    format!("{}/{}", PARITY_DIRECTORY, name)
+}
+
+fn split_shards(data: &[u8], shard_size: usize) -> Vec<Vec<u8>> {
+    (0..DATA_SHARDS)
+        .map(|index| {
+            let start = std::cmp::min(index * shard_size, data.len());
+            let end = std::cmp::min(start + shard_size, data.len());
+
+            let mut shard = data[start..end].to_vec();
+            shard.resize(shard_size, 0);
+            shard
+        })
+        .collect()
+}
+
+fn codec(parity_shards: usize) -> io::Result<ReedSolomon> {
+    ReedSolomon::new(DATA_SHARDS, parity_shards)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+}
+
+/// Generate and store the parity data of the blob `name` containing `data`.
+///
+/// `parity_shards` is the amount of damaged shards the blob can recover from, 0 disables parity.
+pub fn write_parity(store: &dyn BlobStore, name: &str, data: &[u8], parity_shards: usize) -> io::Result<()> {
+    if parity_shards == 0 {
+        return Ok(());
+    }
+
+    let shard_size = std::cmp::max(1, data.len().div_ceil(DATA_SHARDS));
+
+    let mut shards = split_shards(data, shard_size);
+    shards.resize(DATA_SHARDS + parity_shards, vec![0; shard_size]);
+    codec(parity_shards)?
+        .encode(&mut shards)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    let mut output = Vec::with_capacity(
+        PARITY_HEADER_SIZE + shards.len() * SHARD_HASH_SIZE + parity_shards * shard_size,
+    );
+    output.extend_from_slice(PARITY_MAGIC);
+    output.extend_from_slice(&(DATA_SHARDS as u32).to_le_bytes());
+    output.extend_from_slice(&(parity_shards as u32).to_le_bytes());
+    output.extend_from_slice(&(shard_size as u32).to_le_bytes());
+    output.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    for shard in shards.iter() {
+        output.extend_from_slice(&shard_hash(shard));
+    }
+    for shard in shards[DATA_SHARDS..].iter() {
+        output.extend_from_slice(shard);
+    }
+
+    store.put(&parity_name(name), &mut Cursor::new(output))
+}
+
+fn read_parity(store: &dyn BlobStore, name: &str) -> io::Result<ParityData> {
+    let mut file = store.get(&parity_name(name))?;
+
+    let mut header = [0u8; PARITY_HEADER_SIZE];
+    file.read_exact(&mut header)?;
+
+    if &header[..8] != PARITY_MAGIC {
+        return Err(invalid_data("Invalid parity magic"));
+    }
+
+    let mut word = [0u8; 4];
+    word.copy_from_slice(&header[8..12]);
+    let data_shards = u32::from_le_bytes(word) as usize;
+    word.copy_from_slice(&header[12..16]);
+    let parity_shards = u32::from_le_bytes(word) as usize;
+    word.copy_from_slice(&header[16..20]);
+    let shard_size = u32::from_le_bytes(word) as usize;
+
+    let mut dword = [0u8; 8];
+    dword.copy_from_slice(&header[20..28]);
+    let file_size = u64::from_le_bytes(dword);
+
+    if data_shards != DATA_SHARDS
+        || parity_shards == 0
+        || parity_shards > MAX_PARITY_SHARDS
+        || shard_size == 0
+    {
+        return Err(invalid_data("Unsupported parity layout"));
+    }
+
+    // The blob is resized to this size before being repaired
+    if file_size > (shard_size * DATA_SHARDS) as u64 {
+        return Err(invalid_data("Parity file size exceeds its shards"));
+    }
+
+    let mut hashes = vec![[0u8; SHARD_HASH_SIZE]; data_shards + parity_shards];
+    for hash in hashes.iter_mut() {
+        file.read_exact(hash)?;
+    }
+
+    let mut parity = vec![vec![0u8; shard_size]; parity_shards];
+    for shard in parity.iter_mut() {
+        file.read_exact(shard)?;
+    }
+
+    Ok(ParityData {
+        parity_shards,
+        shard_size,
+        file_size,
+        hashes,
+        parity,
+    })
+}
+
+/// Try to repair the blob `name` using its parity data.
+///
+/// The rebuilt blob is verified against the SHA-256 of its header before replacing the original.
+pub fn repair(store: &dyn BlobStore, name: &str, key: BlobKey) -> io::Result<RepairOutcome> {
+    let parity_data = read_parity(store, name)?;
+
+    // A truncated blob is handled like any other damage
+    let mut data = Vec::new();
+    store.get(name)?.read_to_end(&mut data)?;
+    data.resize(parity_data.file_size as usize, 0);
+
+    let hashes = parity_data.hashes;
+    let mut shards: Vec<(Vec<u8>, bool)> = split_shards(&data, parity_data.shard_size)
+        .into_iter()
+        .chain(parity_data.parity)
+        .enumerate()
+        .map(|(index, shard)| {
+            let present = shard_hash(&shard) == hashes[index];
+            (shard, present)
+        })
+        .collect();
+
+    let damaged = shards.iter().filter(|(_, present)| !present).count();
+    if damaged == 0 {
+        return Ok(RepairOutcome::Intact);
+    }
+
+    if damaged > parity_data.parity_shards {
+        return Err(invalid_data("Damage exceeds the parity budget"));
+    }
+
+    codec(parity_data.parity_shards)?
+        .reconstruct_data(&mut shards)
+        .map_err(|e| invalid_data(&e.to_string()))?;
+
+    let mut repaired: Vec<u8> = shards[..DATA_SHARDS]
+        .iter()
+        .flat_map(|(shard, _)| shard.iter().cloned())
+        .collect();
+    repaired.truncate(parity_data.file_size as usize);
+
+    // Never trust the reconstruction blindly, the content must match the header hash
+    let mut encrypted_blob = EncryptedBlob::from(Cursor::new(repaired), key)?;
+    if !encrypted_blob.is_header_magic_valid() || !encrypted_blob.is_content_valid() {
+        return Err(invalid_data("Repaired blob failed verification"));
+    }
+    let mut repaired = encrypted_blob.into_inner();
+    repaired.set_position(0);
+    store.put(name, &mut repaired)?;
+
+    Ok(RepairOutcome::Repaired(damaged))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read};
+
+    use super::super::crypt::BlobKey;
+    use super::super::store::{BlobStore, MemoryBlobStore};
+    use super::{parity_name, repair, write_parity, RepairOutcome, DATA_SHARDS};
+
+    fn key() -> BlobKey {
+        BlobKey::new(&"00".repeat(32), "IMAGERS0").unwrap()
+    }
+
+    /// Store an encrypted blob `name` with 8 parity shards, return its content.
+    fn setup(store: &dyn BlobStore, name: &str) -> Vec<u8> {
+        let plaintext: Vec<u8> = (0..65_536u32).map(|i| (i * 7 % 251) as u8).collect();
+        let data = key().encrypt(&plaintext);
+        store.put(name, &mut Cursor::new(&data)).unwrap();
+        write_parity(store, name, &data, 8).unwrap();
+        data
+    }
+
+    fn content(store: &dyn BlobStore, name: &str) -> Vec<u8> {
+        let mut result = Vec::new();
+        store.get(name).unwrap().read_to_end(&mut result).unwrap();
+        result
+    }
+
+    /// Flip a byte in each of the data shards `shards` of `data`.
+    fn damage(data: &[u8], shards: &[usize]) -> Vec<u8> {
+        let shard_size = data.len().div_ceil(DATA_SHARDS);
+        let mut result = data.to_vec();
+        for shard in shards {
+            result[shard * shard_size + 1] ^= 0xff;
+        }
+        result
+    }
+
+    #[test]
+    fn within_budget() {
+        let store = MemoryBlobStore::default();
+        let data = setup(&store, "blob");
+        assert_eq!(repair(&store, "blob", key()).unwrap(), RepairOutcome::Intact);
+
+        let damaged = damage(&data, &[0, 1, 30, 40, 50, 60, 62, 63]);
+        store.put("blob", &mut Cursor::new(damaged)).unwrap();
+        assert_eq!(repair(&store, "blob", key()).unwrap(), RepairOutcome::Repaired(8));
+        assert_eq!(content(&store, "blob"), data);
+
+        // A truncated blob is missing its last shards
+        let shard_size = data.len().div_ceil(DATA_SHARDS);
+        store.put("blob", &mut Cursor::new(&data[..(DATA_SHARDS - 2) * shard_size])).unwrap();
+        assert_eq!(repair(&store, "blob", key()).unwrap(), RepairOutcome::Repaired(2));
+        assert_eq!(content(&store, "blob"), data);
+    }
+
+    #[test]
+    fn over_budget() {
+        let store = MemoryBlobStore::default();
+        let data = setup(&store, "blob");
+
+        let damaged = damage(&data, &(0..9).collect::<Vec<_>>());
+        store.put("blob", &mut Cursor::new(&damaged)).unwrap();
+        let error = repair(&store, "blob", key()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Damage exceeds the parity budget");
+        assert_eq!(content(&store, "blob"), damaged);
+    }
+
+    #[test]
+    fn failed_verification() {
+        let store = MemoryBlobStore::default();
+
+        // The parity of a blob whose content doesn't match its header rebuilds it as it was
+        let data = setup(&store, "blob");
+        let tampered = damage(&data, &[10]);
+        write_parity(&store, "blob", &tampered, 8).unwrap();
+        let damaged = damage(&tampered, &[20]);
+        store.put("blob", &mut Cursor::new(&damaged)).unwrap();
+
+        let error = repair(&store, "blob", key()).unwrap_err();
+        assert_eq!(error.to_string(), "Repaired blob failed verification");
+        assert_eq!(content(&store, "blob"), damaged);
+
+        // Blobs of another key are never replaced
+        let data = setup(&store, "other");
+        store.put("other", &mut Cursor::new(damage(&data, &[20]))).unwrap();
+        let other_key = BlobKey::new(&"01".repeat(32), "IMAGERS0").unwrap();
+        assert!(repair(&store, "other", other_key).is_err());
+    }
+
+    #[test]
+    fn oversized_file_size() {
+        let store = MemoryBlobStore::default();
+        let data = setup(&store, "blob");
+        store.put("blob", &mut Cursor::new(damage(&data, &[0]))).unwrap();
+
+        let mut parity = content(&store, &parity_name("blob"));
+        parity[20..28].copy_from_slice(&u64::MAX.to_le_bytes());
+        store.put(&parity_name("blob"), &mut Cursor::new(parity)).unwrap();
+
+        let error = repair(&store, "blob", key()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,231 @@
+//! Password protection of blobs.
+//!
+//! A protected blob is served as a password prompt. Posting the right password serves the blob
+//! and sets a short-lived cookie unlocking it, scoped to its path and signed like the signed
+//! URLs. Failed attempts are limited per client and blob, an attempt counts as failed from its
+//! start so concurrent ones can't exceed the limit.
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::{header, StatusCode};
+use actix_web::HttpResponse;
+
+use lazy_static::lazy_static;
+
+use rand::RngCore;
+
+use super::crypt::BlobKey;
+use super::html::escape_html;
+
+/// How long a correct password unlocks a blob.
+pub const UNLOCK_LIFETIME: u64 = 600;
+
+/// Name of the cookie unlocking a blob, its path is the one of the blob.
+pub const UNLOCK_COOKIE: &str = "imagers_unlock";
+
+/// Failed attempts allowed per client and blob in `FAILED_ATTEMPTS_WINDOW`.
+const MAX_FAILED_ATTEMPTS: usize = 5;
+const FAILED_ATTEMPTS_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// Clients whose failed attempts are remembered, the ones of the oldest attempts are forgotten.
+const MAX_CLIENTS: usize = 100_000;
+
+lazy_static! {
+    /// Time of the recent failed attempts, by tenant, blob and client.
+    static ref FAILED_ATTEMPTS: Mutex<FailedAttempts> = Mutex::new(FailedAttempts {
+        attempts: HashMap::new(),
+        pruned: Instant::now(),
+    });
+}
+
+/// Hash `password` with Argon2id, the result is self-describing.
+pub fn hash(password: &str) -> io::Result<String> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        mem_cost: 19 * 1024,
+        time_cost: 2,
+        ..argon2::Config::default()
+    };
+
+    argon2::hash_encoded(password.as_bytes(), &salt, &config)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+}
+
+/// Check `password` against a hash made by `hash`.
+pub fn verify(password_hash: &str, password: &str) -> bool {
+    argon2::verify_encoded(password_hash, password.as_bytes()).unwrap_or(false)
+}
+
+fn attempts_key(tenant: &str, name: &str, client: &str) -> String {
+    format!("{}/{}/{}", tenant, name, client)
+}
+
+/// Failed attempts of every client, pruned of the expired ones.
+struct FailedAttempts {
+    attempts: HashMap<String, VecDeque<Instant>>,
+    /// Last time every expired attempt was dropped.
+    pruned: Instant,
+}
+
+impl FailedAttempts {
+    /// Drop the expired attempts, then the clients of the oldest ones if still over `MAX_CLIENTS`.
+    fn prune(&mut self, now: Instant) {
+        self.attempts.retain(|_, attempts| {
+            while let Some(attempt) = attempts.front() {
+                if now.duration_since(*attempt) < FAILED_ATTEMPTS_WINDOW {
+                    break;
+                }
+                attempts.pop_front();
+            }
+            !attempts.is_empty()
+        });
+        self.pruned = now;
+
+        while self.attempts.len() >= MAX_CLIENTS {
+            let oldest = self
+                .attempts
+                .iter()
+                .min_by_key(|(_, attempts)| attempts.back().copied())
+                .map(|(key, _)| key.clone())
+                .unwrap();
+            self.attempts.remove(&oldest);
+        }
+    }
+
+    fn start(&mut self, key: String, now: Instant) -> Option<Attempt> {
+        let is_due = now.duration_since(self.pruned) >= FAILED_ATTEMPTS_WINDOW;
+        if is_due || self.attempts.len() >= MAX_CLIENTS {
+            self.prune(now);
+        }
+
+        let attempts = self.attempts.entry(key.clone()).or_default();
+        while let Some(attempt) = attempts.front() {
+            if now.duration_since(*attempt) < FAILED_ATTEMPTS_WINDOW {
+                break;
+            }
+            attempts.pop_front();
+        }
+        if attempts.len() >= MAX_FAILED_ATTEMPTS {
+            return None;
+        }
+
+        attempts.push_back(now);
+        Some(Attempt { key, time: now })
+    }
+
+    fn forget(&mut self, attempt: &Attempt) {
+        if let Some(attempts) = self.attempts.get_mut(&attempt.key) {
+            if let Some(position) = attempts.iter().position(|time| *time == attempt.time) {
+                attempts.remove(position);
+            }
+            if attempts.is_empty() {
+                self.attempts.remove(&attempt.key);
+            }
+        }
+    }
+}
+
+/// An attempt to unlock a blob, counted as failed unless forgotten.
+pub struct Attempt {
+    key: String,
+    time: Instant,
+}
+
+impl Attempt {
+    /// Stop counting the attempt as failed, the password was right.
+    pub fn forget(self) {
+        FAILED_ATTEMPTS.lock().unwrap().forget(&self);
+    }
+}
+
+/// Start an attempt of `client` to unlock `name`, `None` if it failed too many times recently.
+///
+/// The attempt counts as failed until forgotten, concurrent attempts can't exceed the limit.
+pub fn start_attempt(tenant: &str, name: &str, client: &str) -> Option<Attempt> {
+    FAILED_ATTEMPTS
+        .lock()
+        .unwrap()
+        .start(attempts_key(tenant, name, client), Instant::now())
+}
+
+/// Return the key of the unlock cookies of blobs encrypted with `key`.
+pub fn cookie_key(key: &BlobKey) -> [u8; 32] {
+    key.derive("imagers unlock cookie")
+}
+
+/// Return the password prompt of `name`, `status` tells why it is shown.
+pub fn prompt(name: &str, status: StatusCode) -> HttpResponse {
+    let message = match status {
+        StatusCode::FORBIDDEN => "<p>Wrong password.</p>",
+        StatusCode::TOO_MANY_REQUESTS => "<p>Too many failed attempts, try again later.</p>",
+        _ => "",
+    };
+
+    let name = escape_html(name);
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="robots" content="noindex">
+<title>{name}</title>
+</head>
+<body>
+<form method="post">
+<p>{name} is protected by a password.</p>
+{message}
+<input type="password" name="password" autofocus required>
+<button type="submit">Unlock</button>
+</form>
+</body>
+</html>
+"#,
+        name = name,
+        message = message
+    );
+
+    HttpResponse::build(status)
+        .header(header::CACHE_CONTROL, "no-store")
+        .content_type("text/html; charset=utf-8")
+        .body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use super::{FailedAttempts, FAILED_ATTEMPTS_WINDOW, MAX_FAILED_ATTEMPTS};
+
+    #[test]
+    fn failed_attempts() {
+        let mut failed_attempts = FailedAttempts {
+            attempts: HashMap::new(),
+            pruned: Instant::now(),
+        };
+        let now = Instant::now();
+
+        // Pending attempts count, concurrent ones can't exceed the limit
+        let attempts: Vec<_> = (0..MAX_FAILED_ATTEMPTS)
+            .map(|_| failed_attempts.start("blob".to_string(), now).unwrap())
+            .collect();
+        assert!(failed_attempts.start("blob".to_string(), now).is_none());
+        assert!(failed_attempts.start("other".to_string(), now).is_some());
+
+        // A right password isn't a failure
+        failed_attempts.forget(&attempts[0]);
+        assert!(failed_attempts.start("blob".to_string(), now).is_some());
+        assert!(failed_attempts.start("blob".to_string(), now).is_none());
+
+        // Expired attempts are dropped
+        let later = now + FAILED_ATTEMPTS_WINDOW + Duration::from_secs(1);
+        failed_attempts.prune(later);
+        assert!(failed_attempts.attempts.is_empty());
+        assert!(failed_attempts.start("blob".to_string(), later).is_some());
+    }
+}
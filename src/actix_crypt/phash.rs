@@ -0,0 +1,311 @@
+//! Perceptual hashes of images, to find their re-encoded, resized or recompressed copies.
+//!
+//! The hash of an uploaded image is its 64 bits dHash, stored in hexadecimal as `.phash/<name>`.
+//! Similar images have hashes with a small Hamming distance. The server searches them in a
+//! `PhashIndex` loaded at start.
+//!
+//! Moderators block the hashes of removed content in `.blocklist`, one `<hash> <note>` line each,
+//! the note being like the name of the removed blob. Depending on the tenant, uploads matching a
+//! blocked hash are rejected or flagged as `.flagged/<name>`, holding the blocked hash they
+//! matched, or `undecodable` for images whose hash cannot be computed.
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read};
+use std::sync::RwLock;
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+use super::store::{BlobStore, StoreLock};
+
+const PHASH_PREFIX: &str = ".phash/";
+const BLOCKLIST_NAME: &str = ".blocklist";
+const BLOCKLIST_LOCK: &str = "blocklist";
+const FLAGGED_PREFIX: &str = ".flagged/";
+const UNDECODABLE: &str = "undecodable";
+
+pub type PerceptualHash = u64;
+
+/// Return the dHash of `image`: whether each pixel of a 9x8 grayscale thumbnail is brighter than
+/// the one on its right.
+pub fn dhash(image: &DynamicImage) -> PerceptualHash {
+    let thumbnail = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let is_brighter = thumbnail.get_pixel(x, y)[0] > thumbnail.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | PerceptualHash::from(is_brighter);
+        }
+    }
+    hash
+}
+
+/// Return the amount of differing bits of two hashes.
+pub fn distance(first: PerceptualHash, second: PerceptualHash) -> u32 {
+    (first ^ second).count_ones()
+}
+
+pub fn to_hex(hash: PerceptualHash) -> String {
+    format!("{:016x}", hash)
+}
+
+/// Parse a hash written by `to_hex`.
+pub fn from_hex(hash: &str) -> Option<PerceptualHash> {
+    if hash.len() != 16 {
+        return None;
+    }
+    PerceptualHash::from_str_radix(hash, 16).ok()
+}
+
+fn read_string(store: &dyn BlobStore, name: &str) -> io::Result<Option<String>> {
+    let mut content = String::new();
+    match store.get(name) {
+        Ok(mut file) => file.read_to_string(&mut content)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    Ok(Some(content.trim().to_string()))
+}
+
+fn read_hash(store: &dyn BlobStore, name: &str) -> io::Result<Option<PerceptualHash>> {
+    match read_string(store, name)? {
+        Some(hash) => from_hex(&hash)
+            .map(Some)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a hash", name))),
+        None => Ok(None),
+    }
+}
+
+fn delete_entry(store: &dyn BlobStore, name: &str) -> io::Result<()> {
+    match store.delete(name) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Record `hash` as the perceptual hash of the blob `name`.
+fn index(store: &dyn BlobStore, name: &str, hash: PerceptualHash) -> io::Result<()> {
+    store.put(&format!("{}{}", PHASH_PREFIX, name), &mut Cursor::new(to_hex(hash)))
+}
+
+/// Return the perceptual hash of the blob `name`, `None` if it isn't an indexed image.
+pub fn read(store: &dyn BlobStore, name: &str) -> io::Result<Option<PerceptualHash>> {
+    read_hash(store, &format!("{}{}", PHASH_PREFIX, name))
+}
+
+/// Delete the perceptual hash and the flag of the blob `name`.
+pub fn delete(store: &dyn BlobStore, name: &str) -> io::Result<()> {
+    delete_entry(store, &format!("{}{}", PHASH_PREFIX, name))?;
+    delete_entry(store, &format!("{}{}", FLAGGED_PREFIX, name))
+}
+
+/// The perceptual hashes of the images of a store, searched without reading every hash.
+///
+/// Uploads of the server add their hash, images deleted since are dropped when found.
+pub struct PhashIndex {
+    hashes: RwLock<HashMap<String, PerceptualHash>>,
+}
+
+impl PhashIndex {
+    /// Read every perceptual hash of `store`.
+    pub fn load(store: &dyn BlobStore) -> io::Result<Self> {
+        let mut hashes = HashMap::new();
+        for entry in store.list(PHASH_PREFIX)? {
+            // Deleted since listed
+            if let Some(hash) = read_hash(store, &entry)? {
+                hashes.insert(entry[PHASH_PREFIX.len()..].to_string(), hash);
+            }
+        }
+
+        Ok(PhashIndex {
+            hashes: RwLock::new(hashes),
+        })
+    }
+
+    /// Record `hash` as the perceptual hash of the blob `name`, in the store and the index.
+    pub fn index(&self, store: &dyn BlobStore, name: &str, hash: PerceptualHash) -> io::Result<()> {
+        index(store, name, hash)?;
+        self.hashes.write().unwrap().insert(name.to_string(), hash);
+        Ok(())
+    }
+
+    /// Return the name and distance of every image within `max_distance` of `hash`, closest
+    /// first.
+    pub fn near_duplicates(
+        &self,
+        store: &dyn BlobStore,
+        hash: PerceptualHash,
+        max_distance: u32,
+    ) -> io::Result<Vec<(String, u32)>> {
+        let candidates: Vec<_> = self
+            .hashes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, other)| (name.clone(), distance(hash, *other)))
+            .filter(|(_, distance)| *distance <= max_distance)
+            .collect();
+
+        // Only the matches are read, the images may have been deleted by another process
+        let mut result = Vec::new();
+        for (name, distance) in candidates {
+            if read(store, &name)?.is_some() {
+                result.push((name, distance));
+            } else {
+                self.hashes.write().unwrap().remove(&name);
+            }
+        }
+
+        result.sort_by(|first, second| first.1.cmp(&second.1).then_with(|| first.0.cmp(&second.0)));
+        Ok(result)
+    }
+}
+
+fn write_blocklist(
+    store: &dyn BlobStore,
+    blocklist: &[(PerceptualHash, String)],
+) -> io::Result<()> {
+    let content: String = blocklist
+        .iter()
+        .map(|(hash, note)| format!("{} {}\n", to_hex(*hash), note))
+        .collect();
+    store.put(BLOCKLIST_NAME, &mut Cursor::new(content))
+}
+
+/// Block `hash`, `note` is shown when listing the blocklist.
+pub fn block(store: &dyn BlobStore, hash: PerceptualHash, note: &str) -> io::Result<()> {
+    let _lock = StoreLock::acquire(store, BLOCKLIST_LOCK)?;
+
+    let mut blocklist = blocklist(store)?;
+    blocklist.retain(|(blocked, _)| *blocked != hash);
+    // Notes are single lines
+    blocklist.push((hash, note.lines().next().unwrap_or_default().to_string()));
+    write_blocklist(store, &blocklist)
+}
+
+pub fn unblock(store: &dyn BlobStore, hash: PerceptualHash) -> io::Result<()> {
+    let _lock = StoreLock::acquire(store, BLOCKLIST_LOCK)?;
+
+    let mut blocklist = blocklist(store)?;
+    let len = blocklist.len();
+    blocklist.retain(|(blocked, _)| *blocked != hash);
+    if blocklist.len() == len {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not blocked", to_hex(hash)),
+        ));
+    }
+    write_blocklist(store, &blocklist)
+}
+
+/// List every blocked hash with its note.
+pub fn blocklist(store: &dyn BlobStore) -> io::Result<Vec<(PerceptualHash, String)>> {
+    let mut result = Vec::new();
+    for line in read_string(store, BLOCKLIST_NAME)?.unwrap_or_default().lines() {
+        let mut fields = line.splitn(2, ' ');
+        if let Some(hash) = fields.next().and_then(from_hex) {
+            result.push((hash, fields.next().unwrap_or_default().to_string()));
+        }
+    }
+
+    Ok(result)
+}
+
+/// Return the closest blocked hash within `max_distance` of `hash`.
+pub fn find_blocked(
+    store: &dyn BlobStore,
+    hash: PerceptualHash,
+    max_distance: u32,
+) -> io::Result<Option<PerceptualHash>> {
+    Ok(blocklist(store)?
+        .into_iter()
+        .map(|(blocked, _)| blocked)
+        .filter(|blocked| distance(hash, *blocked) <= max_distance)
+        .min_by_key(|blocked| distance(hash, *blocked)))
+}
+
+/// Flag the blob `name` for matching the blocked hash `blocked`, `None` for an undecodable image.
+pub fn flag(store: &dyn BlobStore, name: &str, blocked: Option<PerceptualHash>) -> io::Result<()> {
+    let reason = blocked.map_or_else(|| UNDECODABLE.to_string(), to_hex);
+    store.put(&format!("{}{}", FLAGGED_PREFIX, name), &mut Cursor::new(reason))
+}
+
+/// List every flagged blob with the blocked hash it matched, `None` for an undecodable image.
+pub fn flagged(store: &dyn BlobStore) -> io::Result<Vec<(String, Option<PerceptualHash>)>> {
+    let mut result = Vec::new();
+    for entry in store.list(FLAGGED_PREFIX)? {
+        // Deleted since listed
+        let reason = match read_string(store, &entry)? {
+            Some(reason) => reason,
+            None => continue,
+        };
+        let blocked = match from_hex(&reason) {
+            Some(blocked) => Some(blocked),
+            None if reason == UNDECODABLE => None,
+            None => continue,
+        };
+        result.push((entry[FLAGGED_PREFIX.len()..].to_string(), blocked));
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::store::{BlobStore, MemoryBlobStore};
+    use super::{block, blocklist, delete, find_blocked, flag, flagged, unblock, PhashIndex};
+
+    #[test]
+    fn blocklist_file() {
+        let store = MemoryBlobStore::default();
+
+        block(&store, 0xff, "a.png").unwrap();
+        block(&store, 0xf0f0, "").unwrap();
+        // Blocked again with another note
+        block(&store, 0xff, "b.png").unwrap();
+        assert_eq!(
+            blocklist(&store).unwrap(),
+            [(0xf0f0, String::new()), (0xff, "b.png".to_string())]
+        );
+        assert_eq!(find_blocked(&store, 0xfe, 1).unwrap(), Some(0xff));
+        assert_eq!(find_blocked(&store, 0xfc, 1).unwrap(), None);
+
+        unblock(&store, 0xff).unwrap();
+        assert!(unblock(&store, 0xff).is_err());
+        assert_eq!(blocklist(&store).unwrap(), [(0xf0f0, String::new())]);
+    }
+
+    #[test]
+    fn index() {
+        let store = MemoryBlobStore::default();
+        let phashes = PhashIndex::load(&store).unwrap();
+        phashes.index(&store, "a.png", 0).unwrap();
+        phashes.index(&store, "b.png", 0b111).unwrap();
+        phashes.index(&store, "c.png", 0xffff).unwrap();
+
+        let loaded = PhashIndex::load(&store).unwrap();
+        assert_eq!(
+            loaded.near_duplicates(&store, 1, 4).unwrap(),
+            [("a.png".to_string(), 1), ("b.png".to_string(), 2)]
+        );
+
+        // Deleted by another process
+        delete(&store, "a.png").unwrap();
+        assert_eq!(phashes.near_duplicates(&store, 1, 4).unwrap(), [("b.png".to_string(), 2)]);
+    }
+
+    #[test]
+    fn flags() {
+        let store = MemoryBlobStore::default();
+        flag(&store, "a.png", Some(0xff)).unwrap();
+        flag(&store, "b.png", None).unwrap();
+        assert_eq!(
+            flagged(&store).unwrap(),
+            [("a.png".to_string(), Some(0xff)), ("b.png".to_string(), None)]
+        );
+
+        delete(&store, "a.png").unwrap();
+        assert!(store.stat(".flagged/a.png").is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! Preview pages of blobs, served at `v/<name>`.
+//!
+//! Chat apps only embed a link to a blob if it is an image, and as a bare one. The preview page
+//! describes the blob with OpenGraph and Twitter card tags pointing at its URL, and shows it to
+//! visitors. Blobs behind a password or a download limit get a page without them, crawlers
+//! would otherwise be prompted or use up the downloads.
+//!
+//! Pages also link to the oEmbed description of the blob, for consumers discovering it.
+use actix_web::http::header;
+use actix_web::HttpResponse;
+
+use super::html::escape_html;
+use super::info::BlobInfo;
+
+/// Return a size in bytes as written for humans.
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if size < 1024 {
+        return format!("{} bytes", size);
+    }
+
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Percent-encode `value` to use it in a query string.
+fn encode_query_value(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => result.push(char::from(byte)),
+            byte => result.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    result
+}
+
+/// Push a `<meta>` tag with the attribute `key` named `name`.
+fn push_meta(tags: &mut String, key: &str, name: &str, content: &str) {
+    tags.push_str(&format!(
+        "<meta {}=\"{}\" content=\"{}\">\n",
+        key,
+        name,
+        escape_html(content)
+    ));
+}
+
+/// Return the preview page of `name`, served at `url` with the content type `content_type`.
+///
+/// `info` describes the blob to embed it, `None` when it shouldn't be. The dimensions are only
+/// given when the blob is served as is. The oEmbed route is at `oembed_url`.
+pub fn page(
+    name: &str,
+    url: &str,
+    page_url: &str,
+    oembed_url: &str,
+    content_type: &mime::Mime,
+    info: Option<&BlobInfo>,
+    has_dimensions: bool,
+) -> HttpResponse {
+    let mut tags = String::new();
+    tags.push_str(&format!(
+        "<link rel=\"alternate\" type=\"application/json+oembed\" href=\"{}\" title=\"{}\">\n",
+        escape_html(&format!("{}?url={}", oembed_url, encode_query_value(page_url))),
+        escape_html(name)
+    ));
+    push_meta(&mut tags, "property", "og:title", name);
+    push_meta(&mut tags, "property", "og:url", page_url);
+    push_meta(&mut tags, "name", "twitter:title", name);
+
+    // Without parameters like the charset
+    let essence = format!("{}/{}", content_type.type_(), content_type.subtype());
+    let escaped_name = escape_html(name);
+    let escaped_url = escape_html(url);
+    let (card, og_type, body) = match info {
+        Some(info) => {
+            push_meta(
+                &mut tags,
+                "property",
+                "og:description",
+                &format!("{}, {}", essence, human_size(info.size)),
+            );
+
+            let dimensions = match (info.width, info.height) {
+                (Some(width), Some(height)) if has_dimensions => Some((width, height)),
+                _ => None,
+            };
+
+            match content_type.type_() {
+                mime::IMAGE => {
+                    push_meta(&mut tags, "property", "og:image", url);
+                    if let Some((width, height)) = dimensions {
+                        push_meta(&mut tags, "property", "og:image:width", &width.to_string());
+                        push_meta(&mut tags, "property", "og:image:height", &height.to_string());
+                    }
+                    push_meta(&mut tags, "name", "twitter:image", url);
+                    let body = format!("<img src=\"{}\" alt=\"{}\">", escaped_url, escaped_name);
+                    ("summary_large_image", "website", body)
+                }
+                mime::VIDEO => {
+                    push_meta(&mut tags, "property", "og:video", url);
+                    push_meta(&mut tags, "property", "og:video:type", &essence);
+                    let body = format!("<video src=\"{}\" controls></video>", escaped_url);
+                    ("summary", "video.other", body)
+                }
+                mime::AUDIO => {
+                    push_meta(&mut tags, "property", "og:audio", url);
+                    push_meta(&mut tags, "property", "og:audio:type", &essence);
+                    let body = format!("<audio src=\"{}\" controls></audio>", escaped_url);
+                    ("summary", "music.song", body)
+                }
+                _ => {
+                    let body = format!("<a href=\"{}\">Download {}</a>", escaped_url, escaped_name);
+                    ("summary", "website", body)
+                }
+            }
+        }
+        None => {
+            let body = format!("<a href=\"{}\">Open {}</a>", escaped_url, escaped_name);
+            ("summary", "website", body)
+        }
+    };
+    push_meta(&mut tags, "property", "og:type", og_type);
+    push_meta(&mut tags, "name", "twitter:card", card);
+
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{name}</title>
+{tags}<style>
+body {{ margin: 0; min-height: 100vh; display: flex; align-items: center; justify-content: center; }}
+img, video {{ max-width: 100vw; max-height: 100vh; }}
+</style>
+</head>
+<body>
+{body}
+</body>
+</html>
+"#,
+        name = escaped_name,
+        tags = tags,
+        body = body
+    );
+
+    HttpResponse::Ok()
+        .header(header::CACHE_CONTROL, "no-cache")
+        .content_type("text/html; charset=utf-8")
+        .body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::dev::{Body, ResponseBody};
+
+    use super::super::info::BlobInfo;
+    use super::{human_size, page};
+
+    /// Return the page of `name` with `content_type`, described by `info`.
+    fn render(
+        name: &str,
+        content_type: &str,
+        info: Option<&BlobInfo>,
+        has_dimensions: bool,
+    ) -> String {
+        let url = format!("http://localhost/{}?x=1&y=2", name);
+        let page_url = format!("http://localhost/v/{}?x=1&y=2", name);
+        let content_type = content_type.parse().unwrap();
+        let response = page(
+            name,
+            &url,
+            &page_url,
+            "http://localhost/oembed",
+            &content_type,
+            info,
+            has_dimensions,
+        );
+        assert_eq!(response.headers().get("cache-control").unwrap(), "no-cache");
+        match response.body() {
+            ResponseBody::Body(Body::Bytes(body)) => String::from_utf8(body.to_vec()).unwrap(),
+            _ => panic!("unexpected body"),
+        }
+    }
+
+    fn info() -> BlobInfo {
+        BlobInfo { width: Some(640), height: Some(480), size: 1536, ..BlobInfo::default() }
+    }
+
+    #[test]
+    fn escaping() {
+        let page = render("<b>\"a&b\"'.png", "image/png", Some(&info()), true);
+        assert!(!page.contains("<b>"));
+        assert!(page.contains("<title>&lt;b&gt;&quot;a&amp;b&quot;&#39;.png</title>"));
+        assert!(page.contains(
+            "<meta property=\"og:title\" content=\"&lt;b&gt;&quot;a&amp;b&quot;&#39;.png\">"
+        ));
+        assert!(page.contains(
+            "<meta property=\"og:image\" \
+             content=\"http://localhost/&lt;b&gt;&quot;a&amp;b&quot;&#39;.png?x=1&amp;y=2\">"
+        ));
+        // The page URL is a single parameter of the oEmbed URL
+        assert!(page.contains(
+            "href=\"http://localhost/oembed?url=\
+             http%3A%2F%2Flocalhost%2Fv%2F%3Cb%3E%22a%26b%22%27.png%3Fx%3D1%26y%3D2\""
+        ));
+    }
+
+    #[test]
+    fn image() {
+        let page = render("photo.png", "image/png", Some(&info()), true);
+        assert!(page.contains(
+            "<meta property=\"og:image\" content=\"http://localhost/photo.png?x=1&amp;y=2\">"
+        ));
+        assert!(page.contains("<meta property=\"og:image:width\" content=\"640\">"));
+        assert!(page.contains("<meta property=\"og:image:height\" content=\"480\">"));
+        assert!(page.contains(
+            "<meta name=\"twitter:image\" content=\"http://localhost/photo.png?x=1&amp;y=2\">"
+        ));
+        assert!(page.contains("<meta name=\"twitter:card\" content=\"summary_large_image\">"));
+        assert!(page.contains("<meta property=\"og:type\" content=\"website\">"));
+        assert!(page.contains("<meta property=\"og:description\" content=\"image/png, 1.5 KiB\">"));
+        assert!(
+            page.contains("<img src=\"http://localhost/photo.png?x=1&amp;y=2\" alt=\"photo.png\">")
+        );
+
+        // A variant doesn't have the dimensions of the original
+        let page = render("photo.png", "image/png", Some(&info()), false);
+        assert!(page.contains("og:image\""));
+        assert!(!page.contains("og:image:width"));
+    }
+
+    #[test]
+    fn video_and_audio() {
+        let page = render("clip.mp4", "video/mp4", Some(&info()), true);
+        assert!(page.contains(
+            "<meta property=\"og:video\" content=\"http://localhost/clip.mp4?x=1&amp;y=2\">"
+        ));
+        assert!(page.contains("<meta property=\"og:video:type\" content=\"video/mp4\">"));
+        assert!(page.contains("<meta property=\"og:type\" content=\"video.other\">"));
+        assert!(
+            page.contains("<video src=\"http://localhost/clip.mp4?x=1&amp;y=2\" controls></video>")
+        );
+        assert!(!page.contains("og:image"));
+
+        let page = render("song.mp3", "audio/mpeg; charset=binary", Some(&info()), true);
+        assert!(page.contains(
+            "<meta property=\"og:audio\" content=\"http://localhost/song.mp3?x=1&amp;y=2\">"
+        ));
+        assert!(page.contains("<meta property=\"og:audio:type\" content=\"audio/mpeg\">"));
+        assert!(page.contains("<meta property=\"og:type\" content=\"music.song\">"));
+        assert!(
+            page.contains("<audio src=\"http://localhost/song.mp3?x=1&amp;y=2\" controls></audio>")
+        );
+    }
+
+    #[test]
+    fn link() {
+        let page = render("report.pdf", "application/pdf", Some(&info()), true);
+        assert!(page
+            .contains("<meta property=\"og:description\" content=\"application/pdf, 1.5 KiB\">"));
+        assert!(page.contains("<meta name=\"twitter:card\" content=\"summary\">"));
+        assert!(page.contains(
+            "<a href=\"http://localhost/report.pdf?x=1&amp;y=2\">Download report.pdf</a>"
+        ));
+        assert!(!page.contains("og:image") && !page.contains("<img"));
+    }
+
+    #[test]
+    fn not_embedded() {
+        // Blobs behind a password or a download limit
+        for content_type in &["image/png", "video/mp4", "audio/mpeg"] {
+            let page = render("photo.png", content_type, None, true);
+            for tag in &[
+                "og:image",
+                "og:video",
+                "og:audio",
+                "og:description",
+                "twitter:image",
+                "summary_large_image",
+            ] {
+                assert!(!page.contains(tag), "{} {}", content_type, tag);
+            }
+            assert!(!page.contains("<img") && !page.contains("<video") && !page.contains("<audio"));
+            assert!(page.contains("<meta property=\"og:title\" content=\"photo.png\">"));
+            assert!(page
+                .contains("<a href=\"http://localhost/photo.png?x=1&amp;y=2\">Open photo.png</a>"));
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(human_size(0), "0 bytes");
+        assert_eq!(human_size(1023), "1023 bytes");
+        assert_eq!(human_size(1024), "1.0 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024 + 512 * 1024), "5.5 MiB");
+        assert_eq!(human_size(1 << 50), "1024.0 TiB");
+    }
+}
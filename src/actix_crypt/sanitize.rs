@@ -0,0 +1,554 @@
+//! Removal of the metadata of uploaded images.
+//!
+//! Phone photos carry EXIF metadata like the GPS position where they were taken or the serial
+//! number of the camera. Tenants with `strip_metadata` decrypt every uploaded JPEG, PNG and WebP,
+//! remove its EXIF, XMP, IPTC and text metadata, and store it re-encrypted in a fresh blob.
+//!
+//! Metadata is removed without decoding the image, unless its EXIF orientation isn't the default:
+//! viewers would show it rotated without the metadata, so it is rotated and encoded again with its
+//! color profile. Animated images are never encoded again, they would lose their animation: their
+//! orientation is removed with the metadata.
+use std::io;
+
+use image::{DynamicImage, GenericImageView, ImageFormat};
+
+use super::decode::{self, DecodeLimits};
+use super::variants::{self, Quality};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Quality of the images encoded again, high as they replace the upload.
+const QUALITY: Quality = Quality { jpeg: 92, webp: 90 };
+
+/// An image without its metadata.
+struct Stripped {
+    data: Vec<u8>,
+    /// The removed EXIF metadata, as a TIFF structure.
+    exif: Option<Vec<u8>>,
+    /// The segments or chunk of the kept color profile.
+    icc: Option<Vec<u8>>,
+    is_animated: bool,
+    /// True if anything was removed.
+    is_modified: bool,
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Malformed image")
+}
+
+fn strip_jpeg(data: &[u8]) -> io::Result<Stripped> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return Err(invalid());
+    }
+
+    let mut result = data[..2].to_vec();
+    let mut exif: Option<Vec<u8>> = None;
+    let mut icc: Option<Vec<u8>> = None;
+    let mut is_modified = false;
+    let mut position = 2;
+
+    loop {
+        // Markers can be preceded by fill bytes
+        while data.get(position..position + 2) == Some(&[0xff, 0xff]) {
+            position += 1;
+        }
+
+        let marker = match data.get(position..position + 2) {
+            Some(&[0xff, marker]) => marker,
+            _ => return Err(invalid()),
+        };
+
+        // Metadata comes before the image data, the end of image is the first EOI after it.
+        // What follows it (other images of a MPF file, motion photo videos...) is dropped.
+        if marker == 0xda {
+            let end = data[position..]
+                .windows(2)
+                .position(|window| window == [0xff, 0xd9])
+                .map(|index| position + index + 2)
+                .unwrap_or_else(|| data.len());
+            result.extend_from_slice(&data[position..end]);
+            is_modified |= end != data.len();
+            break;
+        }
+        if marker == 0xd9 {
+            result.extend_from_slice(&data[position..position + 2]);
+            break;
+        }
+
+        // Markers without payload
+        if marker == 0x01 || (0xd0..=0xd7).contains(&marker) {
+            result.extend_from_slice(&data[position..position + 2]);
+            position += 2;
+            continue;
+        }
+
+        let length = match data.get(position + 2..position + 4) {
+            Some(length) => usize::from(u16::from_be_bytes([length[0], length[1]])),
+            None => return Err(invalid()),
+        };
+        if length < 2 {
+            return Err(invalid());
+        }
+        let segment = data.get(position..position + 2 + length).ok_or_else(invalid)?;
+        let payload = &segment[4..];
+
+        let is_kept = match marker {
+            // JFIF
+            0xe0 => payload.starts_with(b"JFIF\0"),
+            // EXIF or XMP
+            0xe1 => {
+                if let Some(tiff) = payload.strip_prefix(b"Exif\0\0") {
+                    exif.get_or_insert_with(|| tiff.to_vec());
+                }
+                false
+            }
+            // The color profile, split in several segments if large, also used by MPF
+            0xe2 if payload.starts_with(b"ICC_PROFILE\0") => {
+                icc.get_or_insert_with(Vec::new).extend_from_slice(segment);
+                true
+            }
+            0xe2 => true,
+            // Adobe color transform
+            0xee => true,
+            // IPTC and vendor metadata, comments
+            0xe3..=0xef | 0xfe => false,
+            _ => true,
+        };
+
+        if is_kept {
+            result.extend_from_slice(segment);
+        } else {
+            is_modified = true;
+        }
+        position += segment.len();
+    }
+
+    Ok(Stripped {
+        data: result,
+        exif,
+        icc,
+        is_animated: false,
+        is_modified,
+    })
+}
+
+fn strip_png(data: &[u8]) -> io::Result<Stripped> {
+    if !data.starts_with(PNG_SIGNATURE) {
+        return Err(invalid());
+    }
+
+    let mut result = PNG_SIGNATURE.to_vec();
+    let mut exif = None;
+    let mut icc = None;
+    let mut is_animated = false;
+    let mut is_modified = false;
+    let mut position = PNG_SIGNATURE.len();
+
+    loop {
+        let header = data.get(position..position + 8).ok_or_else(invalid)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let chunk_type = &header[4..8];
+        // Length, type, data and CRC
+        let chunk = data.get(position..position + 12 + length).ok_or_else(invalid)?;
+
+        match chunk_type {
+            b"eXIf" => {
+                exif = Some(chunk[8..8 + length].to_vec());
+                is_modified = true;
+            }
+            b"tEXt" | b"zTXt" | b"iTXt" | b"tIME" => is_modified = true,
+            chunk_type => {
+                match chunk_type {
+                    b"iCCP" => icc = Some(chunk.to_vec()),
+                    // APNG
+                    b"acTL" => is_animated = true,
+                    _ => {}
+                }
+                result.extend_from_slice(chunk);
+            }
+        }
+        position += chunk.len();
+
+        if chunk_type == b"IEND" {
+            is_modified |= position != data.len();
+            break;
+        }
+    }
+
+    Ok(Stripped {
+        data: result,
+        exif,
+        icc,
+        is_animated,
+        is_modified,
+    })
+}
+
+fn strip_webp(data: &[u8]) -> io::Result<Stripped> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err(invalid());
+    }
+
+    let mut result = data[..12].to_vec();
+    let mut exif = None;
+    let mut icc = None;
+    let mut is_animated = false;
+    let mut is_modified = false;
+    let mut position = 12;
+
+    while position < data.len() {
+        let header = data.get(position..position + 8).ok_or_else(invalid)?;
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        // Chunks are padded to an even size
+        let padded_length = length + (length & 1);
+        let chunk = data.get(position..position + 8 + padded_length).ok_or_else(invalid)?;
+
+        match &header[..4] {
+            b"EXIF" => {
+                let payload = &chunk[8..8 + length];
+                exif = Some(payload.strip_prefix(b"Exif\0\0").unwrap_or(payload).to_vec());
+                is_modified = true;
+            }
+            b"XMP " => is_modified = true,
+            b"VP8X" if length >= 1 => {
+                // Clear the EXIF and XMP flags
+                let flags = result.len() + 8;
+                result.extend_from_slice(chunk);
+                result[flags] &= !0x0c;
+            }
+            chunk_type => {
+                match chunk_type {
+                    b"ICCP" => icc = Some(chunk.to_vec()),
+                    b"ANIM" => is_animated = true,
+                    _ => {}
+                }
+                result.extend_from_slice(chunk);
+            }
+        }
+        position += chunk.len();
+    }
+
+    let riff_size = (result.len() - 8) as u32;
+    result[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    Ok(Stripped {
+        data: result,
+        exif,
+        icc,
+        is_animated,
+        is_modified,
+    })
+}
+
+/// Return the orientation of the EXIF metadata `exif`, a TIFF structure.
+fn orientation(exif: &[u8]) -> Option<u16> {
+    let is_little_endian = match exif.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let read_u16 = |offset: usize| -> Option<u16> {
+        let bytes = [*exif.get(offset)?, *exif.get(offset + 1)?];
+        Some(if is_little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let read_u32 = |offset: usize| -> Option<u32> {
+        let bytes = [
+            *exif.get(offset)?,
+            *exif.get(offset + 1)?,
+            *exif.get(offset + 2)?,
+            *exif.get(offset + 3)?,
+        ];
+        Some(if is_little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+
+    // The orientation is in the first image file directory
+    let directory = read_u32(4)? as usize;
+    let entries = usize::from(read_u16(directory)?);
+    (0..entries)
+        .map(|index| directory + 2 + index * 12)
+        .find(|entry| read_u16(*entry) == Some(0x0112))
+        .and_then(|entry| read_u16(entry + 8))
+}
+
+/// Apply the EXIF `orientation` to `image`.
+fn orient(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Return `encoded`, `image` encoded in `format`, with the color profile `icc` of the original.
+fn with_profile(encoded: Vec<u8>, format: ImageFormat, icc: &[u8], image: &DynamicImage) -> Vec<u8> {
+    let splice = |position: usize, inserted: &[u8]| {
+        let mut result = Vec::with_capacity(encoded.len() + inserted.len());
+        result.extend_from_slice(&encoded[..position]);
+        result.extend_from_slice(inserted);
+        result.extend_from_slice(&encoded[position..]);
+        result
+    };
+
+    match format {
+        ImageFormat::Jpeg => {
+            // The JFIF segment comes first
+            let position = match encoded.get(2..6) {
+                Some(&[0xff, 0xe0, high, low]) => 4 + usize::from(u16::from_be_bytes([high, low])),
+                _ => 2,
+            };
+            splice(position.min(encoded.len()), icc)
+        }
+        // After the IHDR chunk
+        ImageFormat::Png => splice((PNG_SIGNATURE.len() + 25).min(encoded.len()), icc),
+        ImageFormat::WebP => {
+            let mut result = if encoded.get(12..16) == Some(b"VP8X") {
+                let mut result = splice(30.min(encoded.len()), icc);
+                result[20] |= 0x20;
+                result
+            } else {
+                // The simple format has no room for it, use the extended one
+                let (width, height) = image.dimensions();
+                let mut vp8x = b"VP8X".to_vec();
+                vp8x.extend_from_slice(&10u32.to_le_bytes());
+                vp8x.push(if image.color().has_alpha() { 0x30 } else { 0x20 });
+                vp8x.extend_from_slice(&[0, 0, 0]);
+                vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+                vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+                vp8x.extend_from_slice(icc);
+                splice(12, &vp8x)
+            };
+            let riff_size = (result.len() - 8) as u32;
+            result[4..8].copy_from_slice(&riff_size.to_le_bytes());
+            result
+        }
+        _ => encoded,
+    }
+}
+
+/// Return the image `plaintext` in `format` without its metadata, `None` if it has none.
+///
+/// Errors of kind `InvalidData` mean the image is malformed, or over the decoding limits if
+/// `decode::is_too_large` is true.
+pub fn strip_metadata(
+    plaintext: &[u8],
+    format: ImageFormat,
+    limits: &DecodeLimits,
+) -> io::Result<Option<Vec<u8>>> {
+    let stripped = match format {
+        ImageFormat::Jpeg => strip_jpeg(plaintext)?,
+        ImageFormat::Png => strip_png(plaintext)?,
+        ImageFormat::WebP => strip_webp(plaintext)?,
+        _ => return Ok(None),
+    };
+
+    match stripped.exif.as_deref().and_then(orientation) {
+        Some(orientation @ 2..=8) if !stripped.is_animated => {
+            let image = orient(decode::decode(&stripped.data, format, limits)?, orientation);
+            let encoded = variants::encode(&image, format, QUALITY, false)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            Ok(Some(match stripped.icc {
+                Some(ref icc) => with_profile(encoded, format, icc, &image),
+                None => encoded,
+            }))
+        }
+        _ if stripped.is_modified => Ok(Some(stripped.data)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use image::{DynamicImage, GenericImageView, ImageFormat, RgbImage};
+
+    use super::super::decode::DecodeLimits;
+    use super::super::variants;
+    use super::{strip_metadata, PNG_SIGNATURE, QUALITY};
+
+    const ICC: &[u8] = b"ICC_PROFILE\0\x01\x01profile";
+
+    /// Strip the metadata of `data` within the default decoding limits.
+    fn strip(data: &[u8], format: ImageFormat) -> io::Result<Option<Vec<u8>>> {
+        strip_metadata(data, format, &DecodeLimits::default())
+    }
+
+    /// EXIF metadata holding `orientation`, big-endian.
+    fn exif(orientation: u16) -> Vec<u8> {
+        let mut exif = b"MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1]);
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0; 6]);
+        exif
+    }
+
+    /// A 2 by 1 image in `format`.
+    fn image(format: ImageFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_raw(2, 1, vec![255, 0, 0, 0, 0, 255]).unwrap());
+        match format {
+            ImageFormat::WebP => variants::encode(&image, format, QUALITY, false).unwrap(),
+            format => {
+                let mut result = Vec::new();
+                image.write_to(&mut Cursor::new(&mut result), format).unwrap();
+                result
+            }
+        }
+    }
+
+    fn contains(data: &[u8], part: &[u8]) -> bool {
+        data.windows(part.len()).any(|window| window == part)
+    }
+
+    fn is_invalid<T>(result: io::Result<T>) -> bool {
+        matches!(result, Err(ref e) if e.kind() == io::ErrorKind::InvalidData)
+    }
+
+    fn jpeg(orientation: u16) -> Vec<u8> {
+        let segment = |marker: u8, payload: &[u8]| {
+            let mut segment = vec![0xff, marker];
+            segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+            segment.extend_from_slice(payload);
+            segment
+        };
+
+        let original = image(ImageFormat::Jpeg);
+        let mut result = original[..2].to_vec();
+        result.extend(segment(0xe1, &[&b"Exif\0\0"[..], &exif(orientation)].concat()));
+        result.extend(segment(0xe2, ICC));
+        result.extend(segment(0xfe, b"comment"));
+        result.extend_from_slice(&original[2..]);
+        result
+    }
+
+    fn png_chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(chunk_type);
+        chunk.extend_from_slice(data);
+        // The CRC isn't checked
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    fn png(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let original = image(ImageFormat::Png);
+        let header_end = PNG_SIGNATURE.len() + 25;
+        let mut result = original[..header_end].to_vec();
+        for chunk in chunks {
+            result.extend_from_slice(chunk);
+        }
+        result.extend_from_slice(&original[header_end..]);
+        result
+    }
+
+    fn webp(orientation: u16) -> Vec<u8> {
+        let chunk = |chunk_type: &[u8], data: &[u8]| {
+            let mut chunk = chunk_type.to_vec();
+            chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            chunk.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                chunk.push(0);
+            }
+            chunk
+        };
+
+        let original = image(ImageFormat::WebP);
+        let mut result = b"RIFF\0\0\0\0WEBP".to_vec();
+        // ICC, EXIF and XMP flags, a 2 by 1 canvas
+        result.extend(chunk(b"VP8X", &[0x2c, 0, 0, 0, 1, 0, 0, 0, 0, 0]));
+        result.extend(chunk(b"ICCP", ICC));
+        result.extend_from_slice(&original[12..]);
+        result.extend(chunk(b"EXIF", &exif(orientation)));
+        result.extend(chunk(b"XMP ", b"<x:xmpmeta/>"));
+        let riff_size = (result.len() - 8) as u32;
+        result[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        result
+    }
+
+    #[test]
+    fn jpeg_metadata() {
+        let stripped = strip(&jpeg(1), ImageFormat::Jpeg).unwrap().unwrap();
+        assert!(!contains(&stripped, b"Exif"));
+        assert!(!contains(&stripped, b"comment"));
+        assert!(contains(&stripped, ICC));
+        assert_eq!(image::load_from_memory(&stripped).unwrap().dimensions(), (2, 1));
+
+        // Without metadata
+        assert_eq!(strip(&image(ImageFormat::Jpeg), ImageFormat::Jpeg).unwrap(), None);
+
+        let data = jpeg(1);
+        assert!(is_invalid(strip(&data[..10], ImageFormat::Jpeg)));
+        assert!(is_invalid(strip(b"not a jpeg", ImageFormat::Jpeg)));
+    }
+
+    #[test]
+    fn jpeg_orientation() {
+        // Rotated, with the color profile
+        let stripped = strip(&jpeg(6), ImageFormat::Jpeg).unwrap().unwrap();
+        assert!(!contains(&stripped, b"Exif"));
+        assert!(contains(&stripped, ICC));
+        assert_eq!(image::load_from_memory(&stripped).unwrap().dimensions(), (1, 2));
+    }
+
+    #[test]
+    fn png_metadata() {
+        let exif_chunk = png_chunk(b"eXIf", &exif(1));
+        let text_chunk = png_chunk(b"tEXt", b"Comment\0text");
+        let stripped = strip(&png(&[exif_chunk, text_chunk]), ImageFormat::Png).unwrap().unwrap();
+        assert_eq!(stripped, image(ImageFormat::Png));
+
+        assert_eq!(strip(&image(ImageFormat::Png), ImageFormat::Png).unwrap(), None);
+
+        let data = png(&[png_chunk(b"eXIf", &exif(1))]);
+        assert!(is_invalid(strip(&data[..data.len() - 4], ImageFormat::Png)));
+        assert!(is_invalid(strip(&data[..40], ImageFormat::Png)));
+    }
+
+    #[test]
+    fn animated() {
+        // Not encoded again, the orientation is dropped with the metadata
+        let animation = png_chunk(b"acTL", &[0, 0, 0, 1, 0, 0, 0, 0]);
+        let data = png(&[animation.clone(), png_chunk(b"eXIf", &exif(6))]);
+        let stripped = strip(&data, ImageFormat::Png).unwrap().unwrap();
+        assert_eq!(stripped, png(&[animation]));
+    }
+
+    #[test]
+    fn webp_metadata() {
+        let stripped = strip(&webp(1), ImageFormat::WebP).unwrap().unwrap();
+        assert!(!contains(&stripped, b"EXIF"));
+        assert!(!contains(&stripped, b"XMP "));
+        assert!(contains(&stripped, ICC));
+        // The flags are cleared
+        assert_eq!(stripped[20], 0x20);
+        assert_eq!(stripped[4..8], ((stripped.len() - 8) as u32).to_le_bytes());
+        assert_eq!(image::load_from_memory(&stripped).unwrap().dimensions(), (2, 1));
+
+        let data = webp(1);
+        assert!(is_invalid(strip(&data[..data.len() - 3], ImageFormat::WebP)));
+        assert!(is_invalid(strip(b"RIFF\0\0\0\0WEBM", ImageFormat::WebP)));
+    }
+
+    #[test]
+    fn webp_orientation() {
+        // Encoded again in the extended format, to keep the color profile
+        let stripped = strip(&webp(6), ImageFormat::WebP).unwrap().unwrap();
+        assert!(!contains(&stripped, b"EXIF"));
+        assert_eq!(&stripped[12..16], b"VP8X");
+        assert!(contains(&stripped, ICC));
+        assert_eq!(stripped[4..8], ((stripped.len() - 8) as u32).to_le_bytes());
+        assert_eq!(image::load_from_memory(&stripped).unwrap().dimensions(), (1, 2));
+    }
+}
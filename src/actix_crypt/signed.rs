@@ -0,0 +1,138 @@
+//! Signed, expiring URLs of private blobs.
+//!
+//! A private blob is only served with `?expires=<Unix time>&signature=<hex>`, the signature is
+//! an HMAC-SHA256 of the expiry and the blob name with the URL signing key of the tenant.
+//!
+//! Cookies granting access to a single blob use the same format with their own keys.
+use actix_web::HttpMessage;
+
+use hmac::{Hmac, Mac};
+
+use sha2::Sha256;
+
+use super::error::CryptFilesError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(key: &[u8], name: &str, expires: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(key).expect("HMAC can take key of any size");
+    mac.input(format!("{}\n{}", expires, name).as_bytes());
+    mac
+}
+
+/// Return the query string granting access to `name` until `expires`.
+pub fn signed_query(key: &[u8], name: &str, expires: u64) -> String {
+    let signature = hex::encode(mac(key, name, expires).result().code());
+    format!("expires={}&signature={}", expires, signature)
+}
+
+/// Check that `query` grants access to `name` at `now`.
+pub fn verify(key: &[u8], name: &str, query: &str, now: u64) -> Result<(), CryptFilesError> {
+    let mut expires = None;
+    let mut signature = None;
+    for parameter in query.split('&') {
+        let mut parts = parameter.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("expires"), Some(value)) => expires = value.parse::<u64>().ok(),
+            (Some("signature"), Some(value)) => signature = hex::decode(value).ok(),
+            _ => {}
+        }
+    }
+
+    let (expires, signature) = match (expires, signature) {
+        (Some(expires), Some(signature)) => (expires, signature),
+        _ => return Err(CryptFilesError::InvalidSignature),
+    };
+
+    // Compared in constant time
+    mac(key, name, expires)
+        .verify(&signature)
+        .map_err(|_| CryptFilesError::InvalidSignature)?;
+
+    if expires < now {
+        return Err(CryptFilesError::ExpiredSignature);
+    }
+
+    Ok(())
+}
+
+/// Return a `Set-Cookie` value named `cookie` holding `value` at `path` for `lifetime` seconds.
+///
+/// `value` is a `signed_query` expiring with the cookie.
+pub fn cookie_header(cookie: &str, value: &str, path: &str, lifetime: u64, secure: bool) -> String {
+    // A path is percent-encoded but can still contain attribute separators
+    let path = path.replace(';', "%3B").replace(',', "%2C");
+
+    format!(
+        "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        cookie,
+        value,
+        path,
+        lifetime,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+/// Return true if one of the cookies named `cookie` of `req` grants access to `name`.
+///
+/// Cookies of parent paths are sent too, they are signed for another name.
+pub fn has_cookie<R: HttpMessage>(req: &R, cookie: &str, key: &[u8], name: &str, now: u64) -> bool {
+    match req.cookies() {
+        Ok(cookies) => cookies
+            .iter()
+            .filter(|candidate| candidate.name() == cookie)
+            .any(|candidate| verify(key, name, candidate.value(), now).is_ok()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::error::CryptFilesError;
+    use super::{signed_query, verify};
+
+    const KEY: &[u8] = b"url signing key";
+
+    #[test]
+    fn valid() {
+        let query = signed_query(KEY, "photo.png", 1000);
+        assert_eq!(verify(KEY, "photo.png", &query, 999), Ok(()));
+        assert_eq!(verify(KEY, "photo.png", &query, 1000), Ok(()));
+
+        // Other parameters are ignored, like those of variants
+        let query = format!("w=64&{}&format=webp", query);
+        assert_eq!(verify(KEY, "photo.png", &query, 999), Ok(()));
+    }
+
+    #[test]
+    fn expired() {
+        let query = signed_query(KEY, "photo.png", 1000);
+        assert_eq!(verify(KEY, "photo.png", &query, 1001), Err(CryptFilesError::ExpiredSignature));
+    }
+
+    #[test]
+    fn tampered() {
+        let query = signed_query(KEY, "photo.png", 1000);
+        let invalid = Err(CryptFilesError::InvalidSignature);
+        assert_eq!(verify(KEY, "other.png", &query, 999), invalid);
+        assert_eq!(verify(b"other key", "photo.png", &query, 999), invalid);
+
+        let extended = query.replace("expires=1000", "expires=2000");
+        assert_eq!(verify(KEY, "photo.png", &extended, 1500), invalid);
+    }
+
+    #[test]
+    fn malformed() {
+        let query = signed_query(KEY, "photo.png", 1000);
+        let signature = query.split("signature=").nth(1).unwrap();
+        let invalid = Err(CryptFilesError::InvalidSignature);
+
+        assert_eq!(verify(KEY, "photo.png", "", 999), invalid);
+        assert_eq!(verify(KEY, "photo.png", "expires=1000", 999), invalid);
+        assert_eq!(verify(KEY, "photo.png", &format!("signature={}", signature), 999), invalid);
+        assert_eq!(verify(KEY, "photo.png", "expires=1000&signature=not-hex", 999), invalid);
+        assert_eq!(verify(KEY, "photo.png", "expires=1000&signature=", 999), invalid);
+        let truncated = format!("expires=1000&signature={}", &signature[..32]);
+        assert_eq!(verify(KEY, "photo.png", &truncated, 999), invalid);
+    }
+}
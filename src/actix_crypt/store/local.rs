@@ -0,0 +1,374 @@
+//! Store blobs on the local filesystem.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use rand::RngCore;
+
+use super::{is_hidden, is_listed, validate_name, BlobObject, BlobStat, BlobStore};
+
+/// How blobs are laid out on disk, names exposed by the store are identical for both.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BucketLayout {
+    /// Every blob directly in its directory.
+    Flat,
+    /// Blobs spread in `depth` levels of directories named after `width` characters of their name,
+    /// `abcdef.png` becomes `ab/cd/abcdef.png` with a depth and a width of 2.
+    Sharded { depth: usize, width: usize },
+}
+
+/// How symbolic links inside the bucket are handled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Never access a blob through a symbolic link.
+    Deny,
+    /// Follow symbolic links as long as they resolve inside the bucket.
+    WithinRoot,
+    /// Follow symbolic links anywhere, the bucket is trusted.
+    Follow,
+}
+
+pub struct LocalBlobStore {
+    root: PathBuf,
+    layout: BucketLayout,
+    symlinks: SymlinkPolicy,
+}
+
+fn is_not_found(result: &io::Result<impl Sized>) -> bool {
+    match result {
+        Err(e) => e.kind() == io::ErrorKind::NotFound,
+        Ok(_) => false,
+    }
+}
+
+impl LocalBlobStore {
+    pub fn new<T: Into<PathBuf>>(
+        root: T,
+        layout: BucketLayout,
+        symlinks: SymlinkPolicy,
+    ) -> io::Result<Self> {
+        let root = root.into().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Specified path is not a directory",
+            ));
+        }
+
+        Ok(LocalBlobStore {
+            root,
+            layout,
+            symlinks,
+        })
+    }
+
+    /// Make sure `path` resolves inside the root as required by the symlink policy.
+    ///
+    /// Missing components are ignored, the closest existing ancestor must be inside the root.
+    fn confine(&self, path: &Path) -> io::Result<()> {
+        if self.symlinks == SymlinkPolicy::Follow {
+            return Ok(());
+        }
+
+        let ancestors = path.ancestors().take_while(|ancestor| *ancestor != self.root);
+
+        if self.symlinks == SymlinkPolicy::Deny {
+            for ancestor in ancestors.clone() {
+                match fs::symlink_metadata(ancestor) {
+                    Ok(metadata) => {
+                        if metadata.file_type().is_symlink() {
+                            return Err(io::Error::new(
+                                io::ErrorKind::PermissionDenied,
+                                "Symbolic links are denied",
+                            ));
+                        }
+                    }
+                    Err(e) => {
+                        if e.kind() != io::ErrorKind::NotFound {
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+        }
+
+        for ancestor in ancestors {
+            match ancestor.canonicalize() {
+                Ok(ref real_path) if real_path.starts_with(&self.root) => return Ok(()),
+                Ok(_) => {
+                    log::warn!("Local: {} escapes the bucket root", path.display());
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "Path escapes the bucket root",
+                    ));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Nothing exists between the root and the path
+        Ok(())
+    }
+
+    fn flat_path(&self, name: &str) -> io::Result<PathBuf> {
+        validate_name(name)?;
+        Ok(self.root.join(name))
+    }
+
+    /// Return the sharded location of `name`, `None` if it isn't sharded.
+    fn sharded_path(&self, name: &str) -> io::Result<Option<PathBuf>> {
+        let flat_path = self.flat_path(name)?;
+
+        let (depth, width) = match self.layout {
+            BucketLayout::Sharded { depth, width } => (depth, width),
+            BucketLayout::Flat => return Ok(None),
+        };
+
+        let file_name = flat_path.file_name().unwrap().to_string_lossy().into_owned();
+        let shards = file_name.get(..depth * width).filter(|_| depth * width > 0);
+        let shards = match shards {
+            Some(shards) if shards.chars().all(|c| c.is_ascii_alphanumeric()) => shards,
+            _ => return Ok(None),
+        };
+
+        let mut path = flat_path.parent().unwrap().to_path_buf();
+        for level in 0..depth {
+            path.push(&shards[level * width..(level + 1) * width]);
+        }
+        path.push(file_name);
+
+        Ok(Some(path))
+    }
+
+    /// Return where `name` is written, its sharded location if the layout is sharded.
+    fn write_path(&self, name: &str) -> io::Result<PathBuf> {
+        match self.sharded_path(name)? {
+            Some(path) => Ok(path),
+            None => self.flat_path(name),
+        }
+    }
+
+    /// Run `operation` on the location of `name`, both layouts are looked up while migrating.
+    fn with_path<T, F: Fn(&Path) -> io::Result<T>>(&self, name: &str, operation: F) -> io::Result<T> {
+        let operation = |path: &Path| {
+            self.confine(path)?;
+            operation(path)
+        };
+
+        let flat_path = self.flat_path(name)?;
+        let sharded_path = match self.sharded_path(name)? {
+            Some(path) => path,
+            None => return operation(&flat_path),
+        };
+
+        let result = operation(&sharded_path);
+        if !is_not_found(&result) {
+            return result;
+        }
+
+        // Not migrated yet, but the migration might be moving it right now
+        let result = operation(&flat_path);
+        if is_not_found(&result) {
+            return operation(&sharded_path);
+        }
+        result
+    }
+
+    /// Return the name stored at `path`, shard directories are stripped.
+    fn name_of(&self, path: &Path) -> io::Result<String> {
+        let mut segments = path
+            .strip_prefix(&self.root)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Path outside of root"))?
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        if let BucketLayout::Sharded { depth, width } = self.layout {
+            let file_index = segments.len() - 1;
+            if file_index >= depth {
+                let shards = segments[file_index - depth..file_index].concat();
+                let is_sharded = segments[file_index - depth..file_index]
+                    .iter()
+                    .all(|segment| segment.len() == width)
+                    && segments[file_index].starts_with(&shards);
+
+                if is_sharded {
+                    segments.drain(file_index - depth..file_index);
+                }
+            }
+        }
+
+        Ok(segments.join("/"))
+    }
+
+    /// Collect the name and path of every blob under `directory`, or only the listed ones if a `prefix` is given.
+    fn walk(
+        &self,
+        directory: &Path,
+        prefix: Option<&str>,
+        result: &mut Vec<(String, PathBuf)>,
+    ) -> io::Result<()> {
+        // Only the directory part of the prefix can be matched against directories,
+        // shard directories are never part of a name
+        let prefix_directory = prefix.map(|prefix| match prefix.rfind('/') {
+            Some(index) => &prefix[..=index],
+            None => "",
+        });
+
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let path = entry.path();
+
+            if self.confine(&path).is_err() {
+                continue;
+            }
+
+            if entry.file_type()?.is_dir() {
+                let directory_name = format!("{}/", self.name_of(&path)?);
+
+                // Only walk directories that can contain a listed name
+                let is_reachable = match (prefix, prefix_directory) {
+                    (Some(prefix), Some(prefix_directory)) => {
+                        (directory_name.starts_with(prefix_directory)
+                            || prefix_directory.starts_with(&directory_name))
+                            && (is_hidden(prefix) || !is_hidden(&directory_name))
+                    }
+                    _ => true,
+                };
+
+                if is_reachable {
+                    self.walk(&path, prefix, result)?;
+                }
+            } else if !file_name.starts_with('.') {
+                // Hidden files are uploads or repairs in progress
+                let name = self.name_of(&path)?;
+                let is_included = match prefix {
+                    Some(prefix) => is_listed(&name, prefix),
+                    None => true,
+                };
+
+                if is_included {
+                    result.push((name, path));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move every blob not stored at the location of the current layout, return the amount of moved blobs.
+    ///
+    /// Blobs stay reachable during the migration, it can run while the server is serving.
+    pub fn migrate_layout(&self) -> io::Result<u64> {
+        let mut blobs = Vec::new();
+        self.walk(&self.root, None, &mut blobs)?;
+
+        let mut moved = 0;
+        for (name, path) in blobs {
+            let destination = self.write_path(&name)?;
+            if destination == path {
+                continue;
+            }
+
+            self.confine(destination.parent().unwrap())?;
+            fs::create_dir_all(destination.parent().unwrap())?;
+            fs::rename(&path, &destination)?;
+            moved += 1;
+        }
+
+        Ok(moved)
+    }
+}
+
+impl BlobStore for LocalBlobStore {
+    fn put(&self, name: &str, source: &mut dyn Read) -> io::Result<()> {
+        let path = self.write_path(name)?;
+        let parent = path.parent().unwrap_or(&self.root);
+        self.confine(parent)?;
+        fs::create_dir_all(parent)?;
+
+        // Write under a hidden name first so readers never see a partial blob
+        let partial_path = parent.join(format!(
+            ".{}.part",
+            path.file_name().unwrap().to_string_lossy()
+        ));
+
+        let result = File::create(&partial_path)
+            .and_then(|mut file| io::copy(source, &mut file))
+            .and_then(|_| fs::rename(&partial_path, &path));
+
+        if result.is_err() {
+            fs::remove_file(&partial_path).ok();
+            return result.map(|_| ());
+        }
+
+        // Don't let an outdated copy in the other layout come back after a delete
+        let flat_path = self.flat_path(name)?;
+        if flat_path != path {
+            fs::remove_file(flat_path).ok();
+        }
+
+        Ok(())
+    }
+
+    fn create(&self, name: &str, source: &mut dyn Read) -> io::Result<()> {
+        let path = self.write_path(name)?;
+        let parent = path.parent().unwrap_or(&self.root);
+        self.confine(parent)?;
+        fs::create_dir_all(parent)?;
+
+        // Not migrated yet
+        let flat_path = self.flat_path(name)?;
+        if flat_path != path && flat_path.exists() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+
+        // Linked into place once complete, concurrent creations each get their own partial file
+        let mut random = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut random);
+        let partial_path = parent.join(format!(
+            ".{}.{}.part",
+            path.file_name().unwrap().to_string_lossy(),
+            hex::encode(random)
+        ));
+
+        let result = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&partial_path)
+            .and_then(|mut file| io::copy(source, &mut file))
+            .and_then(|_| fs::hard_link(&partial_path, &path));
+        fs::remove_file(&partial_path).ok();
+        result
+    }
+
+    fn get(&self, name: &str) -> io::Result<Box<dyn BlobObject>> {
+        let file = self.with_path(name, |path| {
+            let mut option = OpenOptions::new();
+            option.write(true).read(true);
+            option.open(path)
+        })?;
+
+        Ok(Box::new(file))
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        self.with_path(name, |path| fs::remove_file(path))
+    }
+
+    fn stat(&self, name: &str) -> io::Result<BlobStat> {
+        let metadata = self.with_path(name, |path| fs::metadata(path))?;
+        Ok(BlobStat {
+            len: metadata.len(),
+            is_dir: metadata.is_dir(),
+        })
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut result = Vec::new();
+        self.walk(&self.root, Some(prefix), &mut result)?;
+        Ok(result.into_iter().map(|(name, _)| name).collect())
+    }
+}
@@ -0,0 +1,115 @@
+//! Locks shared by every process using a store, like the server and the command line.
+//!
+//! A lock is a blob `.locks/<name>` holding a random token, only created if it doesn't exist.
+//! A holder that died leaves its lock behind: a waiter seeing the same token for `STALE_AFTER`
+//! takes the lock over, the locked sections only last a few store operations.
+use std::io::{self, Cursor, Read};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::RngCore;
+
+use super::BlobStore;
+
+/// How long a lock can be held before being taken over.
+const STALE_AFTER: Duration = Duration::from_secs(60);
+
+/// Delay between two attempts to take a held lock.
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// A held lock, released when dropped.
+pub struct StoreLock<'a> {
+    store: &'a dyn BlobStore,
+    name: String,
+}
+
+fn read_token(store: &dyn BlobStore, name: &str) -> io::Result<String> {
+    let mut token = String::new();
+    store.get(name)?.read_to_string(&mut token)?;
+    Ok(token)
+}
+
+impl<'a> StoreLock<'a> {
+    /// Take the lock `name` of `store`, waiting for its holder to release it.
+    pub fn acquire(store: &'a dyn BlobStore, name: &str) -> io::Result<Self> {
+        let name = format!(".locks/{}", name);
+
+        let mut random = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut random);
+        let token = hex::encode(random);
+
+        // The token of the holder, and since when it holds the lock
+        let mut holder: Option<(String, Instant)> = None;
+
+        loop {
+            match store.create(&name, &mut Cursor::new(&token)) {
+                Ok(()) => return Ok(StoreLock { store, name }),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+
+            let holder_token = match read_token(store, &name) {
+                Ok(holder_token) => holder_token,
+                // Released in the meantime
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            match holder {
+                Some((ref current, since)) if *current == holder_token => {
+                    if since.elapsed() >= STALE_AFTER {
+                        log::warn!("Store: taking over the stale lock {}", name);
+                        store.delete(&name).ok();
+                        holder = None;
+                        continue;
+                    }
+                }
+                _ => holder = Some((holder_token, Instant::now())),
+            }
+
+            thread::sleep(RETRY_DELAY);
+        }
+    }
+}
+
+impl<'a> Drop for StoreLock<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.store.delete(&self.name) {
+            log::error!("Store: cannot release the lock {}: {}", self.name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::super::{BlobStore, MemoryBlobStore};
+    use super::StoreLock;
+
+    #[test]
+    fn exclusive() {
+        let store = Arc::new(MemoryBlobStore::default());
+        let released = Arc::new(AtomicBool::new(false));
+
+        let lock = StoreLock::acquire(&*store, "test").unwrap();
+        let waiter = {
+            let store = store.clone();
+            let released = released.clone();
+            thread::spawn(move || {
+                let _lock = StoreLock::acquire(&*store, "test").unwrap();
+                assert!(released.load(Ordering::SeqCst));
+            })
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        released.store(true, Ordering::SeqCst);
+        drop(lock);
+        waiter.join().unwrap();
+
+        assert!(store.list(".locks/").unwrap().is_empty());
+    }
+}
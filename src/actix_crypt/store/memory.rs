@@ -0,0 +1,82 @@
+//! Keep blobs in memory, for tests and throwaway instances.
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read};
+use std::sync::{Arc, RwLock};
+
+use super::{is_listed, validate_name, BlobObject, BlobStat, BlobStore};
+
+#[derive(Default)]
+pub struct MemoryBlobStore {
+    blobs: RwLock<BTreeMap<String, Arc<Vec<u8>>>>,
+}
+
+fn not_found() -> io::Error {
+    io::Error::from(io::ErrorKind::NotFound)
+}
+
+impl BlobStore for MemoryBlobStore {
+    fn put(&self, name: &str, source: &mut dyn Read) -> io::Result<()> {
+        validate_name(name)?;
+
+        let mut data = Vec::new();
+        source.read_to_end(&mut data)?;
+
+        self.blobs
+            .write()
+            .unwrap()
+            .insert(name.to_string(), Arc::new(data));
+        Ok(())
+    }
+
+    fn create(&self, name: &str, source: &mut dyn Read) -> io::Result<()> {
+        validate_name(name)?;
+
+        let mut data = Vec::new();
+        source.read_to_end(&mut data)?;
+
+        match self.blobs.write().unwrap().entry(name.to_string()) {
+            Entry::Vacant(entry) => {
+                entry.insert(Arc::new(data));
+                Ok(())
+            }
+            Entry::Occupied(_) => Err(io::Error::from(io::ErrorKind::AlreadyExists)),
+        }
+    }
+
+    /// Return a copy of the blob, writes on it are not persisted.
+    fn get(&self, name: &str) -> io::Result<Box<dyn BlobObject>> {
+        let blobs = self.blobs.read().unwrap();
+        let data = blobs.get(name).ok_or_else(not_found)?;
+        Ok(Box::new(Cursor::new(data.as_ref().clone())))
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        self.blobs
+            .write()
+            .unwrap()
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(not_found)
+    }
+
+    fn stat(&self, name: &str) -> io::Result<BlobStat> {
+        let blobs = self.blobs.read().unwrap();
+        let data = blobs.get(name).ok_or_else(not_found)?;
+        Ok(BlobStat {
+            len: data.len() as u64,
+            is_dir: false,
+        })
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .blobs
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|name| is_listed(name, prefix))
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,277 @@
+//! Storage backends holding the encrypted blobs.
+use std::io::{self, Read, Seek, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+mod local;
+mod lock;
+mod memory;
+mod s3;
+
+pub use local::{BucketLayout, LocalBlobStore, SymlinkPolicy};
+pub use lock::StoreLock;
+pub use memory::MemoryBlobStore;
+pub use s3::S3BlobStore;
+
+/// A stored blob opened for access.
+///
+/// Only the local store persists writes, they are used to upgrade legacy headers in place.
+pub trait BlobObject: Read + Write + Seek + Send + Sync {}
+
+impl<T: Read + Write + Seek + Send + Sync> BlobObject for T {}
+
+#[derive(Debug, Clone)]
+pub struct BlobStat {
+    pub len: u64,
+    pub is_dir: bool,
+}
+
+pub trait BlobStore: Send + Sync {
+    /// Store the content of `source` under `name`, replacing any existing blob.
+    fn put(&self, name: &str, source: &mut dyn Read) -> io::Result<()>;
+
+    /// Store the content of `source` under `name` unless a blob has this name, the store then
+    /// fails with `AlreadyExists`.
+    ///
+    /// Only one of concurrent creations succeeds, even from other processes.
+    fn create(&self, name: &str, source: &mut dyn Read) -> io::Result<()>;
+
+    fn get(&self, name: &str) -> io::Result<Box<dyn BlobObject>>;
+
+    fn delete(&self, name: &str) -> io::Result<()>;
+
+    fn stat(&self, name: &str) -> io::Result<BlobStat>;
+
+    /// List every blob name starting with `prefix`.
+    ///
+    /// Hidden names (parity, metadata...) are only listed when `prefix` is itself hidden.
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+}
+
+pub type SharedBlobStore = Arc<dyn BlobStore>;
+
+/// Return true if a segment of `name` starts with a dot, those are never served.
+pub fn is_hidden(name: &str) -> bool {
+    name.split('/').any(|segment| segment.starts_with('.'))
+}
+
+fn validate_name(name: &str) -> io::Result<()> {
+    let is_valid = !name.is_empty()
+        && name
+            .split('/')
+            .all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+
+    if is_valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid blob name"))
+    }
+}
+
+fn is_listed(name: &str, prefix: &str) -> bool {
+    name.starts_with(prefix) && (is_hidden(prefix) || !is_hidden(name))
+}
+
+/// Settings of the storage backend.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageConfig {
+    Local {
+        path: PathBuf,
+        layout: BucketLayout,
+        symlinks: SymlinkPolicy,
+    },
+    Memory,
+    S3 {
+        endpoint: String,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+    },
+}
+
+/// Open the local store described by `config`.
+pub fn open_local(config: &StorageConfig) -> io::Result<LocalBlobStore> {
+    match config {
+        StorageConfig::Local {
+            path,
+            layout,
+            symlinks,
+        } => LocalBlobStore::new(path.clone(), *layout, *symlinks).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("bucket {} is not reachable: {}", path.display(), e),
+            )
+        }),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The storage backend is not local",
+        )),
+    }
+}
+
+/// Open the store described by `config` and make sure it can be reached.
+pub fn open(config: &StorageConfig) -> io::Result<SharedBlobStore> {
+    let store: SharedBlobStore = match config {
+        StorageConfig::Local { .. } => Arc::new(open_local(config)?),
+        StorageConfig::Memory => Arc::new(MemoryBlobStore::default()),
+        StorageConfig::S3 {
+            endpoint,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+        } => Arc::new(S3BlobStore::new(
+            endpoint, bucket, region, access_key, secret_key,
+        )),
+    };
+
+    // Fail at startup rather than on the first request
+    match store.stat(".reachable") {
+        Ok(_) => Ok(store),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(store),
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!("bucket is not reachable: {}", e),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{self, Cursor, Read};
+
+    use super::{
+        BlobStore, BucketLayout, LocalBlobStore, MemoryBlobStore, S3BlobStore, SymlinkPolicy,
+    };
+
+    fn read(store: &dyn BlobStore, name: &str) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        store.get(name)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    fn list(store: &dyn BlobStore, prefix: &str) -> Vec<String> {
+        let mut names = store.list(prefix).unwrap();
+        names.sort();
+        names
+    }
+
+    /// Check that `store` gives back what was put in it, and forgets what was deleted.
+    fn round_trip(store: &dyn BlobStore) {
+        store.put("abcdef.png", &mut Cursor::new("first")).unwrap();
+        store.put("album/cat.png", &mut Cursor::new("cat")).unwrap();
+        store.put(".meta/abcdef.png", &mut Cursor::new("size=5")).unwrap();
+        assert_eq!(read(store, "abcdef.png").unwrap(), b"first");
+
+        // Replaced as a whole
+        store.put("abcdef.png", &mut Cursor::new("replaced")).unwrap();
+        assert_eq!(read(store, "abcdef.png").unwrap(), b"replaced");
+        let stat = store.stat("abcdef.png").unwrap();
+        assert_eq!(stat.len, 8);
+        assert!(!stat.is_dir);
+
+        // Only created once
+        let result = store.create("abcdef.png", &mut Cursor::new("created"));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        store.create("created.png", &mut Cursor::new("created")).unwrap();
+        assert_eq!(read(store, "created.png").unwrap(), b"created");
+        store.delete("created.png").unwrap();
+
+        assert_eq!(list(store, ""), ["abcdef.png", "album/cat.png"]);
+        assert_eq!(list(store, "album/"), ["album/cat.png"]);
+        assert_eq!(list(store, ".meta/"), [".meta/abcdef.png"]);
+
+        store.delete("abcdef.png").unwrap();
+        for result in &[
+            store.stat("abcdef.png").map(|_| ()),
+            read(store, "abcdef.png").map(|_| ()),
+            store.delete("abcdef.png"),
+        ] {
+            assert_eq!(result.as_ref().unwrap_err().kind(), io::ErrorKind::NotFound);
+        }
+        assert_eq!(list(store, ""), ["album/cat.png"]);
+
+        for name in &["", "../escape.png", "album//cat.png", "album/./cat.png"] {
+            let result = store.put(name, &mut Cursor::new("invalid"));
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        round_trip(&MemoryBlobStore::default());
+    }
+
+    #[test]
+    fn local_round_trip() {
+        for &(name, layout) in &[
+            ("flat", BucketLayout::Flat),
+            ("sharded", BucketLayout::Sharded { depth: 2, width: 2 }),
+        ] {
+            let directory =
+                std::env::temp_dir().join(format!("imagers-store-{}-{}", name, std::process::id()));
+            fs::remove_dir_all(&directory).ok();
+            fs::create_dir_all(&directory).unwrap();
+
+            round_trip(&LocalBlobStore::new(&directory, layout, SymlinkPolicy::Deny).unwrap());
+
+            fs::remove_dir_all(&directory).ok();
+        }
+    }
+
+    /// A source failing after its first bytes.
+    struct FailingSource(usize);
+
+    impl Read for FailingSource {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::Error::other("source failed"));
+            }
+            let len = self.0.min(buf.len());
+            buf[..len].fill(b'x');
+            self.0 -= len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn local_partial_writes() {
+        let directory =
+            std::env::temp_dir().join(format!("imagers-store-partial-{}", std::process::id()));
+        fs::remove_dir_all(&directory).ok();
+        fs::create_dir_all(&directory).unwrap();
+        let store =
+            LocalBlobStore::new(&directory, BucketLayout::Flat, SymlinkPolicy::Deny).unwrap();
+
+        assert!(store.put("put.png", &mut FailingSource(10)).is_err());
+        assert!(store.create("created.png", &mut FailingSource(10)).is_err());
+
+        // Neither the blobs nor their partial files are left behind
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 0);
+        store.create("created.png", &mut Cursor::new("created")).unwrap();
+        assert_eq!(read(&store, "created.png").unwrap(), b"created");
+
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    /// Run against an empty bucket, like one of a local MinIO:
+    /// `S3_ENDPOINT=http://127.0.0.1:9000 S3_BUCKET=imagers-test cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn s3_round_trip() {
+        let setting =
+            |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.to_string());
+        let store = S3BlobStore::new(
+            &setting("S3_ENDPOINT", "http://127.0.0.1:9000"),
+            &setting("S3_BUCKET", "imagers-test"),
+            &setting("S3_REGION", "us-east-1"),
+            &setting("S3_ACCESS_KEY", "minioadmin"),
+            &setting("S3_SECRET_KEY", "minioadmin"),
+        );
+        round_trip(&store);
+        store.delete("album/cat.png").unwrap();
+        store.delete(".meta/abcdef.png").unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+//! Upload API tokens.
+//!
+//! Only the SHA-256 of a token is stored, as `.tokens/<hash>` holding the identity of its user.
+//! Deleting that entry revokes the token right away.
+use std::io::{self, Cursor, Read};
+
+use actix_web::http::header;
+use actix_web::HttpMessage;
+
+use rand::RngCore;
+
+use sha2::{Digest, Sha256};
+
+use super::store::BlobStore;
+
+const TOKENS_PREFIX: &str = ".tokens/";
+
+fn token_name(hash: &str) -> String {
+    format!("{}{}", TOKENS_PREFIX, hash)
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Return the token of an `Authorization: Bearer <token>` header.
+pub fn bearer_token<R: HttpMessage>(req: &R) -> Option<String> {
+    let authorization = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let mut parts = authorization.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            Some(token.trim().to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Return the user of `token`, `None` if the token is unknown or revoked.
+pub fn authenticate(store: &dyn BlobStore, token: &str) -> io::Result<Option<String>> {
+    let mut user = String::new();
+    match store.get(&token_name(&hash_token(token))) {
+        Ok(mut file) => file.read_to_string(&mut user)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    Ok(Some(user.trim().to_string()))
+}
+
+/// Create a token for `user`, the token itself is only known by the caller.
+pub fn create(store: &dyn BlobStore, user: &str) -> io::Result<String> {
+    let is_valid_user = !user.is_empty()
+        && user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.@".contains(c));
+    if !is_valid_user {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "User names can only contain letters, digits, '-', '_', '.' and '@'",
+        ));
+    }
+
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+    let token = hex::encode(token);
+
+    store.put(&token_name(&hash_token(&token)), &mut Cursor::new(user))?;
+    Ok(token)
+}
+
+/// Revoke a token given either as itself or as its hash.
+pub fn revoke(store: &dyn BlobStore, token_or_hash: &str) -> io::Result<()> {
+    match store.delete(&token_name(token_or_hash)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            store.delete(&token_name(&hash_token(token_or_hash)))
+        }
+        result => result,
+    }
+}
+
+/// List the hash and the user of every token.
+pub fn list(store: &dyn BlobStore) -> io::Result<Vec<(String, String)>> {
+    let mut result = Vec::new();
+    for name in store.list(TOKENS_PREFIX)? {
+        let mut user = String::new();
+        store.get(&name)?.read_to_string(&mut user)?;
+        result.push((name[TOKENS_PREFIX.len()..].to_string(), user.trim().to_string()));
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use actix_web::test;
+
+    use super::super::store::{BlobStore, MemoryBlobStore};
+    use super::*;
+
+    #[test]
+    fn hash() {
+        // SHA-256 of "abc"
+        assert_eq!(
+            hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(hash_token("abc").len(), 64);
+        assert_ne!(hash_token("abc"), hash_token("abd"));
+    }
+
+    #[test]
+    fn create_and_authenticate() {
+        let store = MemoryBlobStore::default();
+        let token = create(&store, "alice@example.com").unwrap();
+        assert_eq!(token.len(), 64);
+        assert_ne!(create(&store, "alice@example.com").unwrap(), token);
+
+        assert_eq!(authenticate(&store, &token).unwrap(), Some("alice@example.com".to_string()));
+        assert_eq!(authenticate(&store, "unknown").unwrap(), None);
+
+        // Only the hash of the token is stored, never the token itself
+        let names = store.list(TOKENS_PREFIX).unwrap();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&format!("{}{}", TOKENS_PREFIX, hash_token(&token))));
+        for name in &names {
+            assert!(!name.contains(&token));
+            let mut content = String::new();
+            store.get(name).unwrap().read_to_string(&mut content).unwrap();
+            assert!(!content.contains(&token));
+        }
+        let users: Vec<_> = list(&store).unwrap().into_iter().map(|(_, user)| user).collect();
+        assert_eq!(users, ["alice@example.com", "alice@example.com"]);
+
+        for user in &["", "alice bob", "../alice", "alice/bob"] {
+            assert_eq!(create(&store, user).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn revoke_token() {
+        let store = MemoryBlobStore::default();
+
+        let token = create(&store, "alice").unwrap();
+        revoke(&store, &token).unwrap();
+        assert_eq!(authenticate(&store, &token).unwrap(), None);
+
+        // As listed by the administration
+        let token = create(&store, "bob").unwrap();
+        revoke(&store, &hash_token(&token)).unwrap();
+        assert_eq!(authenticate(&store, &token).unwrap(), None);
+        assert!(list(&store).unwrap().is_empty());
+
+        assert_eq!(revoke(&store, &token).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn bearer() {
+        let req = test::TestRequest::with_header("Authorization", "Bearer  abc ").to_http_request();
+        assert_eq!(bearer_token(&req), Some("abc".to_string()));
+        let req = test::TestRequest::with_header("Authorization", "bearer abc").to_http_request();
+        assert_eq!(bearer_token(&req), Some("abc".to_string()));
+        let req = test::TestRequest::with_header("Authorization", "Basic abc").to_http_request();
+        assert_eq!(bearer_token(&req), None);
+        assert_eq!(bearer_token(&test::TestRequest::default().to_http_request()), None);
+    }
+}
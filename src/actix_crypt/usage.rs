@@ -0,0 +1,141 @@
+//! Storage used by each uploader, kept as `.usage/<user>`.
+//!
+//! The counters of a user are updated under a store lock, `imagers delete` releases the blobs it
+//! deletes while the server charges uploads.
+use std::io::{self, Cursor, Read};
+
+use super::store::{BlobStore, StoreLock};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Usage {
+    /// Size of the stored blobs.
+    pub bytes: u64,
+    /// Amount of stored blobs.
+    pub files: u64,
+}
+
+fn usage_name(user: &str) -> String {
+    format!(".usage/{}", user)
+}
+
+fn lock<'a>(store: &'a dyn BlobStore, user: &str) -> io::Result<StoreLock<'a>> {
+    StoreLock::acquire(store, &format!("usage-{}", user))
+}
+
+pub fn read(store: &dyn BlobStore, user: &str) -> io::Result<Usage> {
+    let mut content = String::new();
+    match store.get(&usage_name(user)) {
+        Ok(mut file) => file.read_to_string(&mut content)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Usage::default()),
+        Err(e) => return Err(e),
+    };
+
+    let mut usage = Usage::default();
+    for line in content.lines() {
+        if let Some(bytes) = line.strip_prefix("bytes=") {
+            usage.bytes = bytes.parse().unwrap_or(0);
+        } else if let Some(files) = line.strip_prefix("files=") {
+            usage.files = files.parse().unwrap_or(0);
+        }
+    }
+
+    Ok(usage)
+}
+
+fn write(store: &dyn BlobStore, user: &str, usage: &Usage) -> io::Result<()> {
+    let content = format!("bytes={}\nfiles={}\n", usage.bytes, usage.files);
+    store.put(&usage_name(user), &mut Cursor::new(content))
+}
+
+/// Charge a blob of `bytes` to `user` if `accept` allows it given the current usage.
+///
+/// Return true if the blob was charged.
+pub fn charge<F: Fn(&Usage) -> bool>(
+    store: &dyn BlobStore,
+    user: &str,
+    bytes: u64,
+    accept: F,
+) -> io::Result<bool> {
+    let _lock = lock(store, user)?;
+
+    let mut usage = read(store, user)?;
+    if !accept(&usage) {
+        return Ok(false);
+    }
+
+    usage.bytes += bytes;
+    usage.files += 1;
+    write(store, user, &usage)?;
+    Ok(true)
+}
+
+/// Give back a blob of `bytes` to `user` once it is deleted.
+pub fn release(store: &dyn BlobStore, user: &str, bytes: u64) -> io::Result<()> {
+    let _lock = lock(store, user)?;
+
+    let mut usage = read(store, user)?;
+    usage.bytes = usage.bytes.saturating_sub(bytes);
+    usage.files = usage.files.saturating_sub(1);
+    write(store, user, &usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::super::store::{BlobStore, MemoryBlobStore};
+    use super::{charge, read, release, Usage};
+
+    #[test]
+    fn quota() {
+        let store = MemoryBlobStore::default();
+        let within_quota = |bytes| move |usage: &Usage| usage.bytes + bytes <= 100;
+
+        assert!(charge(&store, "alice", 60, within_quota(60)).unwrap());
+        assert!(!charge(&store, "alice", 60, within_quota(60)).unwrap());
+        assert!(charge(&store, "alice", 40, within_quota(40)).unwrap());
+
+        let usage = read(&store, "alice").unwrap();
+        assert_eq!((usage.bytes, usage.files), (100, 2));
+        assert_eq!(read(&store, "bob").unwrap().files, 0);
+    }
+
+    #[test]
+    fn released() {
+        let store = MemoryBlobStore::default();
+        charge(&store, "alice", 60, |_| true).unwrap();
+        charge(&store, "alice", 40, |_| true).unwrap();
+
+        release(&store, "alice", 60).unwrap();
+        let usage = read(&store, "alice").unwrap();
+        assert_eq!((usage.bytes, usage.files), (40, 1));
+
+        // Blobs charged before the usage existed are released too
+        release(&store, "alice", 60).unwrap();
+        release(&store, "alice", 60).unwrap();
+        let usage = read(&store, "alice").unwrap();
+        assert_eq!((usage.bytes, usage.files), (0, 0));
+    }
+
+    #[test]
+    fn locked() {
+        let store = Arc::new(MemoryBlobStore::default());
+        charge(&*store, "alice", 10, |_| true).unwrap();
+
+        // Another process, like `imagers delete`, updates the usage
+        store.create(".locks/usage-alice", &mut Cursor::new("other")).unwrap();
+        let charging = {
+            let store = store.clone();
+            thread::spawn(move || charge(&*store, "alice", 10, |usage| usage.files == 0).unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+        store.put(".usage/alice", &mut Cursor::new("bytes=0\nfiles=0\n")).unwrap();
+        store.delete(".locks/usage-alice").unwrap();
+
+        assert!(charging.join().unwrap());
+        assert_eq!(read(&*store, "alice").unwrap().files, 1);
+    }
+}
@@ -0,0 +1,565 @@
+//! Resized and converted variants of images.
+//!
+//! `?w=` and `?h=` serve an image resized to fit in a box, `?fit=` tells how: `contain` keeps
+//! the whole image (the default), `cover` fills the box and crops the overflow, `fill` stretches
+//! the image to the box. Images are never upscaled. Only the sizes configured for the tenant can
+//! be requested, each one is a variant to render and cache.
+//!
+//! `?format=` converts an image to `png`, `jpeg`, `gif`, `webp` or `avif` (with the `avif`
+//! feature). Without it, tenants negotiating the format convert the images to AVIF or WebP for the
+//! clients accepting them, except GIFs which would lose their animation. PNG images are converted
+//! to lossless WebP.
+//!
+//! A variant is rendered once and cached under `.variants/<stored blob>/`, encrypted with the key
+//! the blob was encrypted with and named after its identifier, so that a blob uploaded before a
+//! key rotation never gets a variant made with another key. The variants of a blob are deleted
+//! with it, past `MAX_CACHED_VARIANTS` the least recently used ones are evicted. Uses are only
+//! tracked in memory, the variants unused since the start go first. Watermarked variants are cached as
+//! `wm-<version>-` followed by the name of the variant, see `watermark`.
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::{WebPEncoder, WebPQuality};
+use image::imageops::FilterType;
+use image::{ColorType, DynamicImage, GenericImageView, ImageEncoder, ImageFormat, ImageResult};
+
+use lazy_static::lazy_static;
+
+use super::crypt::{BlobKey, EncryptedBlob};
+use super::decode::{self, DecodeLimits};
+use super::error::CryptFilesError;
+use super::store::BlobStore;
+use super::watermark::Watermark;
+
+/// Largest width or height that can be requested.
+pub const MAX_DIMENSION: u32 = 4096;
+
+/// Variants cached per blob, each distinct set of parameters is one.
+const MAX_CACHED_VARIANTS: usize = 32;
+
+/// Variants whose last use is tracked, the least recently used half is forgotten past it.
+const MAX_TRACKED_VARIANTS: usize = 100_000;
+
+/// Quality of the variants encoded in a lossy format.
+const QUALITY: Quality = Quality { jpeg: 85, webp: 80 };
+#[cfg(feature = "avif")]
+const AVIF_QUALITY: u8 = 70;
+/// rav1e speed, from 1 (slowest) to 10, the default is too slow for large photos.
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 8;
+
+lazy_static! {
+    /// Last use of the cached variants of every blob.
+    static ref VARIANT_USES: Mutex<VariantUses> = Mutex::new(VariantUses::default());
+}
+
+/// Quality of the images encoded in a lossy format, from 1 to 100.
+#[derive(Debug, Clone, Copy)]
+pub struct Quality {
+    pub jpeg: u8,
+    pub webp: u8,
+}
+
+/// Uses of the cached variants, ordered by a counter increasing with every use.
+#[derive(Default)]
+struct VariantUses {
+    clock: u64,
+    uses: HashMap<String, u64>,
+}
+
+impl VariantUses {
+    fn touch(&mut self, variant_name: &str) {
+        self.clock += 1;
+        if self.uses.len() >= MAX_TRACKED_VARIANTS && !self.uses.contains_key(variant_name) {
+            let oldest = self.clock.saturating_sub(MAX_TRACKED_VARIANTS as u64 / 2);
+            self.uses.retain(|_, used| *used >= oldest);
+        }
+        self.uses.insert(variant_name.to_string(), self.clock);
+    }
+
+    /// Return when `variant_name` was last used, 0 if not since the start.
+    fn last_use(&self, variant_name: &str) -> u64 {
+        self.uses.get(variant_name).copied().unwrap_or(0)
+    }
+}
+
+/// How an image is resized to its box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fit {
+    Contain,
+    Cover,
+    Fill,
+}
+
+impl FromStr for Fit {
+    type Err = CryptFilesError;
+
+    fn from_str(fit: &str) -> Result<Self, Self::Err> {
+        match fit {
+            "contain" => Ok(Fit::Contain),
+            "cover" => Ok(Fit::Cover),
+            "fill" => Ok(Fit::Fill),
+            _ => Err(CryptFilesError::InvalidVariant),
+        }
+    }
+}
+
+impl Fit {
+    fn as_str(self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+/// The resize parameters of a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Resize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+}
+
+fn parse_dimension(value: &str, sizes: &[u32]) -> Result<u32, CryptFilesError> {
+    match value.parse::<u32>() {
+        Ok(dimension) if sizes.contains(&dimension) => Ok(dimension),
+        _ => Err(CryptFilesError::InvalidVariant),
+    }
+}
+
+/// Return the largest of `sizes` up to `dimension`, `None` if they are all larger.
+pub fn snap(sizes: &[u32], dimension: u32) -> Option<u32> {
+    sizes.iter().copied().filter(|&size| size <= dimension).max()
+}
+
+impl Resize {
+    /// Parse the resize parameters of `query`, `None` if it has none.
+    ///
+    /// The width and height must be one of `sizes`.
+    pub fn from_query(query: &str, sizes: &[u32]) -> Result<Option<Self>, CryptFilesError> {
+        let mut width = None;
+        let mut height = None;
+        let mut fit = None;
+        for parameter in query.split('&') {
+            let mut parts = parameter.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("w"), Some(value)) => width = Some(parse_dimension(value, sizes)?),
+                (Some("h"), Some(value)) => height = Some(parse_dimension(value, sizes)?),
+                (Some("fit"), Some(value)) => fit = Some(value.parse()?),
+                _ => {}
+            }
+        }
+
+        if width.is_none() && height.is_none() {
+            return match fit {
+                Some(_) => Err(CryptFilesError::InvalidVariant),
+                None => Ok(None),
+            };
+        }
+
+        // Cropping and stretching need both dimensions
+        let fit = fit.unwrap_or(Fit::Contain);
+        if fit != Fit::Contain && (width.is_none() || height.is_none()) {
+            return Err(CryptFilesError::InvalidVariant);
+        }
+
+        Ok(Some(Resize { width, height, fit }))
+    }
+
+    fn suffix(&self) -> String {
+        let dimension = |dimension: Option<u32>| match dimension {
+            Some(dimension) => dimension.to_string(),
+            None => "auto".to_string(),
+        };
+        format!("{}x{}-{}", dimension(self.width), dimension(self.height), self.fit.as_str())
+    }
+
+    /// Resize `image`, `None` if it already fits.
+    fn apply(&self, image: &DynamicImage) -> Option<DynamicImage> {
+        let (image_width, image_height) = image.dimensions();
+        let width = self.width.unwrap_or(image_width);
+        let height = self.height.unwrap_or(image_height);
+
+        match self.fit {
+            Fit::Contain if width >= image_width && height >= image_height => None,
+            Fit::Contain => Some(image.resize(width, height, FilterType::Lanczos3)),
+            Fit::Cover => {
+                // Shrink the box until it fits in the image, keeping its ratio
+                let scale = f64::min(
+                    1.0,
+                    f64::min(
+                        f64::from(image_width) / f64::from(width),
+                        f64::from(image_height) / f64::from(height),
+                    ),
+                );
+                let width = ((f64::from(width) * scale).round() as u32).max(1);
+                let height = ((f64::from(height) * scale).round() as u32).max(1);
+                if width == image_width && height == image_height {
+                    return None;
+                }
+                Some(image.resize_to_fill(width, height, FilterType::Lanczos3))
+            }
+            Fit::Fill => {
+                let width = width.min(image_width);
+                let height = height.min(image_height);
+                if width == image_width && height == image_height {
+                    return None;
+                }
+                Some(image.resize_exact(width, height, FilterType::Lanczos3))
+            }
+        }
+    }
+}
+
+/// Return true if `name` is an image whose variants can be rendered.
+pub fn is_image(name: &str) -> bool {
+    decode::image_format(name).is_some()
+}
+
+fn parse_format(format: &str) -> Result<ImageFormat, CryptFilesError> {
+    match format {
+        "png" => Ok(ImageFormat::Png),
+        "jpeg" | "jpg" => Ok(ImageFormat::Jpeg),
+        "gif" => Ok(ImageFormat::Gif),
+        "webp" => Ok(ImageFormat::WebP),
+        #[cfg(feature = "avif")]
+        "avif" => Ok(ImageFormat::Avif),
+        _ => Err(CryptFilesError::InvalidVariant),
+    }
+}
+
+/// Return true if the `Accept` header `accept` lists `media_type` without rejecting it.
+fn accepts(accept: &str, media_type: &str) -> bool {
+    accept.split(',').any(|item| {
+        let mut parameters = item.split(';').map(str::trim);
+        parameters.next() == Some(media_type)
+            && parameters.all(|parameter| {
+                let quality = parameter.strip_prefix("q=").and_then(|quality| quality.parse::<f32>().ok());
+                quality != Some(0.0)
+            })
+    })
+}
+
+/// Return the smaller format accepted by a client, if any.
+fn negotiate(accept: &str) -> Option<ImageFormat> {
+    #[cfg(feature = "avif")]
+    {
+        if accepts(accept, "image/avif") {
+            return Some(ImageFormat::Avif);
+        }
+    }
+
+    if accepts(accept, "image/webp") {
+        return Some(ImageFormat::WebP);
+    }
+
+    None
+}
+
+/// A variant of an image requested by a client.
+#[derive(Clone)]
+pub struct Variant {
+    source: ImageFormat,
+    format: ImageFormat,
+    resize: Option<Resize>,
+    watermark: Option<Arc<Watermark>>,
+}
+
+impl Variant {
+    /// Parse the variant of `name` requested by `query` and the `Accept` header `accept`, resized
+    /// to one of `sizes`. `accept` is `None` unless the format is negotiated.
+    ///
+    /// Return `None` if the original is served.
+    pub fn from_request(
+        name: &str,
+        query: &str,
+        accept: Option<&str>,
+        sizes: &[u32],
+    ) -> Result<Option<Self>, CryptFilesError> {
+        let resize = Resize::from_query(query, sizes)?;
+
+        let mut format = None;
+        for parameter in query.split('&') {
+            let mut parts = parameter.splitn(2, '=');
+            if let (Some("format"), Some(value)) = (parts.next(), parts.next()) {
+                format = Some(parse_format(value)?);
+            }
+        }
+
+        let source = match decode::image_format(name) {
+            Some(source) => source,
+            None if resize.is_some() || format.is_some() => return Err(CryptFilesError::NotAnImage),
+            None => return Ok(None),
+        };
+
+        let format = match (format, accept) {
+            (Some(format), _) => format,
+            (None, Some(accept)) if source != ImageFormat::Gif => negotiate(accept).unwrap_or(source),
+            (None, _) => source,
+        };
+
+        if resize.is_none() && format == source {
+            return Ok(None);
+        }
+
+        Ok(Some(Variant {
+            source,
+            format,
+            resize,
+            watermark: None,
+        }))
+    }
+
+    /// Return the variant of `name` served as is, `None` if it isn't an image.
+    ///
+    /// It is only rendered with a watermark, the original is served otherwise.
+    pub fn original(name: &str) -> Option<Self> {
+        decode::image_format(name).map(|source| Variant {
+            source,
+            format: source,
+            resize: None,
+            watermark: None,
+        })
+    }
+
+    /// Return this variant with `watermark` drawn on it.
+    pub fn watermarked(self, watermark: Arc<Watermark>) -> Self {
+        Variant {
+            watermark: Some(watermark),
+            ..self
+        }
+    }
+
+    /// Return the name `name` is served as, its extension is the one of the format.
+    pub fn served_name(&self, name: &str) -> String {
+        if self.format == self.source {
+            return name.to_string();
+        }
+
+        let extension = self.format.extensions_str()[0];
+        Path::new(name).with_extension(extension).to_string_lossy().into_owned()
+    }
+
+    fn suffix(&self) -> String {
+        let resize = match self.resize {
+            Some(ref resize) => resize.suffix(),
+            None => "original".to_string(),
+        };
+        format!("{}.{}", resize, self.format.extensions_str()[0])
+    }
+}
+
+fn variants_prefix(storage_name: &str) -> String {
+    format!(".variants/{}/", storage_name)
+}
+
+/// Return the plaintext of the blob `storage_name`, unencrypted blobs are read as is.
+fn read_plaintext(store: &dyn BlobStore, key: BlobKey, storage_name: &str) -> io::Result<Vec<u8>> {
+    let mut encrypted_blob = EncryptedBlob::from(store.get(storage_name)?, key)?;
+    if !encrypted_blob.is_header_magic_valid() {
+        let mut file = encrypted_blob.into_inner();
+        let mut result = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut result)?;
+        return Ok(result);
+    }
+
+    encrypted_blob
+        .decrypted_data()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Cannot decrypt the blob"))
+}
+
+/// Return the pixels of `image` as 8-bit RGB, or RGBA if it has an alpha channel.
+fn rgb_pixels(image: &DynamicImage) -> (Vec<u8>, ColorType) {
+    if image.color().has_alpha() {
+        (image.to_rgba8().into_raw(), ColorType::Rgba8)
+    } else {
+        (image.to_rgb8().into_raw(), ColorType::Rgb8)
+    }
+}
+
+/// Encode `image` in `format` with `quality`, or as lossless WebP if `lossless` is true.
+pub fn encode(
+    image: &DynamicImage,
+    format: ImageFormat,
+    quality: Quality,
+    lossless: bool,
+) -> ImageResult<Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let mut result = Vec::new();
+
+    match format {
+        ImageFormat::Jpeg => {
+            let pixels = image.to_rgb8();
+            JpegEncoder::new_with_quality(&mut result, quality.jpeg).write_image(
+                &pixels,
+                width,
+                height,
+                ColorType::Rgb8,
+            )?;
+        }
+        ImageFormat::WebP => {
+            // The lossless encoder would make photos larger than their JPEG
+            #[allow(deprecated)]
+            let quality = if lossless {
+                WebPQuality::lossless()
+            } else {
+                WebPQuality::lossy(quality.webp)
+            };
+            let (pixels, color) = rgb_pixels(image);
+            #[allow(deprecated)]
+            WebPEncoder::new_with_quality(&mut result, quality).write_image(&pixels, width, height, color)?;
+        }
+        #[cfg(feature = "avif")]
+        ImageFormat::Avif => {
+            let (pixels, color) = rgb_pixels(image);
+            image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut result, AVIF_SPEED, AVIF_QUALITY)
+                .write_image(&pixels, width, height, color)?;
+        }
+        format => image.write_to(&mut Cursor::new(&mut result), format)?,
+    }
+
+    Ok(result)
+}
+
+/// Return the name of the blob to serve for the `variant` of the blob `storage_name`.
+///
+/// The variant is rendered and cached if needed, an image already fitting is served as is unless
+/// it is watermarked. Variants are encrypted with the key of `keys` the blob was encrypted with,
+/// and cached under its identifier. Errors of kind `InvalidData` mean the blob isn't an image
+/// that can be decoded, or one over the decoding limits if `decode::is_too_large` is true.
+pub fn render(
+    store: &dyn BlobStore,
+    keys: &[BlobKey],
+    storage_name: &str,
+    variant: &Variant,
+    limits: &DecodeLimits,
+) -> io::Result<String> {
+    let key = super::blob_key(store, keys, storage_name)?;
+    let watermark = variant.watermark.as_deref();
+    let prefix = variants_prefix(storage_name);
+    let watermark_prefix = format!("{}wm-", prefix);
+    let variant_name = match watermark {
+        Some(watermark) => {
+            format!("{}{}-{}-{}", watermark_prefix, watermark.version, key.id(), variant.suffix())
+        }
+        None => format!("{}{}-{}", prefix, key.id(), variant.suffix()),
+    };
+    if store.stat(&variant_name).is_ok() {
+        VARIANT_USES.lock().unwrap().touch(&variant_name);
+        return Ok(variant_name);
+    }
+
+    let data = read_plaintext(store, key, storage_name)?;
+    let image = decode::decode(&data, variant.source, limits)?;
+
+    let image = match variant.resize.and_then(|resize| resize.apply(&image)) {
+        Some(resized) => resized,
+        None if variant.format == variant.source && watermark.is_none() => return Ok(storage_name.to_string()),
+        None => image,
+    };
+    let image = match watermark {
+        Some(watermark) => watermark.apply(image),
+        None => image,
+    };
+
+    // PNG images stay lossless
+    let lossless = variant.source == ImageFormat::Png;
+    let encoded =
+        encode(&image, variant.format, QUALITY, lossless).map_err(|e| io::Error::other(e.to_string()))?;
+
+    // Variants of a previous watermark are never served again
+    if let Some(watermark) = watermark {
+        let current_prefix = format!("{}{}-", watermark_prefix, watermark.version);
+        for cached_name in store.list(&prefix)? {
+            if cached_name.starts_with(&watermark_prefix) && !cached_name.starts_with(&current_prefix) {
+                store.delete(&cached_name)?;
+            }
+        }
+    }
+
+    // Every parameter set is a variant, don't let them pile up
+    let mut cached_names = store.list(&prefix)?;
+    if cached_names.len() >= MAX_CACHED_VARIANTS {
+        let evicted = cached_names.len() + 1 - MAX_CACHED_VARIANTS;
+        {
+            let variant_uses = VARIANT_USES.lock().unwrap();
+            cached_names.sort_by_key(|cached_name| variant_uses.last_use(cached_name));
+        }
+        for cached_name in &cached_names[..evicted] {
+            store.delete(cached_name)?;
+        }
+    }
+
+    store.put(&variant_name, &mut Cursor::new(key.encrypt(&encoded)))?;
+    VARIANT_USES.lock().unwrap().touch(&variant_name);
+    Ok(variant_name)
+}
+
+/// Delete the cached variants of the blob `storage_name`.
+pub fn delete_variants(store: &dyn BlobStore, storage_name: &str) -> io::Result<()> {
+    for variant_name in store.list(&variants_prefix(storage_name))? {
+        store.delete(&variant_name)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat, RgbImage};
+
+    use super::super::crypt::BlobKey;
+    use super::super::decode::DecodeLimits;
+    use super::super::store::{BlobStore, MemoryBlobStore};
+    use super::{render, Resize, Variant, MAX_CACHED_VARIANTS};
+
+    #[test]
+    fn sizes() {
+        let sizes = [64, 128];
+        assert!(Resize::from_query("w=64&h=128", &sizes).unwrap().is_some());
+        assert!(Resize::from_query("w=65", &sizes).is_err());
+        assert!(Resize::from_query("h=0", &sizes).is_err());
+        assert!(Resize::from_query("w=64", &[]).is_err());
+    }
+
+    #[test]
+    fn least_recently_used() {
+        let store = MemoryBlobStore::default();
+        let keys = [BlobKey::new(&"00".repeat(32), "IMAGERS0").unwrap()];
+        let limits = DecodeLimits::default();
+
+        // Unencrypted blobs are read as is
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(64, 64))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        store.put("lru.png", &mut Cursor::new(png)).unwrap();
+
+        let sizes: Vec<u32> = (1..=64).collect();
+        let variant = |width: u32| {
+            let query = format!("w={}", width);
+            Variant::from_request("lru.png", &query, None, &sizes).unwrap().unwrap()
+        };
+
+        let first = render(&store, &keys, "lru.png", &variant(1), &limits).unwrap();
+        let second = render(&store, &keys, "lru.png", &variant(2), &limits).unwrap();
+        for width in 3..=MAX_CACHED_VARIANTS as u32 {
+            render(&store, &keys, "lru.png", &variant(width), &limits).unwrap();
+        }
+        assert_eq!(render(&store, &keys, "lru.png", &variant(1), &limits).unwrap(), first);
+
+        let last = variant(MAX_CACHED_VARIANTS as u32 + 1);
+        render(&store, &keys, "lru.png", &last, &limits).unwrap();
+        assert_eq!(store.list(".variants/lru.png/").unwrap().len(), MAX_CACHED_VARIANTS);
+        assert!(store.stat(&first).is_ok());
+        assert!(store.stat(&second).is_err());
+    }
+}
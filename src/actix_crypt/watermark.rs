@@ -0,0 +1,394 @@
+//! Watermarks drawn on the images of a tenant when they are served.
+//!
+//! The stored blobs stay untouched: a watermarked image is a variant, rendered and cached like a
+//! resized one under a name holding the version of the watermark. Changing the watermark changes
+//! its version, the images are then rendered again and the stale variants dropped.
+//!
+//! Animated images are watermarked and served as their first frame, like their resized variants.
+use std::io;
+
+use ab_glyph::{point, Font, FontArc, PxScale, ScaleFont};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+use sha2::{Digest, Sha256};
+
+/// Font size the width of a text is measured at, it then scales linearly.
+const MEASURE_SIZE: f32 = 100.0;
+
+/// Space between the mark and the edges of the image, relative to its smallest side.
+const MARGIN: f32 = 0.02;
+
+/// Where the mark is drawn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl Position {
+    fn as_str(self) -> &'static str {
+        match self {
+            Position::TopLeft => "top_left",
+            Position::TopRight => "top_right",
+            Position::BottomLeft => "bottom_left",
+            Position::BottomRight => "bottom_right",
+            Position::Center => "center",
+        }
+    }
+}
+
+enum MarkKind {
+    /// An image, with its alpha channel.
+    Image(RgbaImage),
+    /// A line of text in `color`.
+    Text {
+        text: String,
+        font: FontArc,
+        color: [u8; 3],
+    },
+}
+
+/// What is drawn on the images.
+pub struct Mark {
+    kind: MarkKind,
+    /// SHA-256 of what is drawn.
+    fingerprint: String,
+}
+
+impl Mark {
+    /// Decode the image `data` to draw it.
+    pub fn image(data: &[u8]) -> io::Result<Self> {
+        let image = image::load_from_memory(data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+            .to_rgba8();
+        if image.width() == 0 || image.height() == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The image is empty"));
+        }
+
+        Ok(Mark {
+            kind: MarkKind::Image(image),
+            fingerprint: hex::encode(Sha256::digest(data)),
+        })
+    }
+
+    /// Load the TrueType or OpenType font `font` to draw `text` in `color`.
+    pub fn text(text: &str, font: Vec<u8>, color: [u8; 3]) -> io::Result<Self> {
+        if text.trim().is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The text is empty"));
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.input(&font);
+        hasher.input(text.as_bytes());
+        hasher.input(color);
+        let fingerprint = hex::encode(hasher.result());
+
+        let font = FontArc::try_from_vec(font).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(Mark {
+            kind: MarkKind::Text {
+                text: text.to_string(),
+                font,
+                color,
+            },
+            fingerprint,
+        })
+    }
+}
+
+/// Return the width of `text` in `font` at `size` pixels, and its glyphs laid out on a line.
+fn layout(font: &FontArc, text: &str, size: f32) -> (f32, Vec<ab_glyph::Glyph>) {
+    let scaled = font.as_scaled(PxScale::from(size));
+    let mut glyphs = Vec::new();
+    let mut x = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            x += scaled.kern(previous, id);
+        }
+        glyphs.push(id.with_scale_and_position(size, point(x, scaled.ascent())));
+        x += scaled.h_advance(id);
+        previous = Some(id);
+    }
+    (x, glyphs)
+}
+
+/// Draw `text` in `font` and `color` to be at most `width` by `height` pixels.
+fn render_text(font: &FontArc, text: &str, color: [u8; 3], width: u32, height: u32) -> RgbaImage {
+    let (measured_width, _) = layout(font, text, MEASURE_SIZE);
+    let measured_height = font.as_scaled(PxScale::from(MEASURE_SIZE)).height();
+    let size = f32::min(
+        MEASURE_SIZE * width as f32 / measured_width.max(1.0),
+        MEASURE_SIZE * height as f32 / measured_height.max(1.0),
+    )
+    .max(1.0);
+
+    let (text_width, glyphs) = layout(font, text, size);
+    let text_height = font.as_scaled(PxScale::from(size)).height();
+    let mut mark = RgbaImage::from_pixel(
+        (text_width.ceil() as u32).max(1),
+        (text_height.ceil() as u32).max(1),
+        Rgba([color[0], color[1], color[2], 0]),
+    );
+
+    for glyph in glyphs {
+        let outlined = match font.outline_glyph(glyph) {
+            Some(outlined) => outlined,
+            // Spaces have no outline
+            None => continue,
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|x, y, coverage| {
+            let x = x as i64 + bounds.min.x as i64;
+            let y = y as i64 + bounds.min.y as i64;
+            if x >= 0 && y >= 0 && x < i64::from(mark.width()) && y < i64::from(mark.height()) {
+                let pixel = mark.get_pixel_mut(x as u32, y as u32);
+                pixel[3] = pixel[3].max((coverage.clamp(0.0, 1.0) * 255.0).round() as u8);
+            }
+        });
+    }
+
+    mark
+}
+
+/// A watermark of the images of a tenant.
+pub struct Watermark {
+    pub mark: Mark,
+    pub position: Position,
+    /// Opacity of the mark, from 0 to 1.
+    pub opacity: f32,
+    /// Width of the mark relative to the width of the image, from 0 to 1.
+    pub scale: f32,
+    /// Users whose API token sees the originals.
+    pub bypass_users: Vec<String>,
+    /// SHA-256 of the API tokens seeing the originals, as listed by `imagers token list`.
+    pub bypass_tokens: Vec<String>,
+    /// Changes with anything drawn on the images, the cached variants are keyed by it.
+    pub version: String,
+}
+
+impl Watermark {
+    /// Create a watermark drawing `mark`.
+    pub fn new(
+        mark: Mark,
+        position: Position,
+        opacity: f32,
+        scale: f32,
+        bypass_users: Vec<String>,
+        bypass_tokens: Vec<String>,
+    ) -> Self {
+        let settings = format!("{} {} {} {}", mark.fingerprint, position.as_str(), opacity, scale);
+        let version = hex::encode(&Sha256::digest(settings.as_bytes())[..8]);
+
+        Watermark {
+            mark,
+            position,
+            opacity,
+            scale,
+            bypass_users,
+            bypass_tokens,
+            version,
+        }
+    }
+
+    /// Return true if the request of `user`, authenticated by the token `token_hash`, sees the
+    /// originals.
+    pub fn is_bypassed(&self, user: &str, token_hash: &str) -> bool {
+        self.bypass_users.iter().any(|bypass_user| bypass_user == user)
+            || self.bypass_tokens.iter().any(|bypass_token| bypass_token == token_hash)
+    }
+
+    /// Return `image` with the mark drawn on it.
+    pub fn apply(&self, image: DynamicImage) -> DynamicImage {
+        let (width, height) = image.dimensions();
+        let margin = (width.min(height) as f32 * MARGIN).round() as u32;
+        let max_width = ((width as f32 * self.scale).round() as u32)
+            .min(width.saturating_sub(2 * margin))
+            .max(1);
+        let max_height = height.saturating_sub(2 * margin).max(1);
+
+        let mark = match self.mark.kind {
+            MarkKind::Image(ref mark) => {
+                let ratio = f32::min(
+                    max_width as f32 / mark.width() as f32,
+                    max_height as f32 / mark.height() as f32,
+                );
+                let mark_width = ((mark.width() as f32 * ratio).round() as u32).max(1);
+                let mark_height = ((mark.height() as f32 * ratio).round() as u32).max(1);
+                image::imageops::resize(mark, mark_width, mark_height, FilterType::Triangle)
+            }
+            MarkKind::Text {
+                ref text,
+                ref font,
+                color,
+            } => render_text(font, text, color, max_width, max_height),
+        };
+
+        let free_width = width.saturating_sub(mark.width());
+        let free_height = height.saturating_sub(mark.height());
+        let (left, top) = match self.position {
+            Position::TopLeft => (margin, margin),
+            Position::TopRight => (free_width.saturating_sub(margin), margin),
+            Position::BottomLeft => (margin, free_height.saturating_sub(margin)),
+            Position::BottomRight => (free_width.saturating_sub(margin), free_height.saturating_sub(margin)),
+            Position::Center => (free_width / 2, free_height / 2),
+        };
+
+        let has_alpha = image.color().has_alpha();
+        let mut canvas = image.to_rgba8();
+        for (x, y, mark_pixel) in mark.enumerate_pixels() {
+            let (x, y) = (left + x, top + y);
+            if x >= width || y >= height {
+                continue;
+            }
+
+            // The mark over the image
+            let pixel = canvas.get_pixel_mut(x, y);
+            let mark_alpha = f32::from(mark_pixel[3]) / 255.0 * self.opacity;
+            let image_alpha = f32::from(pixel[3]) / 255.0;
+            let alpha = mark_alpha + image_alpha * (1.0 - mark_alpha);
+            if alpha <= 0.0 {
+                continue;
+            }
+            for channel in 0..3 {
+                let blended = (f32::from(mark_pixel[channel]) * mark_alpha
+                    + f32::from(pixel[channel]) * image_alpha * (1.0 - mark_alpha))
+                    / alpha;
+                pixel[channel] = blended.round() as u8;
+            }
+            pixel[3] = (alpha * 255.0).round() as u8;
+        }
+
+        // Opaque images stay opaque, a JPEG couldn't be encoded otherwise
+        if has_alpha {
+            DynamicImage::ImageRgba8(canvas)
+        } else {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::{Mark, Position, Watermark};
+
+    /// Return a `width` by `height` mark of opaque white.
+    fn mark(width: u32, height: u32) -> Mark {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba([255; 4])))
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        Mark::image(&data).unwrap()
+    }
+
+    fn watermark(mark: Mark, position: Position, opacity: f32, scale: f32) -> Watermark {
+        Watermark::new(
+            mark,
+            position,
+            opacity,
+            scale,
+            vec!["alice".to_string()],
+            vec!["ab".repeat(32)],
+        )
+    }
+
+    fn black(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+    }
+
+    #[test]
+    fn positions() {
+        // A 10 pixels mark with a 2 pixels margin on a 100 pixels image
+        for &(position, left, top) in &[
+            (Position::TopLeft, 2, 2),
+            (Position::TopRight, 88, 2),
+            (Position::BottomLeft, 2, 88),
+            (Position::BottomRight, 88, 88),
+            (Position::Center, 45, 45),
+        ] {
+            let marked =
+                watermark(mark(10, 10), position, 1.0, 0.1).apply(black(100, 100)).to_rgb8();
+
+            let white = marked.enumerate_pixels().filter(|(_, _, pixel)| **pixel == Rgb([255; 3]));
+            let (x, y) = white.clone().map(|(x, y, _)| (x, y)).min().unwrap();
+            assert_eq!((x, y), (left, top), "{:?}", position);
+            assert_eq!(white.count(), 100, "{:?}", position);
+        }
+    }
+
+    #[test]
+    fn opacity() {
+        let marked = watermark(mark(10, 10), Position::TopLeft, 0.5, 0.1).apply(black(100, 100));
+        assert_eq!(marked.to_rgb8().get_pixel(2, 2), &Rgb([128; 3]));
+        assert_eq!(marked.to_rgb8().get_pixel(1, 1), &Rgb([0; 3]));
+
+        // Transparent pixels get the color of the mark, as transparent as the mark
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::new(100, 100));
+        let marked = watermark(mark(10, 10), Position::TopLeft, 0.5, 0.1).apply(transparent);
+        assert_eq!(marked.to_rgba8().get_pixel(2, 2), &Rgba([255, 255, 255, 128]));
+        assert_eq!(marked.to_rgba8().get_pixel(1, 1), &Rgba([0; 4]));
+    }
+
+    #[test]
+    fn color_types() {
+        let watermark = watermark(mark(10, 10), Position::Center, 0.5, 0.25);
+
+        // Opaque images stay opaque, to be encoded as JPEG
+        let marked = watermark.apply(black(40, 30));
+        assert_eq!(marked.color(), image::ColorType::Rgb8);
+
+        let marked = watermark.apply(DynamicImage::ImageRgba8(RgbaImage::new(40, 30)));
+        assert_eq!(marked.color(), image::ColorType::Rgba8);
+    }
+
+    #[test]
+    fn large_marks() {
+        // Scaled down to fit, with its aspect ratio
+        let marked =
+            watermark(mark(400, 100), Position::BottomRight, 1.0, 1.0).apply(black(50, 40));
+        assert_eq!((marked.width(), marked.height()), (50, 40));
+        let white = marked.to_rgb8().pixels().filter(|pixel| **pixel == Rgb([255; 3])).count();
+        assert_eq!(white, 48 * 12);
+
+        for &(width, height) in &[(1, 1), (1, 50), (50, 1)] {
+            let marked =
+                watermark(mark(400, 100), Position::Center, 1.0, 1.0).apply(black(width, height));
+            assert_eq!((marked.width(), marked.height()), (width, height));
+        }
+    }
+
+    #[test]
+    fn bypass() {
+        let watermark = watermark(mark(10, 10), Position::Center, 0.5, 0.25);
+        assert!(watermark.is_bypassed("alice", "cd"));
+        assert!(watermark.is_bypassed("bob", &"ab".repeat(32)));
+        assert!(!watermark.is_bypassed("bob", "cd"));
+        assert!(!watermark.is_bypassed("", ""));
+    }
+
+    #[test]
+    fn versions() {
+        let version =
+            |mark, position, opacity, scale| watermark(mark, position, opacity, scale).version;
+        let reference = version(mark(10, 10), Position::Center, 0.5, 0.25);
+
+        assert_eq!(version(mark(10, 10), Position::Center, 0.5, 0.25), reference);
+        assert_ne!(version(mark(10, 11), Position::Center, 0.5, 0.25), reference);
+        assert_ne!(version(mark(10, 10), Position::TopLeft, 0.5, 0.25), reference);
+        assert_ne!(version(mark(10, 10), Position::Center, 0.6, 0.25), reference);
+        assert_ne!(version(mark(10, 10), Position::Center, 0.5, 0.3), reference);
+
+        // Who sees the originals isn't drawn
+        let bypassing =
+            Watermark::new(mark(10, 10), Position::Center, 0.5, 0.25, Vec::new(), Vec::new());
+        assert_eq!(bypassing.version, reference);
+    }
+}
@@ -0,0 +1,1380 @@
+//! Server configuration, read from a TOML file and overridden by the environment.
+//!
+//! The file is `imagers.toml`, or the one given by `CONFIG_FILE`. Every setting can be
+//! overridden by its environment variable, listed next to it in `imagers.example.toml`.
+//!
+//! The top-level settings describe the default tenant, other tenants are listed in
+//! `[[tenants]]` tables and served on their own host or path prefix. Environment
+//! overrides only apply to the default tenant.
+//!
+//! The server reloads the file on SIGHUP, the environment overrides still apply.
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::actix_crypt::decode::DecodeLimits;
+use crate::actix_crypt::store::{is_hidden, BucketLayout, StorageConfig, SymlinkPolicy};
+use crate::actix_crypt::variants::MAX_DIMENSION;
+use crate::actix_crypt::watermark::{Mark, Position, Watermark};
+use crate::actix_crypt::{BlobKey, MAX_PARITY_SHARDS};
+use crate::limits::UploadLimits;
+use crate::replay::ReplayProtection;
+use crate::scrubber::ScrubSettings;
+
+const DEFAULT_CONFIG_FILE: &str = "imagers.toml";
+
+/// Name of the tenant described by the top-level settings.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Widths and heights of the variants, when not configured.
+const DEFAULT_VARIANT_SIZES: [u32; 13] = [
+    32, 64, 128, 256, 320, 480, 640, 800, 1024, 1280, 1600, 1920, 2560,
+];
+
+/// The configuration as written in the file, every setting is optional there.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    base_url: Option<String>,
+    ip: Option<String>,
+    port: Option<u16>,
+    temp_dir: Option<PathBuf>,
+    aes_key: Option<String>,
+    blob_magic: Option<String>,
+    previous_keys: Option<Vec<KeyFile>>,
+    require_upload_token: Option<bool>,
+    url_signing_key: Option<String>,
+    strip_metadata: Option<bool>,
+    dedup: Option<bool>,
+    parity_shards: Option<usize>,
+    #[serde(default)]
+    limits: LimitsFile,
+    #[serde(default)]
+    decode_limits: DecodeLimitsFile,
+    #[serde(default)]
+    replay_protection: ReplayFile,
+    #[serde(default)]
+    moderation: ModerationFile,
+    #[serde(default)]
+    variants: VariantsFile,
+    #[serde(default)]
+    watermark: WatermarkFile,
+    #[serde(default)]
+    storage: StorageFile,
+    #[serde(default)]
+    scrubber: ScrubberFile,
+    #[serde(default)]
+    tenants: Vec<TenantFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TenantFile {
+    name: String,
+    host: Option<String>,
+    prefix: Option<String>,
+    base_url: Option<String>,
+    aes_key: Option<String>,
+    blob_magic: Option<String>,
+    previous_keys: Option<Vec<KeyFile>>,
+    require_upload_token: Option<bool>,
+    url_signing_key: Option<String>,
+    strip_metadata: Option<bool>,
+    dedup: Option<bool>,
+    parity_shards: Option<usize>,
+    #[serde(default)]
+    limits: LimitsFile,
+    #[serde(default)]
+    decode_limits: DecodeLimitsFile,
+    #[serde(default)]
+    replay_protection: ReplayFile,
+    #[serde(default)]
+    moderation: ModerationFile,
+    #[serde(default)]
+    variants: VariantsFile,
+    #[serde(default)]
+    watermark: WatermarkFile,
+    #[serde(default)]
+    storage: StorageFile,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyFile {
+    aes_key: String,
+    blob_magic: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsFile {
+    quota_bytes: Option<u64>,
+    quota_files: Option<u64>,
+    rate_limit_uploads: Option<u64>,
+    rate_limit_window: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DecodeLimitsFile {
+    max_dimension: Option<u32>,
+    max_pixels: Option<u64>,
+    max_frames: Option<u32>,
+    max_memory: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReplayFile {
+    enabled: Option<bool>,
+    window: Option<u64>,
+    capacity: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModerationFile {
+    moderators: Option<Vec<String>>,
+    blocklist: Option<String>,
+    blocklist_distance: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VariantsFile {
+    sizes: Option<Vec<u32>>,
+    negotiate_format: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WatermarkFile {
+    image: Option<PathBuf>,
+    text: Option<String>,
+    font: Option<PathBuf>,
+    color: Option<String>,
+    position: Option<String>,
+    opacity: Option<f32>,
+    scale: Option<f32>,
+    bypass_users: Option<Vec<String>>,
+    bypass_tokens: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScrubberFile {
+    quarantine: Option<PathBuf>,
+    report: Option<PathBuf>,
+    state: Option<PathBuf>,
+    rate: Option<u64>,
+    interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StorageFile {
+    backend: Option<String>,
+    path: Option<PathBuf>,
+    layout: Option<String>,
+    shard_depth: Option<usize>,
+    shard_width: Option<usize>,
+    symlink_policy: Option<String>,
+    s3_endpoint: Option<String>,
+    s3_bucket: Option<String>,
+    s3_region: Option<String>,
+    s3_access_key: Option<String>,
+    s3_secret_key: Option<String>,
+}
+
+/// How requests are routed to a tenant.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TenantRoute {
+    /// Requests no other tenant claimed.
+    Default,
+    /// Requests with this `Host` header.
+    Host(String),
+    /// Requests under this path prefix.
+    Prefix(String),
+}
+
+/// What happens to uploads matching a blocked perceptual hash.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlocklistAction {
+    /// The blocklist isn't checked.
+    Off,
+    /// The upload is stored and listed by `imagers blocklist flagged`.
+    Flag,
+    /// The upload is rejected.
+    Reject,
+}
+
+/// Moderation of the uploaded images, with their perceptual hashes.
+#[derive(Debug, Clone)]
+pub struct Moderation {
+    /// Users whose API token can search near-duplicates.
+    pub moderators: Vec<String>,
+    pub blocklist: BlocklistAction,
+    /// Largest Hamming distance between an upload and a blocked hash it matches.
+    pub blocklist_distance: u32,
+}
+
+/// Resized and converted variants of the images.
+#[derive(Debug, Clone)]
+pub struct VariantSettings {
+    /// Widths and heights that can be requested, every one of them is a cached variant.
+    pub sizes: Vec<u32>,
+    /// Convert the images to the smaller formats accepted by the clients without `?format=`.
+    pub negotiate_format: bool,
+}
+
+/// A set of blobs with its own key, bucket and URLs.
+#[derive(Clone)]
+pub struct Tenant {
+    pub name: String,
+    pub route: TenantRoute,
+    /// Prefix of the URLs returned after an upload.
+    pub base_url: String,
+    pub key: BlobKey,
+    /// Keys of the blobs encrypted before `key`, the scrubber verifies them with these.
+    pub previous_keys: Vec<BlobKey>,
+    /// Reject uploads without a valid API token.
+    pub require_upload_token: bool,
+    /// Quota and rate limit of every user with an API token.
+    pub limits: UploadLimits,
+    /// Limits of the uploaded images decoded for placeholders, variants and metadata removal.
+    pub decode_limits: DecodeLimits,
+    /// Reject replayed uploads, `None` if disabled.
+    pub replay_protection: Option<ReplayProtection>,
+    /// Key of the signed URLs of private blobs.
+    pub url_signing_key: Vec<u8>,
+    /// Remove the metadata of uploaded images.
+    pub strip_metadata: bool,
+    /// Store identical uploads once.
+    pub dedup: bool,
+    /// Amount of damaged shards a blob can recover from, 0 disables parity generation.
+    pub parity_shards: usize,
+    pub moderation: Moderation,
+    pub variants: VariantSettings,
+    /// Drawn on the served images, `None` if they are served as is.
+    pub watermark: Option<Arc<Watermark>>,
+    pub storage: StorageConfig,
+}
+
+/// The validated configuration of the server.
+#[derive(Clone)]
+pub struct Config {
+    pub ip: String,
+    pub port: u16,
+    /// Where uploads are received before being validated.
+    pub temp_dir: PathBuf,
+    pub scrubber: ScrubSettings,
+    /// Every tenant, in the order requests are matched against them.
+    pub tenants: Vec<Arc<Tenant>>,
+}
+
+/// The active configuration, replaced as a whole when reloaded.
+///
+/// Users take a snapshot with `current` for the duration of a request.
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        SharedConfig(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Return the active settings of the tenant `name`, a reload never removes a tenant.
+    pub fn tenant(&self, name: &str) -> Arc<Tenant> {
+        self.current()
+            .tenant(name)
+            .cloned()
+            .expect("Tenants are only removed on restart")
+    }
+
+    fn replace(&self, config: Config) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+
+    /// Load the configuration again, an invalid one is logged and the active one is kept.
+    pub fn reload(&self) {
+        let config = match Config::load() {
+            Ok(config) => config,
+            Err(e) => {
+                log::error!("Config: reload failed, keeping the active configuration: {}", e);
+                return;
+            }
+        };
+
+        let current = self.current();
+        if config.ip != current.ip || config.port != current.port {
+            log::warn!("Config: listen address changes only apply after a restart");
+        }
+        if config.scrubber != current.scrubber {
+            log::warn!("Config: scrubber changes only apply after a restart");
+        }
+
+        // The listener, the routes and the stores are set up at startup
+        let tenants = current
+            .tenants
+            .iter()
+            .map(|tenant| match config.tenant(&tenant.name) {
+                Some(reloaded) => {
+                    if reloaded.route != tenant.route || reloaded.storage != tenant.storage {
+                        log::warn!(
+                            "Config: route and storage changes of tenant {} only apply after a restart",
+                            tenant.name
+                        );
+                    }
+
+                    Arc::new(Tenant {
+                        route: tenant.route.clone(),
+                        storage: tenant.storage.clone(),
+                        ..(**reloaded).clone()
+                    })
+                }
+                None => {
+                    log::warn!("Config: tenant {} can only be removed with a restart", tenant.name);
+                    tenant.clone()
+                }
+            })
+            .collect();
+
+        if config.tenants.len() > current.tenants.len() {
+            log::warn!("Config: new tenants only apply after a restart");
+        }
+
+        let config = Config {
+            ip: current.ip.clone(),
+            port: current.port,
+            scrubber: current.scrubber.clone(),
+            tenants,
+            ..config
+        };
+
+        self.replace(config);
+        log::info!("Config: reloaded");
+    }
+
+    /// Reload the configuration on every SIGHUP, from a dedicated thread.
+    pub fn reload_on_sighup(&self) -> io::Result<()> {
+        let signals = signal_hook::iterator::Signals::new([signal_hook::SIGHUP])?;
+        let config = self.clone();
+
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                config.reload();
+            }
+        });
+
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn env_string(name: &str, setting: &mut Option<String>) {
+    if let Ok(value) = std::env::var(name) {
+        *setting = Some(value);
+    }
+}
+
+fn env_parse<T: FromStr>(name: &str, setting: &mut Option<T>) -> io::Result<()> {
+    if let Ok(value) = std::env::var(name) {
+        let value = value
+            .parse()
+            .map_err(|_| invalid(format!("{} is not valid: {}", name, value)))?;
+        *setting = Some(value);
+    }
+
+    Ok(())
+}
+
+/// Read a comma separated list, empty items are ignored.
+fn env_list(name: &str, setting: &mut Option<Vec<String>>) {
+    if let Ok(value) = std::env::var(name) {
+        *setting = Some(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect(),
+        );
+    }
+}
+
+/// Read a comma separated list of values.
+fn env_parse_list<T: FromStr>(name: &str, setting: &mut Option<Vec<T>>) -> io::Result<()> {
+    let mut items = None;
+    env_list(name, &mut items);
+
+    if let Some(items) = items {
+        let values = items
+            .iter()
+            .map(|item| {
+                item.parse()
+                    .map_err(|_| invalid(format!("{} is not valid: {}", name, item)))
+            })
+            .collect::<io::Result<_>>()?;
+        *setting = Some(values);
+    }
+
+    Ok(())
+}
+
+/// Read a comma separated list of `<aes_key>:<blob_magic>`.
+fn env_keys(name: &str, setting: &mut Option<Vec<KeyFile>>) -> io::Result<()> {
+    let mut keys = None;
+    env_list(name, &mut keys);
+
+    if let Some(keys) = keys {
+        let keys = keys
+            .iter()
+            .map(|key| match key.split_once(':') {
+                Some((aes_key, blob_magic)) => Ok(KeyFile {
+                    aes_key: aes_key.to_string(),
+                    blob_magic: blob_magic.to_string(),
+                }),
+                // Keys don't belong in logs
+                None => Err(invalid(format!("{} must list <aes_key>:<blob_magic>", name))),
+            })
+            .collect::<io::Result<_>>()?;
+        *setting = Some(keys);
+    }
+
+    Ok(())
+}
+
+fn require(setting: Option<String>, name: &str) -> io::Result<String> {
+    setting.ok_or_else(|| invalid(format!("{} must be set", name)))
+}
+
+impl ConfigFile {
+    fn apply_env(&mut self) -> io::Result<()> {
+        env_string("BASE_URL", &mut self.base_url);
+        env_string("IP", &mut self.ip);
+        env_parse("PORT", &mut self.port)?;
+        env_parse("TEMP_DIR", &mut self.temp_dir)?;
+        env_string("AES_KEY", &mut self.aes_key);
+        env_string("BLOB_MAGIC", &mut self.blob_magic);
+        env_keys("PREVIOUS_KEYS", &mut self.previous_keys)?;
+        env_parse("REQUIRE_UPLOAD_TOKEN", &mut self.require_upload_token)?;
+        env_string("URL_SIGNING_KEY", &mut self.url_signing_key);
+        env_parse("STRIP_METADATA", &mut self.strip_metadata)?;
+        env_parse("DEDUP", &mut self.dedup)?;
+        env_parse("PARITY_SHARDS", &mut self.parity_shards)?;
+
+        let limits = &mut self.limits;
+        env_parse("QUOTA_BYTES", &mut limits.quota_bytes)?;
+        env_parse("QUOTA_FILES", &mut limits.quota_files)?;
+        env_parse("RATE_LIMIT_UPLOADS", &mut limits.rate_limit_uploads)?;
+        env_parse("RATE_LIMIT_WINDOW", &mut limits.rate_limit_window)?;
+
+        let decode_limits = &mut self.decode_limits;
+        env_parse("IMAGE_MAX_DIMENSION", &mut decode_limits.max_dimension)?;
+        env_parse("IMAGE_MAX_PIXELS", &mut decode_limits.max_pixels)?;
+        env_parse("IMAGE_MAX_FRAMES", &mut decode_limits.max_frames)?;
+        env_parse("IMAGE_MAX_MEMORY", &mut decode_limits.max_memory)?;
+
+        let replay_protection = &mut self.replay_protection;
+        env_parse("REPLAY_PROTECTION", &mut replay_protection.enabled)?;
+        env_parse("REPLAY_WINDOW", &mut replay_protection.window)?;
+        env_parse("REPLAY_CAPACITY", &mut replay_protection.capacity)?;
+
+        let moderation = &mut self.moderation;
+        env_list("MODERATORS", &mut moderation.moderators);
+        env_string("BLOCKLIST", &mut moderation.blocklist);
+        env_parse("BLOCKLIST_DISTANCE", &mut moderation.blocklist_distance)?;
+
+        let variants = &mut self.variants;
+        env_parse_list("VARIANT_SIZES", &mut variants.sizes)?;
+        env_parse("VARIANT_NEGOTIATE_FORMAT", &mut variants.negotiate_format)?;
+
+        let watermark = &mut self.watermark;
+        env_parse("WATERMARK_IMAGE", &mut watermark.image)?;
+        env_string("WATERMARK_TEXT", &mut watermark.text);
+        env_parse("WATERMARK_FONT", &mut watermark.font)?;
+        env_string("WATERMARK_COLOR", &mut watermark.color);
+        env_string("WATERMARK_POSITION", &mut watermark.position);
+        env_parse("WATERMARK_OPACITY", &mut watermark.opacity)?;
+        env_parse("WATERMARK_SCALE", &mut watermark.scale)?;
+        env_list("WATERMARK_BYPASS_USERS", &mut watermark.bypass_users);
+        env_list("WATERMARK_BYPASS_TOKENS", &mut watermark.bypass_tokens);
+
+        let storage = &mut self.storage;
+        env_string("STORAGE_BACKEND", &mut storage.backend);
+        env_parse("BUCKET_PATH", &mut storage.path)?;
+        env_string("BUCKET_LAYOUT", &mut storage.layout);
+        env_parse("BUCKET_SHARD_DEPTH", &mut storage.shard_depth)?;
+        env_parse("BUCKET_SHARD_WIDTH", &mut storage.shard_width)?;
+        env_string("SYMLINK_POLICY", &mut storage.symlink_policy);
+        env_string("S3_ENDPOINT", &mut storage.s3_endpoint);
+        env_string("S3_BUCKET", &mut storage.s3_bucket);
+        env_string("S3_REGION", &mut storage.s3_region);
+        env_string("S3_ACCESS_KEY", &mut storage.s3_access_key);
+        env_string("S3_SECRET_KEY", &mut storage.s3_secret_key);
+
+        let scrubber = &mut self.scrubber;
+        env_parse("SCRUB_QUARANTINE", &mut scrubber.quarantine)?;
+        env_parse("SCRUB_REPORT", &mut scrubber.report)?;
+        env_parse("SCRUB_STATE", &mut scrubber.state)?;
+        env_parse("SCRUB_RATE", &mut scrubber.rate)?;
+        env_parse("SCRUB_INTERVAL", &mut scrubber.interval)?;
+
+        Ok(())
+    }
+}
+
+impl LimitsFile {
+    /// Validate the limits found in the `context` table.
+    fn into_limits(self, context: &str) -> io::Result<UploadLimits> {
+        let rate_window = self.rate_limit_window.unwrap_or(3600);
+        if rate_window == 0 {
+            return Err(invalid(format!(
+                "{}limits.rate_limit_window must be at least a second",
+                context
+            )));
+        }
+
+        Ok(UploadLimits {
+            quota_bytes: self.quota_bytes.unwrap_or(0),
+            quota_files: self.quota_files.unwrap_or(0),
+            rate_uploads: self.rate_limit_uploads.unwrap_or(0),
+            rate_window: Duration::from_secs(rate_window),
+        })
+    }
+}
+
+impl DecodeLimitsFile {
+    /// Validate the decoding limits found in the `context` table.
+    fn into_limits(self, context: &str) -> io::Result<DecodeLimits> {
+        let defaults = DecodeLimits::default();
+        let limits = DecodeLimits {
+            max_dimension: self.max_dimension.unwrap_or(defaults.max_dimension),
+            max_pixels: self.max_pixels.unwrap_or(defaults.max_pixels),
+            max_frames: self.max_frames.unwrap_or(defaults.max_frames),
+            max_memory: self.max_memory.unwrap_or(defaults.max_memory),
+        };
+        if limits.max_dimension == 0
+            || limits.max_pixels == 0
+            || limits.max_frames == 0
+            || limits.max_memory == 0
+        {
+            return Err(invalid(format!(
+                "{}decode_limits settings must be at least 1",
+                context
+            )));
+        }
+
+        Ok(limits)
+    }
+}
+
+impl ReplayFile {
+    /// Validate the replay protection found in the `context` table.
+    fn into_replay_protection(self, context: &str) -> io::Result<Option<ReplayProtection>> {
+        if !self.enabled.unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let window = self.window.unwrap_or(300);
+        let capacity = self.capacity.unwrap_or(100_000);
+        if window == 0 || capacity == 0 {
+            return Err(invalid(format!(
+                "{}replay_protection.window and capacity must be at least 1",
+                context
+            )));
+        }
+
+        Ok(Some(ReplayProtection {
+            window: Duration::from_secs(window),
+            capacity,
+        }))
+    }
+}
+
+impl ModerationFile {
+    /// Validate the moderation found in the `context` table.
+    fn into_moderation(self, context: &str) -> io::Result<Moderation> {
+        let blocklist = match self.blocklist.as_ref().map_or("off", String::as_str) {
+            "off" => BlocklistAction::Off,
+            "flag" => BlocklistAction::Flag,
+            "reject" => BlocklistAction::Reject,
+            blocklist => {
+                return Err(invalid(format!(
+                    "{}moderation.blocklist must be off, flag or reject, got {}",
+                    context, blocklist
+                )))
+            }
+        };
+
+        // Half of the bits of unrelated images differ
+        let blocklist_distance = self.blocklist_distance.unwrap_or(8);
+        if blocklist_distance > 24 {
+            return Err(invalid(format!(
+                "{}moderation.blocklist_distance must be at most 24",
+                context
+            )));
+        }
+
+        Ok(Moderation {
+            moderators: self.moderators.unwrap_or_default(),
+            blocklist,
+            blocklist_distance,
+        })
+    }
+}
+
+impl VariantsFile {
+    /// Validate the variant settings found in the `context` table.
+    fn into_settings(self, context: &str) -> io::Result<VariantSettings> {
+        let sizes = self.sizes.unwrap_or_else(|| DEFAULT_VARIANT_SIZES.to_vec());
+        if sizes.iter().any(|&size| size == 0 || size > MAX_DIMENSION) {
+            return Err(invalid(format!(
+                "{}variants.sizes must be between 1 and {}",
+                context, MAX_DIMENSION
+            )));
+        }
+
+        Ok(VariantSettings {
+            sizes,
+            negotiate_format: self.negotiate_format.unwrap_or(false),
+        })
+    }
+}
+
+/// Parse a color written as `#rrggbb`.
+fn parse_color(color: &str) -> Option<[u8; 3]> {
+    let color = color.strip_prefix('#')?;
+    if color.len() != 6 {
+        return None;
+    }
+
+    let channel = |index: usize| u8::from_str_radix(color.get(index..index + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+impl WatermarkFile {
+    /// Validate the watermark found in the `context` table, and load its image or font.
+    fn into_watermark(self, context: &str) -> io::Result<Option<Arc<Watermark>>> {
+        let read = |setting: &str, path: &Path| {
+            fs::read(path).map_err(|e| {
+                invalid(format!(
+                    "{}watermark.{} {} is not readable: {}",
+                    context,
+                    setting,
+                    path.display(),
+                    e
+                ))
+            })
+        };
+
+        let mark = match (self.image, self.text) {
+            (None, None) => return Ok(None),
+            (Some(image), None) => Mark::image(&read("image", &image)?)
+                .map_err(|e| invalid(format!("{}watermark.image {}: {}", context, image.display(), e)))?,
+            (None, Some(text)) => {
+                let font = match self.font {
+                    Some(font) => font,
+                    None => {
+                        return Err(invalid(format!(
+                            "{}watermark.font must be set to draw a text",
+                            context
+                        )))
+                    }
+                };
+
+                let color = self.color.as_ref().map_or("#ffffff", String::as_str);
+                let color = parse_color(color).ok_or_else(|| {
+                    invalid(format!("{}watermark.color must be like #rrggbb, got {}", context, color))
+                })?;
+
+                Mark::text(&text, read("font", &font)?, color)
+                    .map_err(|e| invalid(format!("{}watermark: {}", context, e)))?
+            }
+            (Some(_), Some(_)) => {
+                return Err(invalid(format!(
+                    "{}watermark.image or {}watermark.text must be set, not both",
+                    context, context
+                )))
+            }
+        };
+
+        let position = match self.position.as_ref().map_or("bottom_right", String::as_str) {
+            "top_left" => Position::TopLeft,
+            "top_right" => Position::TopRight,
+            "bottom_left" => Position::BottomLeft,
+            "bottom_right" => Position::BottomRight,
+            "center" => Position::Center,
+            position => {
+                return Err(invalid(format!(
+                    "{}watermark.position must be top_left, top_right, bottom_left, bottom_right or center, got {}",
+                    context, position
+                )))
+            }
+        };
+
+        let opacity = self.opacity.unwrap_or(0.5);
+        let scale = self.scale.unwrap_or(0.25);
+        let is_fraction = |value: f32| value > 0.0 && value <= 1.0;
+        if !is_fraction(opacity) || !is_fraction(scale) {
+            return Err(invalid(format!(
+                "{}watermark.opacity and scale must be above 0 and at most 1",
+                context
+            )));
+        }
+
+        // Hashes are what `imagers token list` shows, tokens themselves don't belong in a file
+        let bypass_tokens: Vec<String> = self
+            .bypass_tokens
+            .unwrap_or_default()
+            .into_iter()
+            .map(|hash| hash.to_ascii_lowercase())
+            .collect();
+        if bypass_tokens
+            .iter()
+            .any(|hash| hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()))
+        {
+            return Err(invalid(format!(
+                "{}watermark.bypass_tokens must list token hashes, see `imagers token list`",
+                context
+            )));
+        }
+
+        Ok(Some(Arc::new(Watermark::new(
+            mark,
+            position,
+            opacity,
+            scale,
+            self.bypass_users.unwrap_or_default(),
+            bypass_tokens,
+        ))))
+    }
+}
+
+impl StorageFile {
+    /// Validate the storage settings found in the `context` table.
+    fn into_config(self, context: &str, default_path: PathBuf) -> io::Result<StorageConfig> {
+        match self.backend.as_ref().map_or("local", String::as_str) {
+            "local" => {
+                let layout = match self.layout.as_ref().map_or("flat", String::as_str) {
+                    "flat" => BucketLayout::Flat,
+                    "sharded" => BucketLayout::Sharded {
+                        depth: self.shard_depth.unwrap_or(2),
+                        width: self.shard_width.unwrap_or(2),
+                    },
+                    layout => {
+                        return Err(invalid(format!(
+                            "{}storage.layout must be flat or sharded, got {}",
+                            context, layout
+                        )))
+                    }
+                };
+
+                let symlinks = match self
+                    .symlink_policy
+                    .as_ref()
+                    .map_or("within_root", String::as_str)
+                {
+                    "deny" => SymlinkPolicy::Deny,
+                    "within_root" => SymlinkPolicy::WithinRoot,
+                    "follow" => SymlinkPolicy::Follow,
+                    policy => {
+                        return Err(invalid(format!(
+                            "{}storage.symlink_policy must be deny, within_root or follow, got {}",
+                            context, policy
+                        )))
+                    }
+                };
+
+                Ok(StorageConfig::Local {
+                    path: self.path.unwrap_or(default_path),
+                    layout,
+                    symlinks,
+                })
+            }
+            "memory" => Ok(StorageConfig::Memory),
+            "s3" => {
+                let setting = |name| format!("{}storage.{}", context, name);
+
+                Ok(StorageConfig::S3 {
+                    endpoint: require(self.s3_endpoint, &setting("s3_endpoint"))?,
+                    bucket: require(self.s3_bucket, &setting("s3_bucket"))?,
+                    region: self.s3_region.unwrap_or_else(|| "us-east-1".to_string()),
+                    access_key: require(self.s3_access_key, &setting("s3_access_key"))?,
+                    secret_key: require(self.s3_secret_key, &setting("s3_secret_key"))?,
+                })
+            }
+            backend => Err(invalid(format!(
+                "{}storage.backend must be local, memory or s3, got {}",
+                context, backend
+            ))),
+        }
+    }
+}
+
+impl ScrubberFile {
+    /// Validate the scrubber settings found in the `scrubber` table.
+    fn into_settings(self) -> io::Result<ScrubSettings> {
+        let quarantine = self.quarantine.unwrap_or_else(|| PathBuf::from("./quarantine"));
+        if quarantine.is_file() {
+            return Err(invalid(format!(
+                "scrubber.quarantine {} must be a directory",
+                quarantine.display()
+            )));
+        }
+
+        let report = self.report.unwrap_or_else(|| PathBuf::from("./scrub_report.txt"));
+        let state = self.state.unwrap_or_else(|| PathBuf::from("./scrub.state"));
+        if report == state {
+            return Err(invalid("scrubber.report and state must be different files".to_string()));
+        }
+
+        Ok(ScrubSettings {
+            quarantine,
+            report,
+            state,
+            rate: self.rate.unwrap_or(4 * 1024 * 1024),
+            interval: Duration::from_secs(self.interval.unwrap_or(86_400)),
+        })
+    }
+}
+
+/// Make sure uploads can be moved from `temp_dir` to `bucket` without copying them across filesystems.
+#[cfg(unix)]
+fn check_same_filesystem(temp_dir: &Path, bucket: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let bucket_metadata = fs::metadata(bucket)
+        .map_err(|e| invalid(format!("bucket {} is not reachable: {}", bucket.display(), e)))?;
+
+    if fs::metadata(temp_dir)?.dev() != bucket_metadata.dev() {
+        return Err(invalid(format!(
+            "temp_dir {} and bucket {} must be on the same filesystem",
+            temp_dir.display(),
+            bucket.display()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_same_filesystem(_: &Path, _: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl Tenant {
+    /// Return the key of the uploads, then the previous keys.
+    pub fn keys(&self) -> Vec<BlobKey> {
+        std::iter::once(self.key)
+            .chain(self.previous_keys.iter().cloned())
+            .collect()
+    }
+
+    /// Validate the settings of `tenant`, found in the `context` table.
+    fn from_settings(
+        tenant: TenantFile,
+        route: TenantRoute,
+        context: &str,
+        default_path: PathBuf,
+    ) -> io::Result<Self> {
+        let base_url = require(tenant.base_url, &format!("{}base_url", context))?
+            .trim_end_matches('/')
+            .to_string();
+
+        let key = BlobKey::new(
+            &require(tenant.aes_key, &format!("{}aes_key", context))?,
+            &require(tenant.blob_magic, &format!("{}blob_magic", context))?,
+        )
+        .map_err(|e| invalid(format!("{}{}", context, e)))?;
+
+        let previous_keys = tenant
+            .previous_keys
+            .unwrap_or_default()
+            .into_iter()
+            .map(|previous| {
+                BlobKey::new(&previous.aes_key, &previous.blob_magic)
+                    .map_err(|e| invalid(format!("{}previous_keys: {}", context, e)))
+            })
+            .collect::<io::Result<_>>()?;
+
+        // Rotating the AES key also revokes the signed URLs derived from it
+        let url_signing_key = match tenant.url_signing_key {
+            Some(ref url_signing_key) if url_signing_key.is_empty() => {
+                return Err(invalid(format!("{}url_signing_key cannot be empty", context)))
+            }
+            Some(url_signing_key) => url_signing_key.into_bytes(),
+            None => key.derive("imagers url signing").to_vec(),
+        };
+
+        let parity_shards = tenant.parity_shards.unwrap_or(8);
+        if parity_shards > MAX_PARITY_SHARDS {
+            return Err(invalid(format!(
+                "{}parity_shards must be at most {}",
+                context, MAX_PARITY_SHARDS
+            )));
+        }
+
+        Ok(Tenant {
+            name: tenant.name,
+            route,
+            base_url,
+            key,
+            previous_keys,
+            require_upload_token: tenant.require_upload_token.unwrap_or(true),
+            limits: tenant.limits.into_limits(context)?,
+            decode_limits: tenant.decode_limits.into_limits(context)?,
+            replay_protection: tenant.replay_protection.into_replay_protection(context)?,
+            url_signing_key,
+            strip_metadata: tenant.strip_metadata.unwrap_or(false),
+            dedup: tenant.dedup.unwrap_or(false),
+            parity_shards,
+            moderation: tenant.moderation.into_moderation(context)?,
+            variants: tenant.variants.into_settings(context)?,
+            watermark: tenant.watermark.into_watermark(context)?,
+            storage: tenant.storage.into_config(context, default_path)?,
+        })
+    }
+
+    fn from_file(mut tenant: TenantFile) -> io::Result<Self> {
+        let context = format!("tenants.{}.", tenant.name);
+
+        let is_valid_name = !tenant.name.is_empty()
+            && tenant
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !is_valid_name || tenant.name == DEFAULT_TENANT {
+            return Err(invalid(format!(
+                "{:?} is not a valid tenant name",
+                tenant.name
+            )));
+        }
+
+        let route = match (tenant.host.take(), tenant.prefix.take()) {
+            (Some(host), None) => TenantRoute::Host(host.to_ascii_lowercase()),
+            (None, Some(prefix)) => {
+                let prefix = prefix.trim_end_matches('/').to_string();
+                if !prefix.starts_with('/') || prefix.len() < 2 || is_hidden(&prefix) {
+                    return Err(invalid(format!(
+                        "{}prefix must be a path like /{}",
+                        context, tenant.name
+                    )));
+                }
+                TenantRoute::Prefix(prefix)
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "{}host or {}prefix must be set, not both",
+                    context, context
+                )))
+            }
+        };
+
+        let default_path = PathBuf::from("./buckets").join(&tenant.name);
+        Tenant::from_settings(tenant, route, &context, default_path)
+    }
+}
+
+impl Config {
+    /// Read the configuration file, apply the environment overrides and validate the result.
+    pub fn load() -> io::Result<Self> {
+        let (path, is_required) = match std::env::var("CONFIG_FILE") {
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_CONFIG_FILE.to_string(), false),
+        };
+
+        let mut file = match fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content).map_err(|e| invalid(format!("{}: {}", path, e)))?,
+            // The environment alone is enough
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && !is_required => ConfigFile::default(),
+            Err(e) => return Err(invalid(format!("cannot read {}: {}", path, e))),
+        };
+
+        file.apply_env()?;
+        Config::from_file(file)
+    }
+
+    fn from_file(file: ConfigFile) -> io::Result<Self> {
+        let temp_dir = file.temp_dir.unwrap_or_else(std::env::temp_dir);
+        if !temp_dir.is_dir() {
+            return Err(invalid(format!(
+                "temp_dir {} is not a directory",
+                temp_dir.display()
+            )));
+        }
+
+        let mut tenants = Vec::new();
+
+        // The top-level settings are optional once other tenants are listed
+        if file.tenants.is_empty() || file.aes_key.is_some() {
+            let tenant = TenantFile {
+                name: DEFAULT_TENANT.to_string(),
+                host: None,
+                prefix: None,
+                base_url: file.base_url,
+                aes_key: file.aes_key,
+                blob_magic: file.blob_magic,
+                previous_keys: file.previous_keys,
+                require_upload_token: file.require_upload_token,
+                url_signing_key: file.url_signing_key,
+                strip_metadata: file.strip_metadata,
+                dedup: file.dedup,
+                parity_shards: file.parity_shards,
+                limits: file.limits,
+                decode_limits: file.decode_limits,
+                replay_protection: file.replay_protection,
+                moderation: file.moderation,
+                variants: file.variants,
+                watermark: file.watermark,
+                storage: file.storage,
+            };
+            tenants.push(Tenant::from_settings(
+                tenant,
+                TenantRoute::Default,
+                "",
+                PathBuf::from("./bucket"),
+            )?);
+        }
+
+        for tenant in file.tenants {
+            tenants.push(Tenant::from_file(tenant)?);
+        }
+
+        let mut names = HashSet::new();
+        let mut routes = Vec::new();
+        let mut buckets = Vec::new();
+        for tenant in &tenants {
+            if !names.insert(tenant.name.as_str()) {
+                return Err(invalid(format!("tenant {} is defined twice", tenant.name)));
+            }
+
+            if routes.contains(&&tenant.route) {
+                return Err(invalid(format!(
+                    "tenant {} uses the same route as another tenant",
+                    tenant.name
+                )));
+            }
+            routes.push(&tenant.route);
+
+            if let StorageConfig::Local { ref path, .. } = tenant.storage {
+                check_same_filesystem(&temp_dir, path)?;
+
+                let path = path.canonicalize().map_err(|e| {
+                    invalid(format!("bucket {} is not reachable: {}", path.display(), e))
+                })?;
+                if buckets.contains(&path) {
+                    return Err(invalid(format!(
+                        "tenant {} uses the bucket of another tenant",
+                        tenant.name
+                    )));
+                }
+                buckets.push(path);
+            }
+        }
+
+        // Hosts are matched first, then the longest prefixes, what remains goes to the default tenant
+        tenants.sort_by_key(|tenant| match tenant.route {
+            TenantRoute::Host(_) => (0, 0),
+            TenantRoute::Prefix(ref prefix) => (1, usize::MAX - prefix.len()),
+            TenantRoute::Default => (2, 0),
+        });
+
+        Ok(Config {
+            ip: file.ip.unwrap_or_else(|| "127.0.0.1".to_string()),
+            port: file.port.unwrap_or(8080),
+            temp_dir,
+            scrubber: file.scrubber.into_settings()?,
+            tenants: tenants.into_iter().map(Arc::new).collect(),
+        })
+    }
+
+    /// Return the tenant called `name`.
+    pub fn tenant(&self, name: &str) -> Option<&Arc<Tenant>> {
+        self.tenants.iter().find(|tenant| tenant.name == name)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use image::{DynamicImage, ImageFormat, RgbaImage};
+
+    use super::{Config, ConfigFile, TenantRoute, DEFAULT_TENANT};
+    use crate::actix_crypt::store::StorageConfig;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    /// Return the required settings of the default tenant, followed by `extra`.
+    pub(crate) fn settings(extra: &str) -> String {
+        format!(
+            "base_url = \"http://localhost\"\naes_key = \"{}\"\nblob_magic = \"IMAGERS0\"\n{}\n",
+            KEY, extra
+        )
+    }
+
+    fn parse(content: &str) -> ConfigFile {
+        toml::from_str(content).unwrap()
+    }
+
+    pub(crate) fn load(content: &str) -> std::io::Result<Config> {
+        Config::from_file(parse(content))
+    }
+
+    /// Return the error of loading `content`, which must be invalid.
+    fn error(content: &str) -> String {
+        match load(content) {
+            Ok(_) => panic!("accepted:\n{}", content),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    /// Create an empty directory for a test.
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("imagers-config-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&directory).ok();
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn defaults() {
+        let config = load(&settings("[storage]\nbackend = \"memory\"")).unwrap();
+        assert_eq!(config.tenants.len(), 1);
+
+        let tenant = &config.tenants[0];
+        assert_eq!(tenant.name, DEFAULT_TENANT);
+        assert_eq!(tenant.route, TenantRoute::Default);
+        assert!(tenant.require_upload_token);
+        assert_eq!(tenant.parity_shards, 8);
+        assert!(tenant.watermark.is_none());
+        assert_eq!(tenant.storage, StorageConfig::Memory);
+    }
+
+    #[test]
+    fn keys() {
+        let base_url = "base_url = \"http://localhost\"\n[storage]\nbackend = \"memory\"";
+        let config = |aes_key: &str, blob_magic: &str| {
+            format!(
+                "aes_key = \"{}\"\nblob_magic = \"{}\"\n{}",
+                aes_key, blob_magic, base_url
+            )
+        };
+
+        assert!(error(&config(&KEY[2..], "IMAGERS0")).contains("must be 32 bytes long"));
+        assert!(
+            error(&config(&KEY.replace('0', "g"), "IMAGERS0")).contains("not valid hexadecimal")
+        );
+        assert!(error(&config(KEY, "IMAGERS")).contains("must be 8 bytes long"));
+        assert!(error(&config(KEY, "IMAGERS00")).contains("must be 8 bytes long"));
+        assert!(error(base_url).contains("aes_key must be set"));
+
+        let previous = "previous_keys = [{ aes_key = \"00\", blob_magic = \"IMAGERS0\" }]";
+        let content = settings(&format!("{}\n[storage]\nbackend = \"memory\"", previous));
+        assert!(error(&content).starts_with("previous_keys: "));
+
+        let previous = format!(
+            "previous_keys = [{{ aes_key = \"{}\", blob_magic = \"IMAGERS1\" }}]",
+            KEY
+        );
+        let content = settings(&format!("{}\n[storage]\nbackend = \"memory\"", previous));
+        assert_eq!(load(&content).unwrap().tenants[0].keys().len(), 2);
+    }
+
+    #[test]
+    fn duplicates() {
+        let tenant = |name: &str, route: &str| {
+            format!(
+                "[[tenants]]\nname = \"{}\"\n{}\nbase_url = \"http://localhost\"\n\
+                 aes_key = \"{}\"\nblob_magic = \"IMAGERS0\"\n\
+                 storage = {{ backend = \"memory\" }}\n",
+                name, route, KEY
+            )
+        };
+
+        let hosts = format!(
+            "{}{}",
+            tenant("a", "host = \"a.test\""),
+            tenant("b", "host = \"A.test\"")
+        );
+        assert_eq!(
+            error(&hosts),
+            "tenant b uses the same route as another tenant"
+        );
+
+        let prefixes = format!(
+            "{}{}",
+            tenant("a", "prefix = \"/a\""),
+            tenant("b", "prefix = \"/a/\"")
+        );
+        assert_eq!(
+            error(&prefixes),
+            "tenant b uses the same route as another tenant"
+        );
+
+        let names = format!(
+            "{}{}",
+            tenant("a", "prefix = \"/a\""),
+            tenant("a", "prefix = \"/b\"")
+        );
+        assert_eq!(error(&names), "tenant a is defined twice");
+
+        let config = load(&format!(
+            "{}{}",
+            tenant("a", "prefix = \"/a\""),
+            tenant("b", "host = \"b.test\"")
+        ))
+        .unwrap();
+        assert_eq!(
+            config.tenants[0].route,
+            TenantRoute::Host("b.test".to_string())
+        );
+
+        // Paths are compared once canonicalized
+        let directory = directory("buckets");
+        let bucket = directory.join("bucket");
+        fs::create_dir_all(&bucket).unwrap();
+        let content = format!(
+            "{}{}",
+            settings(&format!(
+                "temp_dir = \"{}\"\n[storage]\npath = \"{}\"",
+                directory.display(),
+                bucket.display()
+            )),
+            tenant("a", "prefix = \"/a\"").replace(
+                "backend = \"memory\"",
+                &format!("path = \"{}/../bucket\"", bucket.display())
+            )
+        );
+        assert_eq!(
+            error(&content),
+            "tenant a uses the bucket of another tenant"
+        );
+
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn limits() {
+        let content =
+            |table: &str| settings(&format!("[storage]\nbackend = \"memory\"\n{}", table));
+
+        let error_of = |table: &str| error(&content(table));
+        assert_eq!(
+            error_of("[limits]\nrate_limit_window = 0"),
+            "limits.rate_limit_window must be at least a second"
+        );
+        assert_eq!(
+            error_of("[decode_limits]\nmax_frames = 0"),
+            "decode_limits settings must be at least 1"
+        );
+        assert_eq!(
+            error_of("[replay_protection]\nenabled = true\ncapacity = 0"),
+            "replay_protection.window and capacity must be at least 1"
+        );
+        assert_eq!(
+            error_of("[moderation]\nblocklist_distance = 25"),
+            "moderation.blocklist_distance must be at most 24"
+        );
+        assert!(error_of("[variants]\nsizes = [0]")
+            .starts_with("variants.sizes must be between 1 and "));
+        assert!(
+            error_of("[scrubber]\nreport = \"scrub\"\nstate = \"scrub\"")
+                .contains("different files")
+        );
+        assert!(error(&settings(
+            "parity_shards = 193\n[storage]\nbackend = \"memory\""
+        ))
+        .starts_with("parity_shards must be at most"));
+
+        let config = load(&content(
+            "[limits]\nquota_bytes = 10\n[decode_limits]\nmax_pixels = 20",
+        ))
+        .unwrap();
+        assert_eq!(config.tenants[0].limits.quota_bytes, 10);
+        assert_eq!(config.tenants[0].decode_limits.max_pixels, 20);
+    }
+
+    #[test]
+    fn watermark() {
+        let directory = directory("watermark");
+        let image = directory.join("mark.png");
+        DynamicImage::ImageRgba8(RgbaImage::new(8, 8))
+            .save_with_format(&image, ImageFormat::Png)
+            .unwrap();
+
+        let content = |table: &str| {
+            let table = table.replace("IMAGE", &image.display().to_string());
+            settings(&format!(
+                "[storage]\nbackend = \"memory\"\n[watermark]\n{}",
+                table
+            ))
+        };
+
+        let config = load(&content("image = \"IMAGE\"\nposition = \"center\"")).unwrap();
+        assert!(config.tenants[0].watermark.is_some());
+
+        assert!(error(&content("image = \"IMAGE\"\ntext = \"mark\"")).ends_with("not both"));
+        assert_eq!(
+            error(&content("text = \"mark\"")),
+            "watermark.font must be set to draw a text"
+        );
+        let position = error(&content("image = \"IMAGE\"\nposition = \"middle\""));
+        assert!(position.ends_with("got middle"));
+        assert!(error(&content("image = \"IMAGE\"\nopacity = 0.0")).contains("opacity and scale"));
+        assert!(error(&content("image = \"IMAGE\"\nscale = 1.5")).contains("opacity and scale"));
+        assert!(
+            error(&content("image = \"IMAGE\"\nbypass_tokens = [\"token\"]"))
+                .contains("token hashes")
+        );
+        let missing = error(&content("image = \"IMAGE.missing\""));
+        assert!(missing.starts_with("watermark.image ") && missing.contains("not readable"));
+
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn environment() {
+        // No other test reads the environment
+        std::env::set_var("QUOTA_BYTES", "42");
+        std::env::set_var("VARIANT_SIZES", "16, 32,");
+        std::env::set_var("PREVIOUS_KEYS", format!("{}:IMAGERS1", KEY));
+        let mut file = parse(&settings(
+            "[limits]\nquota_bytes = 10\n[storage]\nbackend = \"memory\"",
+        ));
+        let applied = file.apply_env();
+        std::env::set_var("PREVIOUS_KEYS", KEY);
+        let invalid = parse("").apply_env();
+        std::env::remove_var("QUOTA_BYTES");
+        std::env::remove_var("VARIANT_SIZES");
+        std::env::remove_var("PREVIOUS_KEYS");
+
+        applied.unwrap();
+        let config = Config::from_file(file).unwrap();
+        let tenant = &config.tenants[0];
+        assert_eq!(tenant.limits.quota_bytes, 42);
+        assert_eq!(tenant.variants.sizes, vec![16, 32]);
+        assert_eq!(tenant.previous_keys.len(), 1);
+
+        // Keys don't belong in logs
+        let invalid = invalid.unwrap_err().to_string();
+        assert_eq!(invalid, "PREVIOUS_KEYS must list <aes_key>:<blob_magic>");
+    }
+}
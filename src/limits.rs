@@ -0,0 +1,148 @@
+//! Per-user limits on uploads: a storage quota and a rate limit.
+//!
+//! The quota is checked against the usage kept in the bucket, the recent uploads are only
+//! tracked in memory and forgotten on restart.
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::actix_crypt::usage::Usage;
+
+lazy_static! {
+    /// Start time of the recent uploads of every user, by tenant and user.
+    static ref RECENT_UPLOADS: Mutex<HashMap<String, VecDeque<Instant>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The limits of every user of a tenant, 0 means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UploadLimits {
+    /// Total size of the blobs of a user.
+    pub quota_bytes: u64,
+    /// Amount of blobs of a user.
+    pub quota_files: u64,
+    /// Uploads a user can start per window.
+    pub rate_uploads: u64,
+    pub rate_window: Duration,
+}
+
+impl UploadLimits {
+    /// Return true if a user with `usage` can store `bytes` more.
+    pub fn allows(&self, usage: &Usage, bytes: u64) -> bool {
+        (self.quota_files == 0 || usage.files < self.quota_files)
+            && (self.quota_bytes == 0 || usage.bytes.saturating_add(bytes) <= self.quota_bytes)
+    }
+
+    /// Return how many bytes a user with `usage` can still store, `None` if unlimited.
+    pub fn remaining_bytes(&self, usage: &Usage) -> Option<u64> {
+        if self.quota_bytes == 0 {
+            None
+        } else {
+            Some(self.quota_bytes.saturating_sub(usage.bytes))
+        }
+    }
+
+    /// Record an upload of `user` starting now, return false if it exceeds the rate limit.
+    pub fn record_upload(&self, tenant: &str, user: &str) -> bool {
+        if self.rate_uploads == 0 {
+            return true;
+        }
+
+        let mut recent_uploads = RECENT_UPLOADS.lock().unwrap();
+        let uploads = recent_uploads
+            .entry(format!("{}/{}", tenant, user))
+            .or_default();
+
+        let now = Instant::now();
+        self.forget_expired(uploads, now);
+
+        if uploads.len() as u64 >= self.rate_uploads {
+            return false;
+        }
+
+        uploads.push_back(now);
+        true
+    }
+
+    /// Return the amount of uploads `user` started in the current window.
+    pub fn recent_uploads(&self, tenant: &str, user: &str) -> u64 {
+        let mut recent_uploads = RECENT_UPLOADS.lock().unwrap();
+        match recent_uploads.get_mut(&format!("{}/{}", tenant, user)) {
+            Some(uploads) => {
+                self.forget_expired(uploads, Instant::now());
+                uploads.len() as u64
+            }
+            None => 0,
+        }
+    }
+
+    fn forget_expired(&self, uploads: &mut VecDeque<Instant>, now: Instant) {
+        while let Some(start) = uploads.front() {
+            if now.duration_since(*start) < self.rate_window {
+                break;
+            }
+            uploads.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::UploadLimits;
+    use crate::actix_crypt::usage::Usage;
+
+    fn limits(quota_bytes: u64, quota_files: u64, rate_uploads: u64) -> UploadLimits {
+        UploadLimits {
+            quota_bytes,
+            quota_files,
+            rate_uploads,
+            rate_window: Duration::from_millis(200),
+        }
+    }
+
+    #[test]
+    fn quota() {
+        let usage = Usage { bytes: 60, files: 2 };
+
+        assert!(limits(100, 3, 0).allows(&usage, 40));
+        assert!(!limits(100, 3, 0).allows(&usage, 41));
+        assert!(!limits(100, 2, 0).allows(&usage, 1));
+        assert!(limits(0, 0, 0).allows(&usage, u64::MAX));
+
+        assert_eq!(limits(100, 0, 0).remaining_bytes(&usage), Some(40));
+        assert_eq!(limits(50, 0, 0).remaining_bytes(&usage), Some(0));
+        assert_eq!(limits(0, 0, 0).remaining_bytes(&usage), None);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let limits = limits(0, 0, 2);
+
+        assert!(limits.record_upload("rate", "alice"));
+        assert!(limits.record_upload("rate", "alice"));
+        assert!(!limits.record_upload("rate", "alice"));
+        assert_eq!(limits.recent_uploads("rate", "alice"), 2);
+
+        // Users and tenants have their own window
+        assert!(limits.record_upload("rate", "bob"));
+        assert!(limits.record_upload("other", "alice"));
+
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(limits.recent_uploads("rate", "alice"), 0);
+        assert!(limits.record_upload("rate", "alice"));
+    }
+
+    #[test]
+    fn unlimited_rate() {
+        let limits = limits(0, 0, 0);
+        for _ in 0..100 {
+            assert!(limits.record_upload("unlimited", "alice"));
+        }
+        assert_eq!(limits.recent_uploads("unlimited", "alice"), 0);
+    }
+}
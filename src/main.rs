@@ -1,13 +1,22 @@
-use std::io::Write;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 
-use std::fs::OpenOptions;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use actix;
-use actix_web::error::{BlockingError, ErrorInternalServerError, ErrorUnauthorized, PayloadError};
+use actix_web::dev::RequestHead;
+use actix_web::error::{
+    BlockingError, ErrorBadRequest, ErrorConflict, ErrorForbidden, ErrorInternalServerError, ErrorNotFound,
+    ErrorNotImplemented, ErrorPayloadTooLarge, ErrorTooManyRequests, ErrorUnauthorized,
+    ErrorUnprocessableEntity, PayloadError,
+};
+use actix_web::guard::{self, Guard};
+use actix_web::http::header;
 use actix_web::middleware;
-use actix_web::web::HttpResponse;
-use actix_web::{App, HttpServer};
+use actix_web::web::{Data, HttpResponse, Query};
+use actix_web::{App, HttpRequest, HttpServer};
 
 use actix_multipart::{Field, Multipart, MultipartError};
 
@@ -15,9 +24,29 @@ use futures::future::{err, Either};
 use futures::{Future, Stream};
 
 mod actix_crypt;
+mod config;
+mod limits;
+mod replay;
+mod scrubber;
 
+use actix_crypt::meta::{self, BlobMeta};
+use actix_crypt::usage::{self, Usage};
+use actix_crypt::phash::{self, PhashIndex};
+use actix_crypt::placeholder::{self, Placeholder};
+use actix_crypt::{decode, dedup, oembed, password, sanitize, signed, tokens};
+use actix_crypt::store::{self, BlobStore, SharedBlobStore};
 use actix_crypt::CryptFiles;
-use actix_crypt::EncryptedBlob;
+use actix_crypt::{
+    announced_blob_size, header_initial_vector, BlobHash, EncryptedBlob, HEADER_PREFIX_SIZE,
+};
+
+use config::{BlocklistAction, Config, SharedConfig, Tenant, TenantRoute, DEFAULT_TENANT};
+
+use replay::Rejection;
+
+use scrubber::Scrubber;
+
+use serde::{Deserialize, Serialize};
 
 use rand;
 use rand::RngCore;
@@ -25,20 +54,31 @@ use rand::RngCore;
 use hex;
 
 use dotenv::dotenv;
-use lazy_static::lazy_static;
 
-lazy_static! {
-    pub static ref BASE_URL: String = std::env::var("BASE_URL").expect("BASE_URL must be set");
-    pub static ref IP: String = std::env::var("IP").expect("IP must be set");
-    pub static ref PORT: String = std::env::var("PORT").expect("PORT must be set");
+/// The tenant behind an upload or usage route.
+pub struct UploadTarget {
+    tenant: String,
+    store: SharedBlobStore,
+    phashes: Arc<PhashIndex>,
 }
 
-pub fn download_file(field: Field) -> impl Future<Item = String, Error = actix_web::error::Error> {
+pub fn download_file(
+    field: Field,
+    target: &UploadTarget,
+    config: Arc<Config>,
+    tenant: Arc<Tenant>,
+    user: Option<String>,
+    usage: Usage,
+    options: UploadOptions,
+) -> impl Future<Item = (String, Option<Placeholder>), Error = actix_web::error::Error> {
+    let store = target.store.clone();
+    let phashes = target.phashes.clone();
+
     let mut data = [0u8; 16];
     rand::thread_rng().fill_bytes(&mut data);
     let hex_str = hex::encode(data);
 
-    let mut file_path = std::env::temp_dir();
+    let mut file_path = config.temp_dir.clone();
 
     let cd = field.content_disposition();
     let filename = if let Some(cd) = cd {
@@ -48,6 +88,10 @@ pub fn download_file(field: Field) -> impl Future<Item = String, Error = actix_w
         if let Some(filename) = filename_opt {
             let file_extension_opt = filename.split('.').nth(1);
             if let Some(file_extension) = file_extension_opt {
+                // A separator would escape the temporary directory or shadow `<name>/info`
+                if !file_extension.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    return Either::A(err(ErrorBadRequest("Invalid file extension")));
+                }
                 res = format!("{}.{}", hex_str, file_extension)
             }
         }
@@ -65,95 +109,1018 @@ pub fn download_file(field: Field) -> impl Future<Item = String, Error = actix_w
         Err(e) => return Either::A(err(ErrorInternalServerError(e))),
     };
 
+    // Anonymous uploads have no quota
+    let remaining = match user {
+        Some(_) => tenant.limits.remaining_bytes(&usage),
+        None => None,
+    };
+    let replay_protection = tenant.replay_protection;
+    let page = if options.preview { "v/" } else { "" };
+
     Either::B(
         field
+            .map_err(|e| {
+                log::error!("Upload: receiving the file failed: {}", e);
+                ErrorInternalServerError(e)
+            })
             .fold(
-                (file, file_path, 0i64),
-                move |(mut file, file_path, mut acc), bytes| {
+                (file, file_path, 0u64, Vec::new()),
+                move |(mut file, file_path, mut acc, mut header), bytes| {
+                    // Reject an upload over the quota or with a stale nonce as soon as the
+                    // header is received
+                    if header.len() < HEADER_PREFIX_SIZE {
+                        let missing = (HEADER_PREFIX_SIZE - header.len()).min(bytes.len());
+                        header.extend_from_slice(&bytes[..missing]);
+                    }
+
+                    if let Some(ref replay_protection) = replay_protection {
+                        if let Some(nonce) = header_initial_vector(&header) {
+                            if replay_protection.check_fresh(&nonce).is_err() {
+                                std::fs::remove_file(&file_path).ok();
+                                return Either::A(err(ErrorForbidden("Stale upload nonce")));
+                            }
+                        }
+                    }
+
+                    if let Some(remaining) = remaining {
+                        let announced = announced_blob_size(&header).unwrap_or(0);
+                        if acc + bytes.len() as u64 > remaining || announced > remaining {
+                            std::fs::remove_file(&file_path).ok();
+                            return Either::A(err(ErrorPayloadTooLarge("Storage quota exceeded")));
+                        }
+                    }
+
                     // fs operations are blocking, we have to execute writes
                     // on threadpool
-                    actix_web::web::block(move || {
-                        file.write_all(bytes.as_ref()).map_err(|e| {
-                            println!("file.write_all failed: {:?}", e);
-
-                            // Try removing the file.
-                            std::fs::remove_file(file_path.clone()).ok();
-                            MultipartError::Payload(PayloadError::Io(e))
-                        })?;
-                        acc += bytes.len() as i64;
-                        Ok((file, file_path, acc))
-                    })
-                    .map_err(|e: BlockingError<MultipartError>| match e {
-                        BlockingError::Error(e) => e,
-                        BlockingError::Canceled => MultipartError::Incomplete,
-                    })
+                    Either::B(
+                        actix_web::web::block(move || {
+                            file.write_all(bytes.as_ref()).map_err(|e| {
+                                log::error!("Upload: writing the file failed: {}", e);
+
+                                // Try removing the file.
+                                std::fs::remove_file(file_path.clone()).ok();
+                                MultipartError::Payload(PayloadError::Io(e))
+                            })?;
+                            acc += bytes.len() as u64;
+                            Ok((file, file_path, acc, header))
+                        })
+                        .map_err(|e: BlockingError<MultipartError>| {
+                            let e = match e {
+                                BlockingError::Error(e) => e,
+                                BlockingError::Canceled => MultipartError::Incomplete,
+                            };
+                            log::error!("Upload: receiving the file failed: {}", e);
+                            ErrorInternalServerError(e)
+                        }),
+                    )
                 },
             )
-            .map(|(file, file_path, _)| {
-                let mut encrypted_blob =
-                    EncryptedBlob::from(file).map_err(ErrorInternalServerError)?;
+            .and_then(move |(file, file_path, _, _)| {
+                let committed_tenant = tenant.clone();
 
-                if encrypted_blob.is_header_magic_valid() && encrypted_blob.is_content_valid() {
-                    // Valid content, move to bucket
+                // Validation and storage are blocking too
+                actix_web::web::block(move || {
+                    commit_upload(
+                        &*store,
+                        &phashes,
+                        &committed_tenant,
+                        user,
+                        options,
+                        file,
+                        &file_path,
+                    )
+                })
+                .map_err(|e| match e {
+                    BlockingError::Error(e) => ErrorInternalServerError(e),
+                    BlockingError::Canceled => ErrorInternalServerError("Upload canceled"),
+                })
+                .map(move |upload| (upload, tenant))
+            })
+            .and_then(move |(upload, tenant)| match upload {
+                Upload::Stored(name, placeholder) => {
+                    Ok((format!("{}/{}{}\n", tenant.base_url, page, name), placeholder))
+                }
+                Upload::Invalid => Err(ErrorUnauthorized("Authentification failed")),
+                Upload::OverQuota => Err(ErrorPayloadTooLarge("Storage quota exceeded")),
+                Upload::Replayed(Rejection::Stale) => Err(ErrorForbidden("Stale upload nonce")),
+                Upload::Replayed(Rejection::Reused) => {
+                    Err(ErrorConflict("Upload nonce already used"))
+                }
+                Upload::Malformed => Err(ErrorUnprocessableEntity("Malformed image")),
+                Upload::Blocked => Err(ErrorForbidden("Blocked content")),
+                Upload::TooLarge => Err(ErrorUnprocessableEntity("Image too large")),
+            }),
+    )
+}
 
-                    let file_name = file_path.file_name().unwrap();
+/// Outcome of the validation of an upload.
+enum Upload {
+    /// The blob was stored under this name, with the placeholders of an image.
+    Stored(String, Option<Placeholder>),
+    /// The blob isn't encrypted with the key of the tenant.
+    Invalid,
+    /// The blob doesn't fit in the quota of its user.
+    OverQuota,
+    /// The nonce of the blob is rejected by the replay protection.
+    Replayed(Rejection),
+    /// The blob is a malformed image, its metadata cannot be removed or its perceptual hash
+    /// checked against the blocklist.
+    Malformed,
+    /// The blob is an image matching a blocked perceptual hash.
+    Blocked,
+    /// The blob is an image over the decoding limits.
+    TooLarge,
+}
 
-                    let mut bucket_path = PathBuf::from("./bucket");
-                    bucket_path.push(file_name);
+/// Validate the uploaded blob at `file_path` and move it to the store.
+fn commit_upload(
+    store: &dyn BlobStore,
+    phashes: &PhashIndex,
+    tenant: &Tenant,
+    user: Option<String>,
+    options: UploadOptions,
+    file: File,
+    file_path: &Path,
+) -> io::Result<Upload> {
+    let result = validate_and_store(store, phashes, tenant, user, options, file, file_path);
+    std::fs::remove_file(file_path).ok();
+    result
+}
 
-                    std::fs::copy(file_path.clone(), bucket_path)
-                        .map_err(|e| {
-                            std::fs::remove_file(file_path.clone()).ok();
-                            ErrorInternalServerError(e)
-                        })?;
-
-                    std::fs::remove_file(file_path.clone())?;
-                    return Ok(format!(
-                        "{}/{}\n",
-                        BASE_URL.as_str(),
-                        file_name.to_string_lossy()
-                    ));
+fn validate_and_store(
+    store: &dyn BlobStore,
+    phashes: &PhashIndex,
+    tenant: &Tenant,
+    user: Option<String>,
+    options: UploadOptions,
+    file: File,
+    file_path: &Path,
+) -> io::Result<Upload> {
+    let name = file_path.file_name().unwrap().to_string_lossy().into_owned();
+    let mut encrypted_blob = EncryptedBlob::from(file, tenant.key)?;
+
+    // The plaintext of images is kept while validating, for the placeholders and the metadata
+    let image_format = decode::image_format(&name);
+    let mut plaintext = Vec::new();
+    let is_valid = encrypted_blob.is_header_magic_valid()
+        && encrypted_blob.inspect_content(|data| {
+            if image_format.is_some() {
+                plaintext.extend_from_slice(data);
+            }
+        });
+    if !is_valid {
+        return Ok(Upload::Invalid);
+    }
+
+    // The nonce is only consumed by a valid blob, it is covered by the hash
+    if let Some(ref replay_protection) = tenant.replay_protection {
+        let nonce = encrypted_blob.initial_vector()?;
+        if let Err(rejection) = replay_protection.accept(&tenant.name, &nonce) {
+            return Ok(Upload::Replayed(rejection));
+        }
+    }
+
+    // The metadata is removed from the plaintext, then encrypted again in a fresh blob
+    let stripped = match image_format {
+        Some(format) if tenant.strip_metadata => {
+            match sanitize::strip_metadata(&plaintext, format, &tenant.decode_limits) {
+                Ok(stripped) => stripped,
+                Err(ref e) if decode::is_too_large(e) => return Ok(Upload::TooLarge),
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => return Ok(Upload::Malformed),
+                Err(e) => return Err(e),
+            }
+        }
+        _ => None,
+    };
+
+    // Placeholders and perceptual hash of what is served, without the metadata
+    let served = stripped.as_deref().unwrap_or(&plaintext);
+    let image = match image_format {
+        Some(format) => match decode::decode(served, format, &tenant.decode_limits) {
+            Ok(image) => Some(image),
+            Err(ref e) if decode::is_too_large(e) => {
+                log::warn!("Upload: {} rejected: {}", name, e);
+                return Ok(Upload::TooLarge);
+            }
+            Err(e) => {
+                log::debug!("Upload: cannot decode {}: {}", name, e);
+                None
+            }
+        },
+        None => None,
+    };
+    let placeholder = image.as_ref().and_then(placeholder::compute);
+    let perceptual_hash = image.as_ref().map(phash::dhash);
+    drop(image);
+
+    // The blocked hash matched by the upload, `None` for an image whose hash cannot be computed
+    let moderation = &tenant.moderation;
+    let flagged = match perceptual_hash {
+        _ if moderation.blocklist == BlocklistAction::Off => None,
+        Some(hash) => phash::find_blocked(store, hash, moderation.blocklist_distance)?.map(Some),
+        None if image_format.is_some() => Some(None),
+        None => None,
+    };
+    if let Some(blocked) = flagged {
+        let reason = match blocked {
+            Some(blocked) => format!("it matches the blocked {}", phash::to_hex(blocked)),
+            None => "it cannot be decoded".to_string(),
+        };
+        if moderation.blocklist == BlocklistAction::Reject {
+            log::warn!("Upload: {} rejected, {}", name, reason);
+            return Ok(if blocked.is_some() { Upload::Blocked } else { Upload::Malformed });
+        }
+        log::warn!("Upload: {} flagged, {}", name, reason);
+    }
+
+    let (hash, data) = match stripped {
+        Some(stripped) => {
+            let data = Cursor::new(tenant.key.encrypt(&stripped));
+            let mut stripped_blob = EncryptedBlob::from(data, tenant.key)?;
+            (stripped_blob.hash()?, stripped_blob.into_inner().into_inner())
+        }
+        None => {
+            // Valid content, move to the store
+            let hash = encrypted_blob.hash()?;
+            let mut file = encrypted_blob.into_inner();
+            let mut data = Vec::new();
+            file.seek(SeekFrom::Start(0))?;
+            file.read_to_end(&mut data)?;
+            (hash, data)
+        }
+    };
+
+    let size = data.len() as u64;
+    let uploaded = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let password = match options.password {
+        Some(ref password) => Some(password::hash(password)?),
+        None => None,
+    };
+    let meta = BlobMeta {
+        user,
+        uploaded,
+        size,
+        private: options.private,
+        password,
+        max_downloads: options.max_downloads,
+        blurhash: placeholder.as_ref().map(|placeholder| placeholder.blurhash.clone()),
+        dominant_color: placeholder.as_ref().map(|placeholder| placeholder.dominant_color.clone()),
+    };
+
+    // Charge the quota first, concurrent uploads of the same user can't both fit in it
+    if let Some(ref user) = meta.user {
+        if !usage::charge(store, user, size, |usage| tenant.limits.allows(usage, size))? {
+            return Ok(Upload::OverQuota);
+        }
+    }
+
+    // Blobs without metadata are public, a private upload is never stored before its metadata
+    let mut result = meta::write(store, &name, &meta);
+    if result.is_ok() {
+        result = store_blob(store, tenant, &name, &hash, &data);
+        if result.is_err() {
+            store.delete(&meta::meta_name(&name)).ok();
+        }
+    }
+    if result.is_err() {
+        if let Some(ref user) = meta.user {
+            usage::release(store, user, size).ok();
+        }
+    }
+    result?;
+
+    if let Some(hash) = perceptual_hash {
+        phashes.index(store, &name, hash)?;
+    }
+    if let Some(blocked) = flagged {
+        phash::flag(store, &name, blocked)?;
+    }
+
+    Ok(Upload::Stored(name, placeholder))
+}
+
+fn store_blob(
+    store: &dyn BlobStore,
+    tenant: &Tenant,
+    name: &str,
+    hash: &BlobHash,
+    data: &[u8],
+) -> io::Result<()> {
+    if tenant.dedup {
+        dedup::put_deduplicated(store, name, hash, data, tenant.parity_shards)?;
+    } else {
+        store.put(name, &mut &data[..])?;
+
+        // Missing parity is backfilled by the scrubber, don't fail the upload for it
+        if let Err(e) = actix_crypt::write_parity(store, name, data, tenant.parity_shards) {
+            log::error!("parity generation failed for {}: {}", name, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Options of an upload, given in its query string.
+#[derive(Clone, Deserialize)]
+pub struct UploadOptions {
+    /// Only serve the blob with a signed URL, see the sign route.
+    #[serde(default)]
+    private: bool,
+    /// Downloads allowed before the blob is deleted, 1 is burn-after-read.
+    max_downloads: Option<u64>,
+    /// Return the URL of the preview page instead of the one of the blob.
+    #[serde(default)]
+    preview: bool,
+    /// Password protecting the blob, given in the `X-Upload-Password` header to stay out of logs.
+    #[serde(skip)]
+    password: Option<String>,
+}
+
+pub fn upload(
+    req: HttpRequest,
+    multipart: Multipart,
+    options: Query<UploadOptions>,
+    target: Data<UploadTarget>,
+    config: Data<SharedConfig>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let mut options = options.into_inner();
+    if options.max_downloads == Some(0) {
+        return Either::A(err(ErrorBadRequest("max_downloads must be at least 1")));
+    }
+
+    // Never store a blob unprotected because its password is unreadable
+    if let Some(password) = req.headers().get("x-upload-password") {
+        match password.to_str() {
+            Ok(password) if !password.is_empty() => options.password = Some(password.to_string()),
+            _ => return Either::A(err(ErrorBadRequest("Invalid upload password"))),
+        }
+    }
+
+    // The whole upload is handled with the configuration active when it started
+    let config = config.current();
+    let tenant = config
+        .tenant(&target.tenant)
+        .cloned()
+        .expect("Tenants are only removed on restart");
+
+    let token = tokens::bearer_token(&req);
+    let store = target.store.clone();
+    let require_upload_token = tenant.require_upload_token;
+    let private = options.private;
+
+    // Authenticate before receiving anything, a token lookup might hit the network
+    Either::B(
+        actix_web::web::block(move || authenticate(&*store, token))
+            .map_err(|e| match e {
+                BlockingError::Error(e) => ErrorInternalServerError(e),
+                BlockingError::Canceled => ErrorInternalServerError("Upload canceled"),
+            })
+            .and_then(move |authentication| match authentication {
+                // A revoked token is never accepted, even when tokens are optional
+                Authentication::Invalid => Err(ErrorUnauthorized("Invalid API token")),
+                Authentication::Anonymous if require_upload_token => {
+                    Err(ErrorUnauthorized("API token required"))
+                }
+                // Only the user of a private blob can sign its URLs
+                Authentication::Anonymous if private => {
+                    Err(ErrorBadRequest("Private uploads require an API token"))
+                }
+                Authentication::Anonymous => Ok((None, Usage::default())),
+                Authentication::User(user, usage) => Ok((Some(user), usage)),
+            })
+            .and_then(move |(user, usage)| {
+                // Check the limits before receiving the body
+                if let Some(ref user) = user {
+                    if !tenant.limits.allows(&usage, 0) {
+                        return Either::A(err(ErrorPayloadTooLarge("Storage quota exceeded")));
+                    }
+                    if !tenant.limits.record_upload(&tenant.name, user) {
+                        return Either::A(err(ErrorTooManyRequests("Upload rate limit exceeded")));
+                    }
                 }
 
-                std::fs::remove_file(file_path.clone()).ok();
-                Err(ErrorUnauthorized("Authentification failed"))
+                Either::B(receive_upload(multipart, target, config, tenant, user, usage, options))
+            })
+            .map(|res| {
+                let (ref url, ref placeholder) = res[0];
+                let mut response = HttpResponse::Ok();
+                // The placeholders are given in headers, the body stays the URL of the blob
+                if let Some(placeholder) = placeholder {
+                    response
+                        .header("x-blurhash", placeholder.blurhash.as_str())
+                        .header("x-dominant-color", placeholder.dominant_color.as_str());
+                }
+                response.body(url.clone())
             })
             .map_err(|e| {
-                println!("file download failed, {:?}", e);
-                ErrorInternalServerError(e)
+                log::error!("Upload: failed: {}", e);
+                e
             })
-            .and_then(|res| res),
     )
 }
 
-pub fn upload(multipart: Multipart) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+/// Who sent a request, as identified by its API token.
+enum Authentication {
+    /// No token was given.
+    Anonymous,
+    /// The token is unknown or revoked.
+    Invalid,
+    /// The user of the token and its current usage.
+    User(String, Usage),
+}
+
+fn authenticate(store: &dyn BlobStore, token: Option<String>) -> io::Result<Authentication> {
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(Authentication::Anonymous),
+    };
+
+    match tokens::authenticate(store, &token)? {
+        Some(user) => {
+            let usage = usage::read(store, &user)?;
+            Ok(Authentication::User(user, usage))
+        }
+        None => Ok(Authentication::Invalid),
+    }
+}
+
+fn receive_upload(
+    multipart: Multipart,
+    target: Data<UploadTarget>,
+    config: Arc<Config>,
+    tenant: Arc<Tenant>,
+    user: Option<String>,
+    usage: Usage,
+    options: UploadOptions,
+) -> impl Future<Item = Vec<(String, Option<Placeholder>)>, Error = actix_web::Error> {
     multipart
         .map_err(ErrorInternalServerError)
-        .map(|field| download_file(field).into_stream())
+        .map(move |field| {
+            download_file(
+                field,
+                &target,
+                config.clone(),
+                tenant.clone(),
+                user.clone(),
+                usage,
+                options.clone(),
+            )
+            .into_stream()
+        })
         .flatten()
         .collect()
-        .map(|res| HttpResponse::Ok().body(res[0].clone()))
-        .map_err(|e| {
-            println!("failed: {}", e);
-            e
+}
+
+/// The usage of a user, as reported by the usage route.
+#[derive(Serialize)]
+struct UsageReport {
+    user: String,
+    bytes: u64,
+    files: u64,
+    /// 0 when unlimited, like the limits below.
+    quota_bytes: u64,
+    quota_files: u64,
+    recent_uploads: u64,
+    rate_limit_uploads: u64,
+    rate_limit_window: u64,
+}
+
+/// Report the usage and limits of the user of the API token.
+pub fn usage_report(
+    req: HttpRequest,
+    target: Data<UploadTarget>,
+    config: Data<SharedConfig>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let tenant = config.tenant(&target.tenant);
+    let token = tokens::bearer_token(&req);
+    let store = target.store.clone();
+
+    actix_web::web::block(move || authenticate(&*store, token))
+        .map_err(|e| match e {
+            BlockingError::Error(e) => ErrorInternalServerError(e),
+            BlockingError::Canceled => ErrorInternalServerError("Request canceled"),
         })
+        .and_then(move |authentication| match authentication {
+            Authentication::Anonymous => Err(ErrorUnauthorized("API token required")),
+            Authentication::Invalid => Err(ErrorUnauthorized("Invalid API token")),
+            Authentication::User(user, usage) => {
+                let limits = tenant.limits;
+                Ok(HttpResponse::Ok().json(UsageReport {
+                    recent_uploads: limits.recent_uploads(&tenant.name, &user),
+                    user,
+                    bytes: usage.bytes,
+                    files: usage.files,
+                    quota_bytes: limits.quota_bytes,
+                    quota_files: limits.quota_files,
+                    rate_limit_uploads: limits.rate_uploads,
+                    rate_limit_window: limits.rate_window.as_secs(),
+                }))
+            }
+        })
+}
+
+/// A signed URL to mint, given in the query string of the sign route.
+#[derive(Deserialize)]
+pub struct SignRequest {
+    name: String,
+    /// Lifetime of the URL in seconds.
+    #[serde(default = "default_url_lifetime")]
+    expires_in: u64,
+}
+
+fn default_url_lifetime() -> u64 {
+    3600
+}
+
+/// Mint a signed URL of a blob uploaded with the API token.
+pub fn sign(
+    req: HttpRequest,
+    request: Query<SignRequest>,
+    target: Data<UploadTarget>,
+    config: Data<SharedConfig>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let tenant = config.tenant(&target.tenant);
+    let token = tokens::bearer_token(&req);
+    let store = target.store.clone();
+    let request = request.into_inner();
+
+    actix_web::web::block(move || {
+        let meta = meta::read(&*store, &request.name)?;
+        authenticate(&*store, token).map(|authentication| (authentication, meta, request))
+    })
+    .map_err(|e| match e {
+        BlockingError::Error(e) => ErrorInternalServerError(e),
+        BlockingError::Canceled => ErrorInternalServerError("Request canceled"),
+    })
+    .and_then(move |(authentication, meta, request)| {
+        let user = match authentication {
+            Authentication::Anonymous => return Err(ErrorUnauthorized("API token required")),
+            Authentication::Invalid => return Err(ErrorUnauthorized("Invalid API token")),
+            Authentication::User(user, _) => user,
+        };
+
+        // Only the uploader can share a blob
+        match meta {
+            Some(ref meta) if meta.user.as_ref() == Some(&user) => {}
+            _ => return Err(ErrorNotFound("Unknown blob")),
+        }
+
+        let expires = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+            .saturating_add(request.expires_in);
+        let query = signed::signed_query(&tenant.url_signing_key, &request.name, expires);
+
+        Ok(HttpResponse::Ok().body(format!("{}/{}?{}\n", tenant.base_url, request.name, query)))
+    })
+}
+
+/// A near-duplicate search, given in the query string of the duplicates route.
+#[derive(Deserialize)]
+pub struct DuplicatesRequest {
+    /// The image to find the near-duplicates of.
+    name: Option<String>,
+    /// The perceptual hash to find the near-duplicates of, instead of an image.
+    phash: Option<String>,
+    /// Largest Hamming distance between the perceptual hashes.
+    #[serde(default = "default_duplicate_distance")]
+    distance: u32,
+}
+
+fn default_duplicate_distance() -> u32 {
+    8
+}
+
+/// Larger distances match unrelated images.
+const MAX_DUPLICATE_DISTANCE: u32 = 24;
+
+#[derive(Serialize)]
+struct Duplicate {
+    name: String,
+    distance: u32,
+}
+
+/// The near-duplicates of an image, as reported by the duplicates route.
+#[derive(Serialize)]
+struct DuplicatesReport {
+    phash: String,
+    /// Closest first.
+    duplicates: Vec<Duplicate>,
+}
+
+/// List the near-duplicates of an image or a perceptual hash, for moderators.
+pub fn duplicates(
+    req: HttpRequest,
+    request: Query<DuplicatesRequest>,
+    target: Data<UploadTarget>,
+    config: Data<SharedConfig>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let tenant = config.tenant(&target.tenant);
+    let token = tokens::bearer_token(&req);
+    let store = target.store.clone();
+    let request = request.into_inner();
+
+    if request.distance > MAX_DUPLICATE_DISTANCE {
+        return Either::A(err(ErrorBadRequest(format!(
+            "distance must be at most {}",
+            MAX_DUPLICATE_DISTANCE
+        ))));
+    }
+    let hash = match (request.name, request.phash) {
+        (Some(name), None) => Ok(name),
+        (None, Some(hash)) => match phash::from_hex(&hash) {
+            Some(hash) => Err(hash),
+            None => return Either::A(err(ErrorBadRequest("phash must be 16 hexadecimal characters"))),
+        },
+        _ => return Either::A(err(ErrorBadRequest("Either name or phash must be given"))),
+    };
+    let distance = request.distance;
+    let search_store = store.clone();
+    let phashes = target.phashes.clone();
+
+    Either::B(
+        actix_web::web::block(move || authenticate(&*store, token))
+            .map_err(|e| match e {
+                BlockingError::Error(e) => ErrorInternalServerError(e),
+                BlockingError::Canceled => ErrorInternalServerError("Request canceled"),
+            })
+            .and_then(move |authentication| match authentication {
+                Authentication::Anonymous => Err(ErrorUnauthorized("API token required")),
+                Authentication::Invalid => Err(ErrorUnauthorized("Invalid API token")),
+                Authentication::User(ref user, _) if tenant.moderation.moderators.contains(user) => Ok(()),
+                Authentication::User(..) => Err(ErrorForbidden("Only moderators can search duplicates")),
+            })
+            .and_then(move |_| {
+                // Searching the perceptual hashes only happens once authorized
+                actix_web::web::block(move || {
+                    let (name, hash) = match hash {
+                        Ok(name) => match phash::read(&*search_store, &name)? {
+                            Some(hash) => (Some(name), hash),
+                            None => return Ok(None),
+                        },
+                        Err(hash) => (None, hash),
+                    };
+
+                    let duplicates = phashes
+                        .near_duplicates(&*search_store, hash, distance)?
+                        .into_iter()
+                        .filter(|(duplicate, _)| Some(duplicate) != name.as_ref())
+                        .map(|(name, distance)| Duplicate { name, distance })
+                        .collect();
+                    Ok(Some(DuplicatesReport {
+                        phash: phash::to_hex(hash),
+                        duplicates,
+                    }))
+                })
+                .map_err(|e: BlockingError<io::Error>| match e {
+                    BlockingError::Error(e) => ErrorInternalServerError(e),
+                    BlockingError::Canceled => ErrorInternalServerError("Request canceled"),
+                })
+            })
+            .and_then(|report| match report {
+                Some(report) => Ok(HttpResponse::Ok().json(report)),
+                None => Err(ErrorNotFound("Unknown image")),
+            }),
+    )
+}
+
+/// An oEmbed request, given in the query string of the oembed route.
+#[derive(Deserialize)]
+pub struct OEmbedRequest {
+    /// URL of a blob or of its preview page.
+    url: String,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+    /// Only json is supported.
+    format: Option<String>,
+}
+
+/// Describe a blob for oEmbed consumers.
+pub fn oembed(
+    request: Query<OEmbedRequest>,
+    target: Data<UploadTarget>,
+    config: Data<SharedConfig>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let tenant = config.tenant(&target.tenant);
+    let store = target.store.clone();
+    let request = request.into_inner();
+
+    if request.format.as_ref().is_some_and(|format| format != "json") {
+        return Either::A(err(ErrorNotImplemented("Only the json format is supported")));
+    }
+    if request.maxwidth == Some(0) || request.maxheight == Some(0) {
+        return Either::A(err(ErrorBadRequest("maxwidth and maxheight must be at least 1")));
+    }
+
+    // Describing an image might decrypt it
+    Either::B(
+        actix_web::web::block(move || {
+            oembed::describe(&*store, &tenant, &request.url, request.maxwidth, request.maxheight)
+        })
+        .map_err(|e| match e {
+            BlockingError::Error(ref e) if e.kind() == io::ErrorKind::NotFound => ErrorNotFound("Unknown URL"),
+            BlockingError::Error(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                ErrorUnauthorized("Private blob")
+            }
+            BlockingError::Error(e) => ErrorInternalServerError(e),
+            BlockingError::Canceled => ErrorInternalServerError("Request canceled"),
+        })
+        .map(|oembed| HttpResponse::Ok().json(oembed)),
+    )
+}
+
+/// Issue a nonce to use as the initial vector of the next upload, for replay protection.
+pub fn nonce() -> HttpResponse {
+    HttpResponse::Ok()
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(format!("{}\n", hex::encode(replay::issue_nonce())))
+}
+
+/// Match requests for `host`, with or without a port.
+fn host_guard(host: String) -> impl Guard {
+    guard::fn_guard(move |head: &RequestHead| {
+        let request_host = head
+            .headers
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok())
+            .or_else(|| head.uri.host());
+
+        match request_host {
+            Some(request_host) => request_host
+                .split(':')
+                .next()
+                .unwrap_or_default()
+                .eq_ignore_ascii_case(&host),
+            None => false,
+        }
+    })
+}
+
+/// Print why the server cannot start and exit.
+fn startup_error(e: io::Error) -> ! {
+    eprintln!("imagers: {}", e);
+    std::process::exit(1)
+}
+
+/// Return the error of a subcommand given wrong arguments.
+fn usage_error(usage: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, usage)
+}
+
+/// Return the store of the tenant `name`, the default tenant if not given.
+fn tenant_store(tenants: &[(Arc<Tenant>, SharedBlobStore)], name: Option<String>) -> SharedBlobStore {
+    let name = name.unwrap_or_else(|| DEFAULT_TENANT.to_string());
+    match tenants.iter().find(|(tenant, _)| tenant.name == name) {
+        Some((_, store)) => store.clone(),
+        None => startup_error(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown tenant {}", name),
+        )),
+    }
 }
 
 fn main() -> std::io::Result<()> {
     dotenv().ok();
     env_logger::init();
 
+    let config = Config::load().unwrap_or_else(|e| startup_error(e));
+    let bind_string = format!("{}:{}", config.ip, config.port);
+
+    // Tenants are in matching order, the routes below are registered in the same order
+    let mut tenants = Vec::new();
+    for tenant in &config.tenants {
+        let store = store::open(&tenant.storage).unwrap_or_else(|e| {
+            startup_error(io::Error::new(e.kind(), format!("tenant {}: {}", tenant.name, e)))
+        });
+        tenants.push((tenant.clone(), store));
+    }
+
+    let config = SharedConfig::new(config);
+
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        // On-demand scrubbing of every tenant: `imagers scrub`
+        Some("scrub") => {
+            for (tenant, store) in tenants {
+                let summary = Scrubber::new(store, config.clone(), &tenant.name).run_pass()?;
+                println!(
+                    "{}: {} blobs scanned, {} repaired, {} corrupted, {} with an unknown key",
+                    tenant.name, summary.scanned, summary.repaired, summary.corrupt, summary.unknown
+                );
+            }
+            return Ok(());
+        }
+        // `imagers delete <name> [tenant]`
+        Some("delete") => {
+            let name = args
+                .next()
+                .ok_or_else(|| usage_error("Usage: imagers delete <name> [tenant]"))?;
+            let store = tenant_store(&tenants, args.next());
+
+            dedup::delete(&*store, &name)?;
+            println!("{} deleted", name);
+            return Ok(());
+        }
+        // `imagers token create <user> [tenant]`, `imagers token revoke <token> [tenant]`
+        // and `imagers token list [tenant]`
+        Some("token") => {
+            let usage = "Usage: imagers token create <user>|revoke <token>|list [tenant]";
+            match args.next().as_deref() {
+                Some("create") => {
+                    let user = args.next().ok_or_else(|| usage_error(usage))?;
+                    let store = tenant_store(&tenants, args.next());
+                    println!("{}", tokens::create(&*store, &user)?);
+                }
+                Some("revoke") => {
+                    let token = args.next().ok_or_else(|| usage_error(usage))?;
+                    let store = tenant_store(&tenants, args.next());
+                    tokens::revoke(&*store, &token)?;
+                    println!("token revoked");
+                }
+                Some("list") => {
+                    let store = tenant_store(&tenants, args.next());
+                    for (hash, user) in tokens::list(&*store)? {
+                        println!("{} {}", hash, user);
+                    }
+                }
+                _ => return Err(usage_error(usage)),
+            }
+            return Ok(());
+        }
+        // `imagers blocklist add <name>|<phash> [tenant]`, `imagers blocklist remove <phash> [tenant]`,
+        // `imagers blocklist list [tenant]` and `imagers blocklist flagged [tenant]`
+        Some("blocklist") => {
+            let usage = "Usage: imagers blocklist add <name>|<phash>|remove <phash>|list|flagged [tenant]";
+            match args.next().as_deref() {
+                Some("add") => {
+                    let target = args.next().ok_or_else(|| usage_error(usage))?;
+                    let store = tenant_store(&tenants, args.next());
+                    // Block the image before deleting it, its name is kept as note
+                    let (hash, note) = match phash::read(&*store, &target)? {
+                        Some(hash) => (hash, target.as_str()),
+                        None => match phash::from_hex(&target) {
+                            Some(hash) => (hash, ""),
+                            None => {
+                                let message = format!(
+                                    "{} is neither an indexed image nor a perceptual hash",
+                                    target
+                                );
+                                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+                            }
+                        },
+                    };
+                    phash::block(&*store, hash, note)?;
+                    println!("{} blocked", phash::to_hex(hash));
+                }
+                Some("remove") => {
+                    let hash = args.next().ok_or_else(|| usage_error(usage))?;
+                    let hash = phash::from_hex(&hash).ok_or_else(|| {
+                        usage_error("Perceptual hashes are 16 hexadecimal characters")
+                    })?;
+                    let store = tenant_store(&tenants, args.next());
+                    phash::unblock(&*store, hash)?;
+                    println!("{} unblocked", phash::to_hex(hash));
+                }
+                Some("list") => {
+                    let store = tenant_store(&tenants, args.next());
+                    for (hash, note) in phash::blocklist(&*store)? {
+                        println!("{} {}", phash::to_hex(hash), note);
+                    }
+                }
+                Some("flagged") => {
+                    let store = tenant_store(&tenants, args.next());
+                    for (name, blocked) in phash::flagged(&*store)? {
+                        match blocked {
+                            Some(blocked) => println!("{} {}", name, phash::to_hex(blocked)),
+                            None => println!("{} undecodable", name),
+                        }
+                    }
+                }
+                _ => return Err(usage_error(usage)),
+            }
+            return Ok(());
+        }
+        // `imagers migrate-layout`, move local blobs to the configured layout
+        Some("migrate-layout") => {
+            for (tenant, _) in tenants {
+                if let Ok(store) = store::open_local(&tenant.storage) {
+                    println!("{}: {} blobs moved", tenant.name, store.migrate_layout()?);
+                }
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // Near-duplicates are searched in memory, the perceptual hashes are only read once
+    let tenants: Vec<_> = tenants
+        .into_iter()
+        .map(|(tenant, store)| {
+            let phashes = PhashIndex::load(&*store).unwrap_or_else(|e| {
+                startup_error(io::Error::new(e.kind(), format!("tenant {}: {}", tenant.name, e)))
+            });
+            (tenant, store, Arc::new(phashes))
+        })
+        .collect();
+
+    for (_, store, _) in &tenants {
+        actix_crypt::downloads::spawn_burner(store.clone());
+    }
+
+    let scrub_interval = config.current().scrubber.interval;
+    if scrub_interval.as_secs() != 0 {
+        for (tenant, store, _) in &tenants {
+            Scrubber::new(store.clone(), config.clone(), &tenant.name).spawn(scrub_interval);
+        }
+    }
+
+    config.reload_on_sighup()?;
+    replay::start();
+
     let system = actix::System::new("imagers");
 
-    let bind_string = format!("{}:{}", IP.as_str(), PORT.as_str());
+    HttpServer::new(move || {
+        let mut app = App::new()
+            .data(config.clone())
+            .wrap(middleware::Logger::default());
 
-    HttpServer::new(|| {
-        App::new()
-            .wrap(middleware::Logger::default())
-            .service(
-                actix_web::web::resource("/upload").route(actix_web::web::post().to_async(upload)),
-            )
-            .service(CryptFiles::new("/", "./bucket/"))
+        for (tenant, store, phashes) in &tenants {
+            let prefix = match tenant.route {
+                TenantRoute::Prefix(ref prefix) => prefix.as_str(),
+                _ => "",
+            };
+
+            let mut upload_resource = actix_web::web::resource(&format!("{}/upload", prefix))
+                .data(UploadTarget {
+                    tenant: tenant.name.clone(),
+                    store: store.clone(),
+                    phashes: phashes.clone(),
+                })
+                .route(actix_web::web::post().to_async(upload));
+
+            let mut usage_resource = actix_web::web::resource(&format!("{}/usage", prefix))
+                .data(UploadTarget {
+                    tenant: tenant.name.clone(),
+                    store: store.clone(),
+                    phashes: phashes.clone(),
+                })
+                .route(actix_web::web::get().to_async(usage_report));
+
+            let mut nonce_resource = actix_web::web::resource(&format!("{}/nonce", prefix))
+                .route(actix_web::web::get().to(nonce));
+
+            let mut sign_resource = actix_web::web::resource(&format!("{}/sign", prefix))
+                .data(UploadTarget {
+                    tenant: tenant.name.clone(),
+                    store: store.clone(),
+                    phashes: phashes.clone(),
+                })
+                .route(actix_web::web::post().to_async(sign));
+
+            let mut duplicates_resource = actix_web::web::resource(&format!("{}/duplicates", prefix))
+                .data(UploadTarget {
+                    tenant: tenant.name.clone(),
+                    store: store.clone(),
+                    phashes: phashes.clone(),
+                })
+                .route(actix_web::web::get().to_async(duplicates));
+
+            let mut oembed_resource = actix_web::web::resource(&format!("{}/oembed", prefix))
+                .data(UploadTarget {
+                    tenant: tenant.name.clone(),
+                    store: store.clone(),
+                    phashes: phashes.clone(),
+                })
+                .route(actix_web::web::get().to_async(oembed));
+
+            let files_path = if prefix.is_empty() { "/" } else { prefix };
+            let mut files = CryptFiles::new(files_path, store.clone(), config.clone(), &tenant.name);
+
+            if let TenantRoute::Host(ref host) = tenant.route {
+                upload_resource = upload_resource.guard(host_guard(host.clone()));
+                usage_resource = usage_resource.guard(host_guard(host.clone()));
+                nonce_resource = nonce_resource.guard(host_guard(host.clone()));
+                sign_resource = sign_resource.guard(host_guard(host.clone()));
+                duplicates_resource = duplicates_resource.guard(host_guard(host.clone()));
+                oembed_resource = oembed_resource.guard(host_guard(host.clone()));
+                files = files.guard(host_guard(host.clone()));
+            }
+
+            app = app
+                .service(upload_resource)
+                .service(usage_resource)
+                .service(nonce_resource)
+                .service(sign_resource)
+                .service(duplicates_resource)
+                .service(oembed_resource)
+                .service(files);
+        }
+
+        app
     })
     .bind(bind_string.as_str())?
     .start();
@@ -0,0 +1,201 @@
+//! Replay protection of uploads.
+//!
+//! The initial vector of a blob is covered by its hash, it carries the nonce of the upload:
+//! the upload time as a little-endian Unix timestamp in its first 8 bytes, random bytes after.
+//! Clients without a reliable clock can get one from the nonce route.
+//!
+//! A blob is rejected if its timestamp is outside of the window around the current time, or if
+//! its initial vector was already accepted. Accepted nonces are kept in memory up to a capacity,
+//! evicting one rejects every timestamp up to its own so it can't be replayed.
+//!
+//! The accepted nonces only live in the memory of the server process: the protection holds within
+//! a single process. Several servers sharing a store each accept a nonce once, and a restarted
+//! server relies on rejecting every timestamp up to its start.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use rand::RngCore;
+
+use crate::actix_crypt::BlobInitialVector;
+
+lazy_static! {
+    /// Blobs stamped before the start might have been accepted by the previous process.
+    static ref STARTED: u64 = now();
+
+    /// The accepted nonces of every tenant, in memory only and not shared with other processes.
+    static ref ACCEPTED_NONCES: Mutex<HashMap<String, AcceptedNonces>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The replay protection settings of a tenant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayProtection {
+    /// How far the timestamp of a nonce can be from the current time.
+    pub window: Duration,
+    /// Amount of accepted nonces kept per tenant.
+    pub capacity: usize,
+}
+
+/// Why a nonce is rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    /// The timestamp is outside of the window.
+    Stale,
+    /// The nonce was already accepted.
+    Reused,
+}
+
+#[derive(Default)]
+struct AcceptedNonces {
+    nonces: HashSet<BlobInitialVector>,
+    /// Accepted nonces with their timestamp, oldest first.
+    order: VecDeque<(u64, BlobInitialVector)>,
+    /// Timestamps up to this one are rejected.
+    horizon: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn timestamp(nonce: &BlobInitialVector) -> u64 {
+    let mut timestamp = [0u8; 8];
+    timestamp.copy_from_slice(&nonce[..8]);
+    u64::from_le_bytes(timestamp)
+}
+
+/// Record the start of the server, nonces issued before it are rejected.
+///
+/// Waits for the next second, so the nonces issued from now on are stamped after the start.
+pub fn start() {
+    lazy_static::initialize(&STARTED);
+
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    if let Some(remaining) = Duration::from_secs(*STARTED + 1).checked_sub(elapsed) {
+        thread::sleep(remaining);
+    }
+}
+
+/// Return a new nonce for the current time.
+pub fn issue_nonce() -> BlobInitialVector {
+    let mut nonce = BlobInitialVector::default();
+    nonce[..8].copy_from_slice(&now().to_le_bytes());
+    rand::thread_rng().fill_bytes(&mut nonce[8..]);
+    nonce
+}
+
+impl ReplayProtection {
+    /// Check the timestamp of `nonce`, it might still have been used.
+    pub fn check_fresh(&self, nonce: &BlobInitialVector) -> Result<(), Rejection> {
+        let timestamp = timestamp(nonce);
+        let now = now();
+        let window = self.window.as_secs();
+
+        if timestamp <= *STARTED
+            || timestamp.saturating_add(window) < now
+            || timestamp > now.saturating_add(window)
+        {
+            return Err(Rejection::Stale);
+        }
+
+        Ok(())
+    }
+
+    /// Accept `nonce` for `tenant` if it is fresh and wasn't used before.
+    pub fn accept(&self, tenant: &str, nonce: &BlobInitialVector) -> Result<(), Rejection> {
+        self.check_fresh(nonce)?;
+
+        let mut accepted_nonces = ACCEPTED_NONCES.lock().unwrap();
+        let accepted = accepted_nonces.entry(tenant.to_string()).or_default();
+
+        let timestamp = timestamp(nonce);
+        if timestamp <= accepted.horizon {
+            return Err(Rejection::Stale);
+        }
+        if accepted.nonces.contains(nonce) {
+            return Err(Rejection::Reused);
+        }
+
+        // Stale nonces are rejected without being looked up
+        let oldest = now().saturating_sub(self.window.as_secs());
+        while let Some((timestamp, _)) = accepted.order.front() {
+            if *timestamp >= oldest && accepted.order.len() < self.capacity {
+                break;
+            }
+
+            let (timestamp, evicted) = accepted.order.pop_front().unwrap();
+            accepted.nonces.remove(&evicted);
+            accepted.horizon = accepted.horizon.max(timestamp);
+        }
+
+        accepted.nonces.insert(*nonce);
+        accepted.order.push_back((timestamp, *nonce));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{issue_nonce, now, start, Rejection, ReplayProtection};
+    use crate::actix_crypt::BlobInitialVector;
+
+    const PROTECTION: ReplayProtection = ReplayProtection {
+        window: Duration::from_secs(300),
+        capacity: 2,
+    };
+
+    fn stamped(timestamp: u64) -> BlobInitialVector {
+        let mut nonce = issue_nonce();
+        nonce[..8].copy_from_slice(&timestamp.to_le_bytes());
+        nonce
+    }
+
+    #[test]
+    fn freshness() {
+        start();
+
+        assert_eq!(PROTECTION.check_fresh(&issue_nonce()), Ok(()));
+        assert_eq!(PROTECTION.check_fresh(&stamped(now() - 200)), Err(Rejection::Stale));
+        assert_eq!(PROTECTION.check_fresh(&stamped(now() - 400)), Err(Rejection::Stale));
+        assert_eq!(PROTECTION.check_fresh(&stamped(now() + 400)), Err(Rejection::Stale));
+        assert_eq!(PROTECTION.check_fresh(&stamped(0)), Err(Rejection::Stale));
+    }
+
+    #[test]
+    fn replay() {
+        start();
+
+        let nonce = issue_nonce();
+        assert_eq!(PROTECTION.accept("replay", &nonce), Ok(()));
+        assert_eq!(PROTECTION.accept("replay", &nonce), Err(Rejection::Reused));
+
+        // Every tenant has its own nonces
+        assert_eq!(PROTECTION.accept("replay-other", &nonce), Ok(()));
+        assert_eq!(PROTECTION.accept("replay", &issue_nonce()), Ok(()));
+    }
+
+    #[test]
+    fn expiry() {
+        start();
+
+        let timestamp = now();
+        let first = stamped(timestamp);
+        assert_eq!(PROTECTION.accept("expiry", &first), Ok(()));
+        assert_eq!(PROTECTION.accept("expiry", &stamped(timestamp)), Ok(()));
+
+        // Evicting the first nonce rejects its timestamp instead of forgetting it
+        let third = stamped(timestamp + 1);
+        assert_eq!(PROTECTION.accept("expiry", &third), Ok(()));
+        assert_eq!(PROTECTION.accept("expiry", &first), Err(Rejection::Stale));
+        assert_eq!(PROTECTION.accept("expiry", &stamped(timestamp)), Err(Rejection::Stale));
+        assert_eq!(PROTECTION.accept("expiry", &third), Err(Rejection::Reused));
+    }
+}
@@ -0,0 +1,522 @@
+//! Bucket scrubber, walks the bucket to detect bit rot and quarantine corrupted blobs.
+//!
+//! Blobs are verified with the key of the tenant, then with its previous keys. A blob whose magic
+//! matches none of them is reported but kept in place: it might be encrypted with a key missing
+//! from the configuration.
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::actix_crypt::store::{BlobStore, SharedBlobStore};
+use crate::actix_crypt::{self, BlobKey, EncryptedBlob, RepairOutcome};
+use crate::config::{SharedConfig, DEFAULT_TENANT};
+
+/// Settings of the scrubbers, shared by every tenant.
+///
+/// The files of a tenant other than the default one are prefixed by its name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrubSettings {
+    /// Directory the corrupted blobs are moved to.
+    pub quarantine: PathBuf,
+    /// File the passes are reported to.
+    pub report: PathBuf,
+    /// File the progress of a pass is saved to.
+    pub state: PathBuf,
+    /// Maximum amount of bytes verified per second, 0 means unlimited.
+    pub rate: u64,
+    /// Time between two background passes, 0 disables the background job.
+    pub interval: Duration,
+}
+
+/// Result of the verification of a single blob.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Valid,
+    /// The magic matches none of the keys, it is damaged or the key is unknown.
+    UnknownMagic,
+    Corrupt(&'static str),
+}
+
+#[derive(Debug, Default)]
+pub struct ScrubSummary {
+    pub scanned: u64,
+    pub corrupt: u64,
+    pub repaired: u64,
+    /// Blobs kept in place, their magic matches none of the keys.
+    pub unknown: u64,
+    pub bytes: u64,
+}
+
+/// Persistent scrubber state, used to resume an interrupted pass.
+#[derive(Debug, Default)]
+struct ScrubState {
+    /// Unix time of the last completed pass.
+    completed: u64,
+    /// Last blob verified by the pass in progress.
+    cursor: Option<String>,
+}
+
+pub struct Scrubber {
+    store: SharedBlobStore,
+    config: SharedConfig,
+    tenant: String,
+    quarantine: PathBuf,
+    report: PathBuf,
+    state: PathBuf,
+    rate: u64,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Verify the header magic and the content hash of the blob `name`, with each of `keys` its magic
+/// matches.
+pub fn check_blob(store: &dyn BlobStore, name: &str, keys: &[BlobKey]) -> io::Result<Verdict> {
+    let mut verdict = Verdict::UnknownMagic;
+
+    for &key in keys {
+        let mut encrypted_blob = match EncryptedBlob::from(store.get(name)?, key) {
+            Ok(encrypted_blob) => encrypted_blob,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(Verdict::Corrupt("truncated header"))
+            }
+            Err(e) => return Err(e),
+        };
+
+        if !encrypted_blob.is_header_magic_valid() {
+            continue;
+        }
+
+        // Keys can share a magic
+        if encrypted_blob.is_content_valid() {
+            return Ok(Verdict::Valid);
+        }
+        verdict = Verdict::Corrupt("content doesn't match header hash");
+    }
+
+    Ok(verdict)
+}
+
+/// Return the file `path` of the tenant `tenant`, prefixed by its name unless it is the default tenant.
+fn tenant_path(path: &Path, tenant: &str) -> PathBuf {
+    if tenant == DEFAULT_TENANT {
+        return path.to_path_buf();
+    }
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.{}", tenant, file_name))
+}
+
+impl Scrubber {
+    /// Create the scrubber of the bucket of the tenant `tenant`.
+    pub fn new(store: SharedBlobStore, config: SharedConfig, tenant: &str) -> Self {
+        let settings = config.current().scrubber.clone();
+        Scrubber {
+            store,
+            config,
+            tenant: tenant.to_string(),
+            quarantine: tenant_path(&settings.quarantine, tenant),
+            report: tenant_path(&settings.report, tenant),
+            state: tenant_path(&settings.state, tenant),
+            rate: settings.rate,
+        }
+    }
+
+    fn load_state(&self) -> io::Result<ScrubState> {
+        let content = match fs::read_to_string(&self.state) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(ScrubState::default()),
+            Err(e) => return Err(e),
+        };
+
+        let mut state = ScrubState::default();
+        for line in content.lines() {
+            if let Some(completed) = line.strip_prefix("completed=") {
+                state.completed = completed.parse().unwrap_or(0);
+            } else if let Some(cursor) = line.strip_prefix("cursor=") {
+                state.cursor = Some(cursor.to_string());
+            }
+        }
+
+        Ok(state)
+    }
+
+    fn save_state(&self, state: &ScrubState) -> io::Result<()> {
+        let mut content = format!("completed={}\n", state.completed);
+        if let Some(ref cursor) = state.cursor {
+            content.push_str(&format!("cursor={}\n", cursor));
+        }
+
+        // Write to a temporary file first so a crash never leaves a truncated state
+        let temp_path = self.state.with_extension("tmp");
+        fs::write(&temp_path, content)?;
+        fs::rename(temp_path, &self.state)
+    }
+
+    fn list_blobs(&self) -> io::Result<Vec<String>> {
+        let mut names = self.store.list("")?;
+
+        // Deduplicated blobs are only reachable through their aliases
+        names.extend(self.store.list(".cas/")?);
+
+        // Sorted to be able to resume from the last verified blob
+        names.sort();
+        Ok(names)
+    }
+
+    fn quarantine_blob(&self, name: &str) -> io::Result<()> {
+        // Blob names might contain separators, the quarantine is flat
+        let quarantine_name = name.replace('/', "_");
+
+        let mut data = Vec::new();
+        self.store.get(name)?.read_to_end(&mut data)?;
+        fs::write(self.quarantine.join(&quarantine_name), data)?;
+        self.store.delete(name)?;
+
+        // Keep the parity along the blob, it might still be useful for a manual recovery
+        let parity_name = actix_crypt::parity_name(name);
+        if let Ok(mut parity) = self.store.get(&parity_name) {
+            let mut data = Vec::new();
+            parity.read_to_end(&mut data)?;
+            fs::write(self.quarantine.join(format!("{}.parity", quarantine_name)), data)?;
+            self.store.delete(&parity_name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Repair the blob `name` from its parity, verified with the first of `keys` that works.
+    fn repair(&self, name: &str, keys: &[BlobKey]) -> io::Result<RepairOutcome> {
+        let mut result = Err(io::Error::new(io::ErrorKind::InvalidInput, "No key"));
+        for &key in keys {
+            result = actix_crypt::repair(&*self.store, name, key);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    fn backfill_parity(&self, name: &str, parity_shards: usize) -> io::Result<()> {
+        let mut data = Vec::new();
+        self.store.get(name)?.read_to_end(&mut data)?;
+        actix_crypt::write_parity(&*self.store, name, &data, parity_shards)
+    }
+
+    /// Sleep long enough to keep the verification under the configured rate.
+    fn throttle(&self, started: Instant, bytes: u64) {
+        if self.rate == 0 {
+            return;
+        }
+
+        let expected = Duration::from_millis(bytes.saturating_mul(1000) / self.rate);
+        let elapsed = started.elapsed();
+        if expected > elapsed {
+            thread::sleep(expected - elapsed);
+        }
+    }
+
+    /// Verify every blob of the bucket, resuming the previous pass if it was interrupted.
+    pub fn run_pass(&self) -> io::Result<ScrubSummary> {
+        fs::create_dir_all(&self.quarantine)?;
+
+        let mut state = self.load_state()?;
+        let mut report = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.report)?;
+
+        match state.cursor {
+            Some(ref cursor) => writeln!(report, "# {} pass resumed after {}", unix_now(), cursor)?,
+            None => writeln!(report, "# {} pass started", unix_now())?,
+        }
+
+        let mut summary = ScrubSummary::default();
+        let started = Instant::now();
+
+        // A reload only applies to the next pass
+        let tenant = self.config.tenant(&self.tenant);
+        let keys = tenant.keys();
+
+        for name in self.list_blobs()? {
+            if let Some(ref cursor) = state.cursor {
+                if &name <= cursor {
+                    continue;
+                }
+            }
+
+            let size = self.store.stat(&name).map(|stat| stat.len).unwrap_or(0);
+
+            match check_blob(&*self.store, &name, &keys) {
+                Ok(Verdict::Valid) => {
+                    // Backfill parity of blobs uploaded before parity existed
+                    if tenant.parity_shards != 0 && self.store.stat(&actix_crypt::parity_name(&name)).is_err() {
+                        if let Err(e) = self.backfill_parity(&name, tenant.parity_shards) {
+                            log::error!("Scrubber: cannot generate parity of {}: {}", name, e);
+                        }
+                    }
+                }
+                // The magic itself might be damaged
+                Ok(Verdict::UnknownMagic) => match self.repair(&name, &keys) {
+                    Ok(RepairOutcome::Repaired(shards)) => {
+                        log::warn!("Scrubber: {} had an invalid magic, repaired {} shards", name, shards);
+                        writeln!(report, "{} repaired invalid header magic ({} shards)", name, shards)?;
+                        summary.repaired += 1;
+                    }
+                    _ => {
+                        log::warn!("Scrubber: {} is encrypted with an unknown key, keeping it in place", name);
+                        writeln!(report, "{} unknown magic", name)?;
+                        summary.unknown += 1;
+                    }
+                },
+                Ok(Verdict::Corrupt(reason)) => match self.repair(&name, &keys) {
+                    Ok(RepairOutcome::Repaired(shards)) => {
+                        log::warn!("Scrubber: {} was corrupted ({}), repaired {} shards", name, reason, shards);
+                        writeln!(report, "{} repaired {} ({} shards)", name, reason, shards)?;
+                        summary.repaired += 1;
+                    }
+                    repair_result => {
+                        if let Err(e) = repair_result {
+                            log::debug!("Scrubber: cannot repair {}: {}", name, e);
+                        }
+
+                        log::warn!("Scrubber: {} is corrupted ({}), moving to quarantine", name, reason);
+                        self.quarantine_blob(&name)?;
+                        writeln!(report, "{} corrupt {}", name, reason)?;
+                        summary.corrupt += 1;
+                    }
+                },
+                Err(e) => {
+                    // Not a proof of corruption, keep the blob in place
+                    log::error!("Scrubber: cannot verify {}: {}", name, e);
+                    writeln!(report, "{} unreadable {}", name, e)?;
+                }
+            }
+
+            summary.scanned += 1;
+            summary.bytes += size;
+
+            state.cursor = Some(name);
+            self.save_state(&state)?;
+            self.throttle(started, summary.bytes);
+        }
+
+        writeln!(
+            report,
+            "# {} pass completed, {} blobs scanned, {} repaired, {} corrupted, {} with an unknown key",
+            unix_now(),
+            summary.scanned,
+            summary.repaired,
+            summary.corrupt,
+            summary.unknown
+        )?;
+
+        state.completed = unix_now();
+        state.cursor = None;
+        self.save_state(&state)?;
+
+        Ok(summary)
+    }
+
+    /// Run a pass every `interval` on a dedicated thread, out of the way of the actix workers.
+    pub fn spawn(self, interval: Duration) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            let state = self.load_state().unwrap_or_default();
+
+            // An interrupted pass is resumed right away
+            if state.cursor.is_none() {
+                let next_pass = state.completed + interval.as_secs();
+                let now = unix_now();
+                if next_pass > now {
+                    thread::sleep(Duration::from_secs(next_pass - now));
+                    continue;
+                }
+            }
+
+            match self.run_pass() {
+                Ok(summary) => log::info!(
+                    "Scrubber: {} blobs of tenant {} scanned, {} repaired, {} corrupted, {} with an unknown key",
+                    summary.scanned,
+                    self.tenant,
+                    summary.repaired,
+                    summary.corrupt,
+                    summary.unknown
+                ),
+                Err(e) => {
+                    log::error!("Scrubber: pass of tenant {} failed: {}", self.tenant, e);
+                    thread::sleep(interval);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use super::{check_blob, Scrubber, Verdict};
+    use crate::actix_crypt::store::{BlobStore, MemoryBlobStore};
+    use crate::actix_crypt::{self, BlobKey};
+    use crate::config::tests::{load, settings};
+    use crate::config::{SharedConfig, DEFAULT_TENANT};
+
+    /// Return a scrubber of `store` writing its files in a directory named after `name`, and that
+    /// directory.
+    fn scrubber(
+        name: &str,
+        store: &Arc<MemoryBlobStore>,
+        parity_shards: usize,
+    ) -> (Scrubber, PathBuf) {
+        let directory =
+            std::env::temp_dir().join(format!("imagers-scrub-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&directory).ok();
+        fs::create_dir_all(&directory).unwrap();
+
+        let config = load(&settings(&format!(
+            "parity_shards = {parity_shards}\n[storage]\nbackend = \"memory\"\n[scrubber]\n\
+             quarantine = \"{0}/quarantine\"\nreport = \"{0}/report.txt\"\n\
+             state = \"{0}/scrub.state\"",
+            directory.display(),
+            parity_shards = parity_shards
+        )))
+        .unwrap();
+        let scrubber = Scrubber::new(store.clone(), SharedConfig::new(config), DEFAULT_TENANT);
+        (scrubber, directory)
+    }
+
+    /// The key of the tenants of `scrubber`.
+    fn key() -> BlobKey {
+        BlobKey::new("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f", "IMAGERS0")
+            .unwrap()
+    }
+
+    #[test]
+    fn previous_keys() {
+        let store = MemoryBlobStore::default();
+        let previous = BlobKey::new(&"01".repeat(32), "IMAGERS0").unwrap();
+        let current = BlobKey::new(&"02".repeat(32), "IMAGERS1").unwrap();
+
+        let mut blob = previous.encrypt(b"encrypted with the previous key");
+        store.put("a.png", &mut Cursor::new(&blob)).unwrap();
+        assert_eq!(check_blob(&store, "a.png", &[current]).unwrap(), Verdict::UnknownMagic);
+        assert_eq!(check_blob(&store, "a.png", &[current, previous]).unwrap(), Verdict::Valid);
+
+        // Same magic, other key
+        let other = BlobKey::new(&"03".repeat(32), "IMAGERS0").unwrap();
+        assert_eq!(check_blob(&store, "a.png", &[other, previous]).unwrap(), Verdict::Valid);
+
+        let last = blob.len() - 1;
+        blob[last] ^= 0xff;
+        store.put("a.png", &mut Cursor::new(&blob)).unwrap();
+        assert_eq!(
+            check_blob(&store, "a.png", &[current, previous]).unwrap(),
+            Verdict::Corrupt("content doesn't match header hash")
+        );
+
+        store.put("a.png", &mut Cursor::new(&blob[..16])).unwrap();
+        assert_eq!(
+            check_blob(&store, "a.png", &[current]).unwrap(),
+            Verdict::Corrupt("truncated header")
+        );
+    }
+
+    #[test]
+    fn quarantine() {
+        let store = Arc::new(MemoryBlobStore::default());
+        let (scrubber, directory) = scrubber("quarantine", &store, 0);
+
+        let mut corrupt = key().encrypt(b"corrupt");
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        store.put("corrupt.png", &mut Cursor::new(&corrupt)).unwrap();
+        store.put("album/valid.png", &mut Cursor::new(key().encrypt(b"valid"))).unwrap();
+        let unknown = BlobKey::new(&"01".repeat(32), "IMAGERS9").unwrap().encrypt(b"unknown");
+        store.put("unknown.png", &mut Cursor::new(unknown)).unwrap();
+
+        let summary = scrubber.run_pass().unwrap();
+        assert_eq!(
+            (summary.scanned, summary.corrupt, summary.unknown, summary.repaired),
+            (3, 1, 1, 0)
+        );
+
+        // Unknown keys might be missing from the configuration, only the corrupt blob is moved
+        assert!(store.stat("corrupt.png").is_err());
+        assert_eq!(fs::read(directory.join("quarantine/corrupt.png")).unwrap(), corrupt);
+        assert!(store.stat("unknown.png").is_ok());
+        assert!(store.stat("album/valid.png").is_ok());
+
+        let report = fs::read_to_string(directory.join("report.txt")).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].ends_with(" pass started"));
+        assert_eq!(lines[1], "corrupt.png corrupt content doesn't match header hash");
+        assert_eq!(lines[2], "unknown.png unknown magic");
+        assert!(lines[3].ends_with(
+            " pass completed, 3 blobs scanned, 0 repaired, 1 corrupted, 1 with an unknown key"
+        ));
+
+        let state = fs::read_to_string(directory.join("scrub.state")).unwrap();
+        assert!(state.starts_with("completed=") && !state.contains("cursor="));
+
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn resume() {
+        let store = Arc::new(MemoryBlobStore::default());
+        let (scrubber, directory) = scrubber("resume", &store, 0);
+        for name in &["a.png", "b.png", "c.png", "d.png"] {
+            store.put(name, &mut Cursor::new(key().encrypt(name.as_bytes()))).unwrap();
+        }
+
+        // Interrupted after b.png
+        fs::write(directory.join("scrub.state"), "completed=0\ncursor=b.png\n").unwrap();
+        let summary = scrubber.run_pass().unwrap();
+        assert_eq!(summary.scanned, 2);
+        let report = fs::read_to_string(directory.join("report.txt")).unwrap();
+        assert!(report.lines().next().unwrap().ends_with(" pass resumed after b.png"));
+
+        // The next pass starts over
+        assert_eq!(scrubber.run_pass().unwrap().scanned, 4);
+
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn parity() {
+        let store = Arc::new(MemoryBlobStore::default());
+        let (scrubber, directory) = scrubber("parity", &store, 8);
+
+        let blob = key().encrypt(&[7; 4096]);
+        store.put("old.png", &mut Cursor::new(&blob)).unwrap();
+        let summary = scrubber.run_pass().unwrap();
+        assert_eq!((summary.scanned, summary.repaired), (1, 0));
+        assert!(store.stat(&actix_crypt::parity_name("old.png")).is_ok());
+
+        // The backfilled parity repairs the blob, its magic included
+        let mut damaged = blob.clone();
+        damaged[0] ^= 0xff;
+        damaged[100] ^= 0xff;
+        store.put("old.png", &mut Cursor::new(&damaged)).unwrap();
+        let summary = scrubber.run_pass().unwrap();
+        assert_eq!((summary.scanned, summary.repaired, summary.unknown), (1, 1, 0));
+
+        let mut repaired = Vec::new();
+        std::io::Read::read_to_end(&mut store.get("old.png").unwrap(), &mut repaired).unwrap();
+        assert_eq!(repaired, blob);
+        let report = fs::read_to_string(directory.join("report.txt")).unwrap();
+        assert!(report.contains("old.png repaired invalid header magic (2 shards)"));
+
+        fs::remove_dir_all(&directory).ok();
+    }
+}